@@ -1,32 +1,554 @@
-use super::{Emulator, RegIdx, io_func::io_out8};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::{Emulator, Eflags, Personality, RegIdx, vga::{TEXT_COLUMNS, TEXT_ROWS, MODE_TEXT}};
 
-const BIOS_TO_TERMINAL: [u8; 8] = [30, 43, 32, 36, 31, 35, 33, 37];
+pub const BDA_EQUIPMENT: u32 = 0x410;
+pub const BDA_MEMORY_SIZE: u32 = 0x413;
+pub const BDA_TICKS: u32 = 0x46c;
+pub const BDA_MIDNIGHT: u32 = 0x470;
+const BDA_END: usize = 0x500;
 
-fn put_string(s: String) {
-    for c in s.chars() {
-        io_out8(0x03f8, c as u8);
-    }
+/// Conventional memory ends where the extended BIOS data area begins.
+pub const EBDA_START: u64 = 0x9fc00;
+const BIOS_ROM_START: u64 = 0xf0000;
+const EXTENDED_MEMORY_START: u64 = 0x100000;
+
+pub const E820_RAM: u32 = 1;
+pub const E820_RESERVED: u32 = 2;
+const SMAP_SIGNATURE: u32 = 0x534d_4150;
+
+/// PIT ticks per day at 1193180 / 65536 Hz.
+const TICKS_PER_DAY: u64 = 0x1800b0;
+/// Instructions per timer tick, taking the guest to run at about one
+/// instruction per microsecond. Counting instructions rather than host time
+/// keeps the tick count the same when a run is replayed.
+pub const INSTRUCTIONS_PER_TICK: u64 = 54_925;
+
+pub(super) fn to_bcd(val: u64) -> u8 {
+    (((val / 10) % 10) << 4 | (val % 10)) as u8
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) civil date.
+pub(super) fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub(super) fn host_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 impl Emulator {
+    fn cursor_position(&self) -> (usize, usize) {
+        let pos = self.vga.cursor() as usize;
+        (pos / TEXT_COLUMNS, pos % TEXT_COLUMNS)
+    }
+
+    fn set_cursor_position(&mut self, row: usize, col: usize) {
+        let row = row.min(TEXT_ROWS - 1);
+        let col = col.min(TEXT_COLUMNS - 1);
+        self.vga.set_cursor((row * TEXT_COLUMNS + col) as u16);
+    }
+
+    /// Moves the rectangle `top`..=`bottom`, `left`..=`right` by `lines`
+    /// rows (up when positive), filling the vacated rows with blanks in
+    /// `attr`. Zero lines blanks the whole rectangle.
+    fn scroll_window(&mut self, lines: i32, attr: u8,
+                     top: usize, left: usize, bottom: usize, right: usize) {
+        let bottom = bottom.min(TEXT_ROWS - 1);
+        let right = right.min(TEXT_COLUMNS - 1);
+        if top > bottom || left > right {
+            return;
+        }
+
+        let height = (bottom - top + 1) as i32;
+        let lines = if lines == 0 || lines >= height {
+            height
+        } else if lines <= -height {
+            -height
+        } else {
+            lines
+        };
+
+        let rows: Vec<usize> = if lines > 0 {
+            (top..=bottom).collect()
+        } else {
+            (top..=bottom).rev().collect()
+        };
+        for row in rows {
+            let src = row as i32 + lines;
+            for col in left..=right {
+                let (ch, a) = if src >= top as i32 && src <= bottom as i32 {
+                    self.text_cell(src as usize, col)
+                } else {
+                    (b' ', attr)
+                };
+                self.set_text_cell(row, col, ch, a);
+            }
+        }
+    }
+
+    fn put_char_repeat(&mut self, ch: u8, attr: Option<u8>, count: usize) {
+        let (row, col) = self.cursor_position();
+        let start = row * TEXT_COLUMNS + col;
+        let end = (start + count).min(TEXT_COLUMNS * TEXT_ROWS);
+        for pos in start..end {
+            let (r, c) = (pos / TEXT_COLUMNS, pos % TEXT_COLUMNS);
+            let attr = attr.unwrap_or_else(|| self.text_cell(r, c).1);
+            self.set_text_cell(r, c, ch, attr);
+        }
+    }
+
+    /// Writes `ch` at the cursor the way a terminal would, interpreting
+    /// bell, backspace, carriage return and line feed, and scrolling the
+    /// screen when the cursor runs off the last row.
+    fn teletype_output(&mut self, ch: u8, attr: Option<u8>) {
+        let (mut row, mut col) = self.cursor_position();
+        match ch {
+            0x07 => { return; },
+            0x08 => { col = col.saturating_sub(1); },
+            b'\r' => { col = 0; },
+            b'\n' => { row += 1; },
+            _ => {
+                let attr = attr.unwrap_or_else(|| self.text_cell(row, col).1);
+                self.set_text_cell(row, col, ch, attr);
+                col += 1;
+            }
+        }
+
+        if col >= TEXT_COLUMNS {
+            col = 0;
+            row += 1;
+        }
+        if row >= TEXT_ROWS {
+            let attr = self.text_cell(TEXT_ROWS - 1, 0).1;
+            self.scroll_window(1, attr, 0, 0, TEXT_ROWS - 1, TEXT_COLUMNS - 1);
+            row = TEXT_ROWS - 1;
+        }
+        self.set_cursor_position(row, col);
+    }
+
     pub fn bios_video_teletype(&mut self) {
-        let color: u8 = self.get_register8(RegIdx::bl()) & 0xf;
+        if !self.in_text_mode() {
+            return;
+        }
         let ch = self.get_register8(RegIdx::al());
+        self.teletype_output(ch, None);
+    }
+
+    fn bios_video_scroll(&mut self, up: bool) {
+        let lines = self.get_register8(RegIdx::al()) as i32;
+        let attr = self.get_register8(RegIdx::bh());
+        let top = self.get_register8(RegIdx::ch()) as usize;
+        let left = self.get_register8(RegIdx::cl()) as usize;
+        let bottom = self.get_register8(RegIdx::dh()) as usize;
+        let right = self.get_register8(RegIdx::dl()) as usize;
+        let lines = if up { lines } else { -lines };
+        self.scroll_window(lines, attr, top, left, bottom, right);
+    }
 
-        let terminal_color = BIOS_TO_TERMINAL[(color & 0x7) as usize];
-        let bright = if (color & 0x8) != 0 { 1 } else { 0 };
-        let buf = format!("\x1b[{};{}m{}\x1b[0m",
-                          bright,
-                          terminal_color,
-                          (ch as char).to_string());
-        put_string(buf);
+    fn bios_video_write_string(&mut self) {
+        let flags = self.get_register8(RegIdx::al());
+        let attr = self.get_register8(RegIdx::bl());
+        let count = self.get_register16(RegIdx::Ecx as u8) as u32;
+        let row = self.get_register8(RegIdx::dh()) as usize;
+        let col = self.get_register8(RegIdx::dl()) as usize;
+        let string = self.get_register32(RegIdx::Ebp as u8);
+        let size = if (flags & 0x02) != 0 { count * 2 } else { count };
+        if string as usize + size as usize > self.memory.len() {
+            return;
+        }
+
+        let saved = self.cursor_position();
+        self.set_cursor_position(row, col);
+        let with_attrs = (flags & 0x02) != 0;
+        for i in 0..count {
+            if with_attrs {
+                let ch = self.get_memory8(string + i * 2) as u8;
+                let a = self.get_memory8(string + i * 2 + 1) as u8;
+                self.teletype_output(ch, Some(a));
+            } else {
+                let ch = self.get_memory8(string + i) as u8;
+                self.teletype_output(ch, Some(attr));
+            }
+        }
+        if (flags & 0x01) == 0 {
+            self.set_cursor_position(saved.0, saved.1);
+        }
     }
 
     pub fn bios_video(&mut self) {
         let f = self.get_register8(RegIdx::ah());
         match f {
+            0x00 => {
+                let mode = self.get_register8(RegIdx::al());
+                self.set_video_mode(mode);
+            },
+            0x01 => {
+                let shape = self.get_register16(RegIdx::Ecx as u8);
+                self.vga.set_cursor_shape(shape);
+            },
+            0x02 => {
+                let row = self.get_register8(RegIdx::dh()) as usize;
+                let col = self.get_register8(RegIdx::dl()) as usize;
+                self.set_cursor_position(row, col);
+            },
+            0x03 => {
+                let (row, col) = self.cursor_position();
+                self.set_register16(RegIdx::Ecx as u8, self.vga.cursor_shape());
+                self.set_register8(RegIdx::dh() as i32, row as u8);
+                self.set_register8(RegIdx::dl() as i32, col as u8);
+            },
+            0x06..=0x0a | 0x13 if !self.in_text_mode() => {},
+            0x06 => { self.bios_video_scroll(true); },
+            0x07 => { self.bios_video_scroll(false); },
+            0x08 => {
+                let (row, col) = self.cursor_position();
+                let (ch, attr) = self.text_cell(row, col);
+                self.set_register8(RegIdx::al() as i32, ch);
+                self.set_register8(RegIdx::ah() as i32, attr);
+            },
+            0x09 | 0x0a => {
+                let ch = self.get_register8(RegIdx::al());
+                let attr = if f == 0x09 {
+                    Some(self.get_register8(RegIdx::bl()))
+                } else {
+                    None
+                };
+                let count = self.get_register16(RegIdx::Ecx as u8) as usize;
+                self.put_char_repeat(ch, attr, count);
+            },
             0x0e => { self.bios_video_teletype(); },
+            0x0f => {
+                let columns = if self.vga.mode == MODE_TEXT { TEXT_COLUMNS } else { 40 };
+                self.set_register8(RegIdx::al() as i32, self.vga.mode);
+                self.set_register8(RegIdx::ah() as i32, columns as u8);
+                self.set_register8(RegIdx::bh() as i32, 0);
+            },
+            0x13 => { self.bios_video_write_string(); },
             _ => { println!("not implemented BIOS video function: {:#02x}", f); }
         }
     }
+
+    /// Physical memory layout reported by INT 15h E820 as (base, length,
+    /// type), derived from the size of `memory`.
+    pub fn memory_map(&self) -> Vec<(u64, u64, u32)> {
+        let size = self.memory.len() as u64;
+        let mut map = vec![(0, size.min(EBDA_START), E820_RAM)];
+        if size > EBDA_START {
+            map.push((EBDA_START, 0xa0000 - EBDA_START, E820_RESERVED));
+        }
+        map.push((BIOS_ROM_START, EXTENDED_MEMORY_START - BIOS_ROM_START, E820_RESERVED));
+        if size > EXTENDED_MEMORY_START {
+            map.push((EXTENDED_MEMORY_START, size - EXTENDED_MEMORY_START, E820_RAM));
+        }
+        map
+    }
+
+    /// Conventional memory in KiB as stored in the BIOS data area.
+    pub fn base_memory_kb(&self) -> u16 {
+        ((self.memory.len() as u64).min(EBDA_START) / 1024) as u16
+    }
+
+    fn extended_memory_kb(&self) -> u64 {
+        (self.memory.len() as u64).saturating_sub(EXTENDED_MEMORY_START) / 1024
+    }
+
+    fn equipment_word(&self) -> u16 {
+        let floppies = self.disks.iter().filter(|d| d.is_floppy()).count() as u16;
+        let mut equipment = 0x0020; // 80x25 colour
+        if floppies > 0 {
+            equipment |= 0x0001 | ((floppies - 1).min(3) << 6);
+        }
+        equipment
+    }
+
+    /// Fills in the equipment word, memory size and tick counter of the BIOS
+    /// data area at 0x400.
+    pub fn update_bios_data_area(&mut self) {
+        if self.memory.len() < BDA_END {
+            return;
+        }
+        let equipment = self.equipment_word() as u32;
+        let kb = self.base_memory_kb() as u32;
+        self.set_memory8(BDA_EQUIPMENT, equipment & 0xff);
+        self.set_memory8(BDA_EQUIPMENT + 1, equipment >> 8);
+        self.set_memory8(BDA_MEMORY_SIZE, kb & 0xff);
+        self.set_memory8(BDA_MEMORY_SIZE + 1, kb >> 8);
+        self.update_bios_ticks();
+    }
+
+    /// Sets the tick counter at 0x46c to the host's time of day (UTC).
+    fn update_bios_ticks(&mut self) {
+        let ticks = (self.clock() % 86400) * TICKS_PER_DAY / 86400;
+        self.set_memory32(BDA_TICKS, ticks as u32);
+    }
+
+    /// What the timer interrupt does every `INSTRUCTIONS_PER_TICK`
+    /// instructions: counts a tick at 0x46c, raising the midnight flag when
+    /// the day rolls over. Guests polling the BDA see time pass this way.
+    pub(super) fn advance_bios_ticks(&mut self) {
+        if self.memory.len() < BDA_END || self.personality == Personality::Linux {
+            return;
+        }
+        let mut bytes = [0u8; 4];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = self.read_phys8(BDA_TICKS + i as u32);
+        }
+        let mut ticks = u32::from_le_bytes(bytes) as u64 + 1;
+        if ticks >= TICKS_PER_DAY {
+            ticks = 0;
+            self.write_phys8(BDA_MIDNIGHT, 1);
+        }
+        for (i, b) in (ticks as u32).to_le_bytes().iter().enumerate() {
+            self.write_phys8(BDA_TICKS + i as u32, *b);
+        }
+    }
+
+    pub fn bios_equipment(&mut self) {
+        let equipment = self.equipment_word();
+        self.set_register16(RegIdx::Eax as u8, equipment);
+    }
+
+    pub fn bios_memory_size(&mut self) {
+        let kb = self.base_memory_kb();
+        self.set_register16(RegIdx::Eax as u8, kb);
+    }
+
+    /// AX=E820h with the 20-byte entry written to ES:DI (flat EDI).
+    fn bios_e820(&mut self) {
+        let map = self.memory_map();
+        let idx = self.get_register32(RegIdx::Ebx as u8) as usize;
+        let signature = self.get_register32(RegIdx::Edx as u8);
+        let size = self.get_register32(RegIdx::Ecx as u8);
+        if signature != SMAP_SIGNATURE || size < 20 || idx >= map.len() {
+            self.set_register8(RegIdx::ah() as i32, 0x86);
+            self.set_eflags(Eflags::Carry, true);
+            return;
+        }
+
+        let (base, len, kind) = map[idx];
+        let buf = self.get_register32(RegIdx::Edi as u8);
+        self.set_memory32(buf, base as u32);
+        self.set_memory32(buf + 4, (base >> 32) as u32);
+        self.set_memory32(buf + 8, len as u32);
+        self.set_memory32(buf + 12, (len >> 32) as u32);
+        self.set_memory32(buf + 16, kind);
+
+        let next = if idx + 1 < map.len() { idx as u32 + 1 } else { 0 };
+        self.set_register32(RegIdx::Eax as u8, SMAP_SIGNATURE);
+        self.set_register32(RegIdx::Ebx as u8, next);
+        self.set_register32(RegIdx::Ecx as u8, 20);
+        self.set_eflags(Eflags::Carry, false);
+    }
+
+    pub fn bios_system(&mut self) {
+        let ax = self.get_register16(RegIdx::Eax as u8);
+        match ax {
+            0xe820 => { self.bios_e820(); },
+            0xe801 => {
+                let kb = self.extended_memory_kb();
+                let below_16m = kb.min(0x3c00) as u16;
+                let above_16m = (kb.saturating_sub(0x3c00) / 64).min(0xffff) as u16;
+                self.set_register16(RegIdx::Eax as u8, below_16m);
+                self.set_register16(RegIdx::Ecx as u8, below_16m);
+                self.set_register16(RegIdx::Ebx as u8, above_16m);
+                self.set_register16(RegIdx::Edx as u8, above_16m);
+                self.set_eflags(Eflags::Carry, false);
+            },
+            _ if (ax >> 8) == 0x88 => {
+                let kb = self.extended_memory_kb().min(0xffff) as u16;
+                self.set_register16(RegIdx::Eax as u8, kb);
+                self.set_eflags(Eflags::Carry, false);
+            },
+            _ => {
+                println!("not implemented BIOS system function: {:#06x}", ax);
+                self.set_register8(RegIdx::ah() as i32, 0x86);
+                self.set_eflags(Eflags::Carry, true);
+            }
+        }
+    }
+
+    pub fn bios_clock(&mut self) {
+        let f = self.get_register8(RegIdx::ah());
+        let now = self.clock();
+        match f {
+            0x00 => {
+                let ticks = self.get_memory32(BDA_TICKS);
+                let midnight = self.get_memory8(BDA_MIDNIGHT) as u8;
+                self.set_memory8(BDA_MIDNIGHT, 0);
+                self.set_register16(RegIdx::Ecx as u8, (ticks >> 16) as u16);
+                self.set_register16(RegIdx::Edx as u8, (ticks & 0xffff) as u16);
+                self.set_register8(RegIdx::al() as i32, midnight);
+            },
+            0x02 => {
+                let secs = now % 86400;
+                self.set_register8(RegIdx::ch() as i32, to_bcd(secs / 3600));
+                self.set_register8(RegIdx::cl() as i32, to_bcd(secs / 60 % 60));
+                self.set_register8(RegIdx::dh() as i32, to_bcd(secs % 60));
+                self.set_register8(RegIdx::dl() as i32, 0);
+                self.set_eflags(Eflags::Carry, false);
+            },
+            0x04 => {
+                let (year, month, day) = civil_from_days((now / 86400) as i64);
+                self.set_register8(RegIdx::ch() as i32, to_bcd(year as u64 / 100));
+                self.set_register8(RegIdx::cl() as i32, to_bcd(year as u64 % 100));
+                self.set_register8(RegIdx::dh() as i32, to_bcd(month));
+                self.set_register8(RegIdx::dl() as i32, to_bcd(day));
+                self.set_eflags(Eflags::Carry, false);
+            },
+            _ => { println!("not implemented BIOS clock function: {:#02x}", f); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_advance_with_instructions() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.memory[0x7c00..0x7c02].copy_from_slice(&[0xeb, 0xfe]); // jmp $
+        emu.set_memory32(BDA_TICKS, 100);
+        let instructions = emu.init_instructions();
+        for _ in 0..INSTRUCTIONS_PER_TICK * 2 {
+            emu.step(&instructions).unwrap();
+        }
+        assert_eq!(emu.get_memory32(BDA_TICKS), 102);
+    }
+
+    #[test]
+    fn ticks_wrap_at_midnight() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        emu.set_memory32(BDA_TICKS, TICKS_PER_DAY as u32 - 1);
+        emu.set_memory8(BDA_MIDNIGHT, 0);
+        emu.advance_bios_ticks();
+        assert_eq!(emu.get_memory32(BDA_TICKS), 0);
+        assert_eq!(emu.get_memory8(BDA_MIDNIGHT), 1);
+    }
+
+    fn video(emu: &mut Emulator, ax: u32, bx: u32, cx: u32, dx: u32) {
+        emu.set_register32(RegIdx::Eax as u8, ax);
+        emu.set_register32(RegIdx::Ebx as u8, bx);
+        emu.set_register32(RegIdx::Ecx as u8, cx);
+        emu.set_register32(RegIdx::Edx as u8, dx);
+        emu.bios_video();
+    }
+
+    fn row(emu: &Emulator, row: usize) -> String {
+        (0..TEXT_COLUMNS).map(|col| emu.text_cell(row, col).0 as char).collect::<String>()
+            .trim_end().to_string()
+    }
+
+    #[test]
+    fn video_mode_and_cursor() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        video(&mut emu, 0x0200, 0, 0, 0x0c22);
+        video(&mut emu, 0x0300, 0, 0, 0);
+        assert_eq!(emu.get_register16(RegIdx::Edx as u8), 0x0c22);
+        assert_eq!(emu.get_register16(RegIdx::Ecx as u8), 0x0607);
+
+        video(&mut emu, 0x0200, 0, 0, 0xffff);
+        video(&mut emu, 0x0300, 0, 0, 0);
+        assert_eq!(emu.get_register16(RegIdx::Edx as u8), 0x184f);
+
+        emu.set_text_cell(0, 0, b'x', 0x1f);
+        video(&mut emu, 0x0003, 0, 0, 0);
+        assert_eq!(emu.text_cell(0, 0), (b' ', 0x07));
+        assert_eq!(emu.vga.cursor(), 0);
+        video(&mut emu, 0x0f00, 0x0100, 0, 0);
+        assert_eq!(emu.get_register16(RegIdx::Eax as u8), 0x5003);
+        assert_eq!(emu.get_register8(RegIdx::bh()), 0);
+
+        video(&mut emu, 0x0013, 0, 0, 0);
+        video(&mut emu, 0x0f00, 0, 0, 0);
+        assert_eq!(emu.get_register16(RegIdx::Eax as u8), 0x2813);
+    }
+
+    #[test]
+    fn video_write_and_read_characters() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        video(&mut emu, 0x0200, 0, 0, 0x014e);
+        video(&mut emu, 0x0941, 0x1e, 4, 0);
+        assert_eq!(row(&emu, 1), format!("{:78}AA", ""));
+        assert_eq!(row(&emu, 2), "AA");
+        assert_eq!(emu.text_cell(2, 1), (b'A', 0x1e));
+        assert_eq!(emu.vga.cursor(), 80 + 78);
+
+        video(&mut emu, 0x0a42, 0, 1, 0);
+        assert_eq!(emu.text_cell(1, 78), (b'B', 0x1e));
+        video(&mut emu, 0x0800, 0, 0, 0);
+        assert_eq!(emu.get_register16(RegIdx::Eax as u8), 0x1e42);
+
+        video(&mut emu, 0x0200, 0, 0, 0x184f);
+        video(&mut emu, 0x095a, 0x07, 10, 0);
+        assert_eq!(emu.text_cell(24, 79), (b'Z', 0x07));
+    }
+
+    #[test]
+    fn video_scroll_window() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        for (r, text) in ["abc", "def", "ghi", "jkl"].iter().enumerate() {
+            for (c, ch) in text.bytes().enumerate() {
+                emu.set_text_cell(r, c, ch, 0x07);
+            }
+        }
+
+        video(&mut emu, 0x0601, 0x1700, 0x0001, 0x0302);
+        assert_eq!((0..4).map(|r| row(&emu, r)).collect::<Vec<_>>(), ["aef", "dhi", "gkl", "j"]);
+        assert_eq!(emu.text_cell(3, 1), (b' ', 0x17));
+        assert_eq!(emu.text_cell(3, 0), (b'j', 0x07));
+
+        video(&mut emu, 0x0702, 0x2000, 0x0000, 0x0302);
+        assert_eq!((0..4).map(|r| row(&emu, r)).collect::<Vec<_>>(), ["", "", "aef", "dhi"]);
+        assert_eq!(emu.text_cell(1, 2), (b' ', 0x20));
+        assert_eq!(row(&emu, 4), "");
+
+        video(&mut emu, 0x0600, 0x7000, 0x0000, 0xffff);
+        assert!((0..TEXT_ROWS).all(|r| row(&emu, r).is_empty()));
+        assert_eq!(emu.text_cell(24, 79), (b' ', 0x70));
+    }
+
+    #[test]
+    fn video_write_string() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        emu.memory[0x1000..0x1004].copy_from_slice(b"hi\r\n");
+        emu.set_register32(RegIdx::Ebp as u8, 0x1000);
+        video(&mut emu, 0x1300, 0x1f, 4, 0x0503);
+        assert_eq!(row(&emu, 5), "   hi");
+        assert_eq!(emu.text_cell(5, 4), (b'i', 0x1f));
+        assert_eq!(emu.vga.cursor(), 0);
+
+        emu.memory[0x1000..0x1004].copy_from_slice(&[b'o', 0x4e, b'k', 0x2a]);
+        video(&mut emu, 0x1303, 0, 2, 0x0a00);
+        assert_eq!(emu.text_cell(10, 0), (b'o', 0x4e));
+        assert_eq!(emu.text_cell(10, 1), (b'k', 0x2a));
+        assert_eq!(emu.vga.cursor(), 10 * 80 + 2);
+
+        emu.set_register32(RegIdx::Ebp as u8, 0xffff0);
+        video(&mut emu, 0x1301, 0, 0x20, 0);
+        assert_eq!(row(&emu, 0), "");
+    }
+
+    #[test]
+    fn video_without_text_buffer() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        for ax in [0x0003, 0x0013, 0x0601, 0x0701, 0x0800, 0x0941, 0x0a41, 0x0e41, 0x0f00, 0x1301] {
+            video(&mut emu, ax, 0, 0x10, 0x184f);
+        }
+        assert!(!emu.in_text_mode());
+
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        video(&mut emu, 0x0013, 0, 0, 0);
+        video(&mut emu, 0x0941, 0x07, 1, 0);
+        assert_eq!(emu.text_cell(0, 0), (b' ', 0x07));
+    }
 }
@@ -0,0 +1,202 @@
+use super::{Emulator, bios::{to_bcd, civil_from_days}};
+
+pub const PCI_CONFIG_ADDRESS: u16 = 0x0cf8;
+pub const PCI_CONFIG_DATA: u16 = 0x0cfc;
+pub const POST_PORT: u16 = 0x0080;
+/// Bochs/QEMU debug console port; bytes written to it go to stdout.
+pub const DEBUG_PORT: u16 = 0x0402;
+pub const CMOS_INDEX: u16 = 0x0070;
+pub const CMOS_DATA: u16 = 0x0071;
+
+const CMOS_SIZE: usize = 128;
+
+/// Minimal i440FX-style chipset: a PCI host bridge answering
+/// configuration cycles, the CMOS/RTC, and the POST and debug ports.
+#[derive(Debug, Default, Clone)]
+pub struct Chipset {
+    pub pci_address: u32,
+    pub host_bridge: Vec<u8>,
+    pub cmos_index: u8,
+    pub cmos: Vec<u8>,
+    /// Last value written to port 0x80.
+    pub post_code: Option<u8>,
+}
+
+impl Chipset {
+    pub fn new() -> Chipset {
+        let mut chipset = Chipset { cmos: vec![0; CMOS_SIZE], ..Default::default() };
+        chipset.init_host_bridge();
+        chipset
+    }
+
+    fn init_host_bridge(&mut self) {
+        let mut config = vec![0u8; 256];
+        config[0x00..0x04].copy_from_slice(&[0x86, 0x80, 0x37, 0x12]);
+        config[0x04] = 0x06;
+        config[0x0a] = 0x00;
+        config[0x0b] = 0x06;
+        self.host_bridge = config;
+    }
+
+    /// Offset into the host bridge's configuration space selected by
+    /// 0xcf8, or `None` when no device answers.
+    fn pci_config_offset(&self, port_offset: u16) -> Option<usize> {
+        let enabled = (self.pci_address & 0x8000_0000) != 0;
+        let device = (self.pci_address >> 8) & 0xffff;
+        if !enabled || device != 0 || self.host_bridge.is_empty() {
+            return None;
+        }
+        Some(((self.pci_address & 0xfc) as usize) + port_offset as usize)
+    }
+
+    pub fn pci_read8(&self, port_offset: u16) -> u8 {
+        match self.pci_config_offset(port_offset) {
+            Some(offset) => self.host_bridge[offset],
+            None => 0xff,
+        }
+    }
+
+    pub fn pci_write8(&mut self, port_offset: u16, val: u8) {
+        if let Some(offset) = self.pci_config_offset(port_offset) {
+            // only the command register is writable
+            if offset == 0x04 {
+                self.host_bridge[offset] = val;
+            }
+        }
+    }
+
+    /// Reads the selected CMOS register; the clock registers report `now`,
+    /// in seconds since the epoch.
+    pub fn read_cmos(&self, now: u64) -> u8 {
+        let idx = (self.cmos_index & 0x7f) as usize;
+        let secs = now % 86400;
+        let (year, month, day) = civil_from_days((now / 86400) as i64);
+        match idx {
+            0x00 => to_bcd(secs % 60),
+            0x02 => to_bcd(secs / 60 % 60),
+            0x04 => to_bcd(secs / 3600),
+            0x06 => to_bcd(((now / 86400 + 4) % 7) + 1),
+            0x07 => to_bcd(day),
+            0x08 => to_bcd(month),
+            0x09 => to_bcd(year as u64 % 100),
+            0x32 => to_bcd(year as u64 / 100),
+            _ if idx < self.cmos.len() => self.cmos[idx],
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_cmos(&mut self, val: u8) {
+        let idx = (self.cmos_index & 0x7f) as usize;
+        if idx < self.cmos.len() {
+            self.cmos[idx] = val;
+        }
+    }
+}
+
+impl Emulator {
+    /// Fills the CMOS configuration bytes for the memory sizes, floppy
+    /// types and boot order, keeping the rest of what the guest wrote.
+    pub(super) fn update_cmos(&mut self) {
+        let size = self.memory.len();
+        let ext_kb = (size.saturating_sub(0x100000) / 1024).min(0xffff);
+        let above_16m = (size.saturating_sub(0x100_0000) / 0x10000).min(0xffff);
+        let floppies = self.disks.iter().filter(|d| d.is_floppy()).count();
+        let base_kb = self.base_memory_kb();
+
+        let cmos = &mut self.chipset.cmos;
+        cmos.resize(CMOS_SIZE, 0);
+        cmos[0x0a] = 0x26;
+        cmos[0x0b] = 0x02;
+        cmos[0x0d] = 0x80;
+        cmos[0x10] = if floppies > 0 { 0x40 } else { 0 } | if floppies > 1 { 0x04 } else { 0 };
+        cmos[0x14] = 0x05 | if floppies > 0 { 0x01 } else { 0 };
+        cmos[0x15] = (base_kb & 0xff) as u8;
+        cmos[0x16] = (base_kb >> 8) as u8;
+        for reg in [0x17, 0x30] {
+            cmos[reg] = (ext_kb & 0xff) as u8;
+            cmos[reg + 1] = (ext_kb >> 8) as u8;
+        }
+        cmos[0x34] = (above_16m & 0xff) as u8;
+        cmos[0x35] = (above_16m >> 8) as u8;
+        cmos[0x3d] = if floppies > 0 { 0x21 } else { 0x02 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::disk::Disk;
+
+    fn cmos(emu: &mut Emulator, idx: u8) -> u8 {
+        emu.io_out8(CMOS_INDEX, idx);
+        emu.io_in8(CMOS_DATA)
+    }
+
+    #[test]
+    fn pci_host_bridge() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        emu.io_out32(PCI_CONFIG_ADDRESS, 0x8000_0000);
+        assert_eq!(emu.io_in32(PCI_CONFIG_ADDRESS), 0x8000_0000);
+        assert_eq!(emu.io_in32(PCI_CONFIG_DATA), 0x1237_8086);
+        assert_eq!(emu.io_in16(PCI_CONFIG_DATA + 2), 0x1237);
+
+        emu.io_out32(PCI_CONFIG_ADDRESS, 0x8000_0008);
+        assert_eq!(emu.io_in8(PCI_CONFIG_DATA + 3), 0x06);
+
+        // the ID is read-only, the command register is not
+        emu.io_out32(PCI_CONFIG_ADDRESS, 0x8000_0000);
+        emu.io_out32(PCI_CONFIG_DATA, 0);
+        assert_eq!(emu.io_in32(PCI_CONFIG_DATA), 0x1237_8086);
+        emu.io_out32(PCI_CONFIG_ADDRESS, 0x8000_0004);
+        emu.io_out8(PCI_CONFIG_DATA, 0x07);
+        assert_eq!(emu.io_in8(PCI_CONFIG_DATA), 0x07);
+
+        // no other device, and nothing while configuration is disabled
+        emu.io_out32(PCI_CONFIG_ADDRESS, 0x8000_0800);
+        assert_eq!(emu.io_in32(PCI_CONFIG_DATA), 0xffff_ffff);
+        emu.io_out32(PCI_CONFIG_ADDRESS, 0);
+        assert_eq!(emu.io_in32(PCI_CONFIG_DATA), 0xffff_ffff);
+    }
+
+    #[test]
+    fn cmos_configuration() {
+        let mut emu = Emulator::new(0x200000, 0, 0);
+        assert_eq!(cmos(&mut emu, 0x15) as u16 | (cmos(&mut emu, 0x16) as u16) << 8, 639);
+        assert_eq!(cmos(&mut emu, 0x17) as u16 | (cmos(&mut emu, 0x18) as u16) << 8, 1024);
+        assert_eq!(cmos(&mut emu, 0x10), 0);
+        assert_eq!(cmos(&mut emu, 0x3d), 0x02);
+
+        emu.attach_disk(Disk::new(0x00, vec![0; 1474560]));
+        assert_eq!(cmos(&mut emu, 0x10), 0x40);
+        assert_eq!(cmos(&mut emu, 0x3d), 0x21);
+
+        // NVRAM keeps what was written; bit 7 of the index is the NMI mask
+        emu.io_out8(CMOS_INDEX, 0x40);
+        emu.io_out8(CMOS_DATA, 0x5a);
+        assert_eq!(cmos(&mut emu, 0xc0), 0x5a);
+        emu.update_cmos();
+        assert_eq!(cmos(&mut emu, 0x40), 0x5a);
+    }
+
+    #[test]
+    fn cmos_clock() {
+        let mut chipset = Chipset::new();
+        // Thursday 2021-03-04 05:06:07 UTC
+        let now = 1614834367;
+        let mut read = |idx| {
+            chipset.cmos_index = idx;
+            chipset.read_cmos(now)
+        };
+        assert_eq!([read(0x00), read(0x02), read(0x04)], [0x07, 0x06, 0x05]);
+        assert_eq!([read(0x06), read(0x07), read(0x08), read(0x09), read(0x32)],
+                   [0x05, 0x04, 0x03, 0x21, 0x20]);
+    }
+
+    #[test]
+    fn post_code() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        assert_eq!(emu.chipset.post_code, None);
+        emu.io_out8(POST_PORT, 0x3c);
+        assert_eq!(emu.chipset.post_code, Some(0x3c));
+    }
+}
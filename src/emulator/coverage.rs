@@ -0,0 +1,113 @@
+//! Guest code coverage: how often each instruction address was executed,
+//! written as a plain hit count file or, with source line information, as
+//! an lcov tracefile.
+//!
+//! The hit count file has one line per executed address,
+//! `0000800c 3 main+0xc start.S:12`, where the symbol and the source line
+//! are only given when known.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use super::{Emulator, debuginfo::DebugInfo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverageFormat {
+    Hits,
+    Lcov,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    /// Executions per instruction address.
+    pub hits: BTreeMap<u32, u64>,
+}
+
+/// Running coverage; pass it back to `Emulator::stop_coverage`.
+pub struct CoverageHandle {
+    hits: Arc<Mutex<HashMap<u32, u64>>>,
+    hook: usize,
+}
+
+impl Emulator {
+    /// Starts counting the executions of every instruction run by `step`.
+    pub fn start_coverage(&mut self) -> CoverageHandle {
+        let hits = Arc::new(Mutex::new(HashMap::new()));
+        let h = hits.clone();
+        let hook = self.hook_code(Arc::new(move |_, inst| {
+            *h.lock().unwrap().entry(inst.addr).or_insert(0) += 1;
+        }));
+        CoverageHandle { hits, hook }
+    }
+
+    pub fn stop_coverage(&mut self, handle: CoverageHandle) -> Coverage {
+        self.remove_hook(handle.hook);
+        let hits = handle.hits.lock().unwrap();
+        Coverage { hits: hits.iter().map(|(&addr, &n)| (addr, n)).collect() }
+    }
+}
+
+impl Coverage {
+    /// Highest hit count of the instructions in `start..end`.
+    fn max_hits(&self, start: u32, end: u32) -> u64 {
+        self.hits.range(start..end).map(|(_, &n)| n).max().unwrap_or(0)
+    }
+
+    pub fn write_hits(&self, out: &mut dyn Write, info: Option<&DebugInfo>) -> io::Result<()> {
+        for (&addr, &n) in &self.hits {
+            write!(out, "{:08x} {}", addr, n)?;
+            if let Some(info) = info {
+                match info.symbolize(addr) {
+                    Some((name, 0)) => write!(out, " {}", name)?,
+                    Some((name, off)) => write!(out, " {}+{:#x}", name, off)?,
+                    None => (),
+                }
+                if let Some(line) = info.line(addr) {
+                    write!(out, " {}:{}", info.files[line.file], line.line)?;
+                }
+            }
+            writeln!(out)?;
+        }
+        out.flush()
+    }
+
+    /// Writes an lcov tracefile with a record per source file in `info`'s
+    /// line table. Lines count as hit as often as their most executed
+    /// instruction; functions as often as their first instruction.
+    pub fn write_lcov(&self, out: &mut dyn Write, info: &DebugInfo, test_name: &str) -> io::Result<()> {
+        writeln!(out, "TN:{}", test_name)?;
+        for (file, path) in info.files.iter().enumerate() {
+            let mut lines: BTreeMap<u32, u64> = BTreeMap::new();
+            for range in info.lines.iter().filter(|l| l.file == file) {
+                let hits = lines.entry(range.line).or_insert(0);
+                *hits = (*hits).max(self.max_hits(range.start, range.end));
+            }
+            if lines.is_empty() {
+                continue;
+            }
+            let functions: Vec<(&str, u32, u64)> = info.symbols.iter()
+                .filter_map(|sym| {
+                    let line = info.line(sym.addr).filter(|l| l.file == file)?;
+                    Some((sym.name.as_str(), line.line, self.hits.get(&sym.addr).copied().unwrap_or(0)))
+                })
+                .collect();
+
+            writeln!(out, "SF:{}", path)?;
+            for (name, line, _) in &functions {
+                writeln!(out, "FN:{},{}", line, name)?;
+            }
+            for (name, _, hits) in &functions {
+                writeln!(out, "FNDA:{},{}", hits, name)?;
+            }
+            writeln!(out, "FNF:{}", functions.len())?;
+            writeln!(out, "FNH:{}", functions.iter().filter(|f| f.2 > 0).count())?;
+            for (line, hits) in &lines {
+                writeln!(out, "DA:{},{}", line, hits)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|&&n| n > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        out.flush()
+    }
+}
@@ -0,0 +1,708 @@
+//! Symbols and source lines for guest addresses, read from an i386 ELF
+//! file's symbol table and DWARF `.debug_line` (versions 2 to 5, with the
+//! compilation directory from `.debug_info`), or from a symbol map in `nm`
+//! or System.map format.
+
+use std::collections::HashMap;
+use byteorder::{LittleEndian, ByteOrder};
+use super::loader::{LoadError, ELF_MAGIC, slice_at};
+
+const ELF_HEADER_SIZE: usize = 52;
+const ELF_SHDR_SIZE: usize = 40;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const ELF_SYM_SIZE: usize = 16;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_BLOCK2: u64 = 0x03;
+const DW_FORM_BLOCK4: u64 = 0x04;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_FLAG: u64 = 0x0c;
+const DW_FORM_SDATA: u64 = 0x0d;
+const DW_FORM_REF_ADDR: u64 = 0x10;
+const DW_FORM_REF1: u64 = 0x11;
+const DW_FORM_REF2: u64 = 0x12;
+const DW_FORM_REF4: u64 = 0x13;
+const DW_FORM_REF8: u64 = 0x14;
+const DW_FORM_REF_UDATA: u64 = 0x15;
+const DW_FORM_INDIRECT: u64 = 0x16;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+const DW_FORM_EXPRLOC: u64 = 0x18;
+const DW_FORM_FLAG_PRESENT: u64 = 0x19;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub addr: u32,
+    pub name: String,
+}
+
+/// Instructions in `start..end` belong to `line` of `files[file]`.
+#[derive(Debug, Clone)]
+pub struct LineRange {
+    pub start: u32,
+    pub end: u32,
+    pub file: usize,
+    pub line: u32,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DebugInfo {
+    /// Sorted by address.
+    pub symbols: Vec<Symbol>,
+    pub files: Vec<String>,
+    /// Sorted by start address.
+    pub lines: Vec<LineRange>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(LittleEndian::read_u16)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(LittleEndian::read_u32)
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut val = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Some(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut val = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+                return Some(val);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<String> {
+        let len = self.data.get(self.pos..)?.iter().position(|&b| b == 0)?;
+        let s = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.pos += 1;
+        Some(s)
+    }
+}
+
+fn cstr_at(data: &[u8], offset: usize) -> Option<String> {
+    let mut r = Reader::new(data);
+    r.pos = offset;
+    r.cstr()
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() || name.starts_with('/') {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), name)
+    }
+}
+
+struct Section<'a> {
+    name: String,
+    kind: u32,
+    link: u32,
+    data: &'a [u8],
+}
+
+fn elf_sections(data: &[u8]) -> Result<Vec<Section<'_>>, LoadError> {
+    let header = slice_at(data, 0, ELF_HEADER_SIZE).map_err(|_| LoadError::NotElf)?;
+    if header[0..4] != ELF_MAGIC {
+        return Err(LoadError::NotElf);
+    }
+    let shoff = LittleEndian::read_u32(&header[32..]) as usize;
+    let shentsize = LittleEndian::read_u16(&header[46..]) as usize;
+    let shnum = LittleEndian::read_u16(&header[48..]) as usize;
+    let shstrndx = LittleEndian::read_u16(&header[50..]) as usize;
+    if shnum == 0 {
+        return Ok(vec![]);
+    }
+    if shentsize < ELF_SHDR_SIZE {
+        return Err(LoadError::UnsupportedElf("bad section header size"));
+    }
+
+    let mut headers = vec![];
+    for i in 0..shnum {
+        let sh = slice_at(data, shoff + i * shentsize, ELF_SHDR_SIZE)?;
+        let kind = LittleEndian::read_u32(&sh[4..]);
+        let offset = LittleEndian::read_u32(&sh[16..]) as usize;
+        let size = LittleEndian::read_u32(&sh[20..]) as usize;
+        // NOBITS sections may point past the end of the file
+        let contents = if kind == SHT_NOBITS { &[][..] } else { slice_at(data, offset, size)? };
+        headers.push((LittleEndian::read_u32(&sh[0..]) as usize, kind,
+                      LittleEndian::read_u32(&sh[24..]), contents));
+    }
+    let names = headers.get(shstrndx).map_or(&[][..], |h| h.3);
+    Ok(headers.into_iter().map(|(name, kind, link, data)| Section {
+        name: cstr_at(names, name).unwrap_or_default(),
+        kind,
+        link,
+        data,
+    }).collect())
+}
+
+/// An attribute of a DWARF 5 directory or file entry.
+enum FormValue {
+    Str(String),
+    Num(u64),
+    Other,
+}
+
+fn read_form(r: &mut Reader, form: u64, debug_str: &[u8], line_str: &[u8]) -> Option<FormValue> {
+    Some(match form {
+        DW_FORM_STRING => FormValue::Str(r.cstr()?),
+        DW_FORM_LINE_STRP => FormValue::Str(cstr_at(line_str, r.u32()? as usize)?),
+        DW_FORM_STRP => FormValue::Str(cstr_at(debug_str, r.u32()? as usize)?),
+        DW_FORM_UDATA => FormValue::Num(r.uleb()?),
+        DW_FORM_DATA1 => FormValue::Num(r.u8()? as u64),
+        DW_FORM_DATA2 => FormValue::Num(r.u16()? as u64),
+        DW_FORM_DATA4 => FormValue::Num(r.u32()? as u64),
+        DW_FORM_DATA8 => {
+            r.take(8)?;
+            FormValue::Other
+        },
+        DW_FORM_DATA16 => {
+            r.take(16)?;
+            FormValue::Other
+        },
+        DW_FORM_BLOCK => {
+            let len = r.uleb()? as usize;
+            r.take(len)?;
+            FormValue::Other
+        },
+        _ => { return None; }
+    })
+}
+
+/// Reads an attribute of a `.debug_info` entry in DWARF 2 to 4, where more
+/// forms than in line tables can appear.
+fn read_attr(r: &mut Reader, form: u64, version: u16, addr_size: usize, debug_str: &[u8])
+    -> Option<FormValue> {
+    let len = match form {
+        DW_FORM_SEC_OFFSET => { return Some(FormValue::Num(r.u32()? as u64)); },
+        DW_FORM_INDIRECT => {
+            let form = r.uleb()?;
+            return read_attr(r, form, version, addr_size, debug_str);
+        },
+        DW_FORM_ADDR => addr_size,
+        DW_FORM_REF_ADDR if version == 2 => addr_size,
+        DW_FORM_REF_ADDR | DW_FORM_REF4 => 4,
+        DW_FORM_FLAG | DW_FORM_REF1 => 1,
+        DW_FORM_REF2 => 2,
+        DW_FORM_REF8 => 8,
+        DW_FORM_FLAG_PRESENT => 0,
+        DW_FORM_SDATA | DW_FORM_REF_UDATA => {
+            r.uleb()?;
+            0
+        },
+        DW_FORM_BLOCK1 => r.u8()? as usize,
+        DW_FORM_BLOCK2 => r.u16()? as usize,
+        DW_FORM_BLOCK4 => r.u32()? as usize,
+        DW_FORM_EXPRLOC => r.uleb()? as usize,
+        _ => { return read_form(r, form, debug_str, &[]); }
+    };
+    r.take(len)?;
+    Some(FormValue::Other)
+}
+
+/// The line program offset and DW_AT_comp_dir of a DWARF 2 to 4
+/// compilation unit, read from its first entry.
+fn unit_comp_dir(unit: &[u8], debug_abbrev: &[u8], debug_str: &[u8]) -> Option<(usize, String)> {
+    let mut r = Reader::new(unit);
+    let version = r.u16()?;
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let mut abbrev = Reader::new(debug_abbrev.get(r.u32()? as usize..)?);
+    let addr_size = r.u8()? as usize;
+    let code = r.uleb()?;
+    let attrs = loop {
+        let entry = abbrev.uleb()?;
+        if entry == 0 {
+            return None;
+        }
+        abbrev.uleb()?; // tag
+        abbrev.u8()?; // has children
+        let mut attrs = vec![];
+        loop {
+            match (abbrev.uleb()?, abbrev.uleb()?) {
+                (0, 0) => break,
+                attr => attrs.push(attr),
+            }
+        }
+        if entry == code {
+            break attrs;
+        }
+    };
+
+    let mut stmt_list = None;
+    let mut comp_dir = None;
+    for (name, form) in attrs {
+        match (name, read_attr(&mut r, form, version, addr_size, debug_str)?) {
+            (DW_AT_STMT_LIST, FormValue::Num(n)) => { stmt_list = Some(n as usize); },
+            (DW_AT_COMP_DIR, FormValue::Str(s)) => { comp_dir = Some(s); },
+            _ => (),
+        }
+    }
+    Some((stmt_list?, comp_dir?))
+}
+
+/// Maps `.debug_line` offsets to the compilation directory that directory 0
+/// of their line program stands for before DWARF 5. Units that cannot be
+/// read are left out.
+fn comp_dirs(debug_info: &[u8], debug_abbrev: &[u8], debug_str: &[u8]) -> HashMap<usize, String> {
+    let mut dirs = HashMap::new();
+    let mut r = Reader::new(debug_info);
+    while !r.is_empty() {
+        let unit = match r.u32() {
+            Some(len) if len < 0xffff_fff0 => r.take(len as usize),
+            _ => None,
+        };
+        let unit = match unit {
+            Some(unit) => unit,
+            None => break,
+        };
+        dirs.extend(unit_comp_dir(unit, debug_abbrev, debug_str));
+    }
+    dirs
+}
+
+/// Reads a DWARF 5 entry format description and the entries following it,
+/// returning (path, directory index) pairs.
+fn read_entries(r: &mut Reader, debug_str: &[u8], line_str: &[u8]) -> Option<Vec<(String, usize)>> {
+    let format_count = r.u8()?;
+    let mut format = vec![];
+    for _ in 0..format_count {
+        format.push((r.uleb()?, r.uleb()?));
+    }
+    let count = r.uleb()?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut dir = 0;
+        for &(content, form) in &format {
+            match (content, read_form(r, form, debug_str, line_str)?) {
+                (DW_LNCT_PATH, FormValue::Str(s)) => { path = s; },
+                (DW_LNCT_DIRECTORY_INDEX, FormValue::Num(n)) => { dir = n as usize; },
+                _ => (),
+            }
+        }
+        entries.push((path, dir));
+    }
+    Some(entries)
+}
+
+impl DebugInfo {
+    /// Reads the symbol table and `.debug_line` of an ELF file. Either may
+    /// be missing.
+    pub fn from_elf(data: &[u8]) -> Result<DebugInfo, LoadError> {
+        let sections = elf_sections(data)?;
+        let section = |name: &str| sections.iter().find(|s| s.name == name).map_or(&[][..], |s| s.data);
+        let mut info = DebugInfo::default();
+
+        for symtab in sections.iter().filter(|s| s.kind == SHT_SYMTAB) {
+            let strtab = sections.get(symtab.link as usize).map_or(&[][..], |s| s.data);
+            for sym in symtab.data.chunks_exact(ELF_SYM_SIZE) {
+                let kind = sym[12] & 0x0f;
+                let shndx = LittleEndian::read_u16(&sym[14..]);
+                if (kind != STT_FUNC && kind != STT_NOTYPE) || shndx == 0 || shndx >= 0xff00 {
+                    continue;
+                }
+                match cstr_at(strtab, LittleEndian::read_u32(&sym[0..]) as usize) {
+                    Some(name) if !name.is_empty() && !name.starts_with(".L") => {
+                        info.symbols.push(Symbol { addr: LittleEndian::read_u32(&sym[4..]), name });
+                    },
+                    _ => (),
+                }
+            }
+        }
+        info.sort_symbols();
+
+        let debug_str = section(".debug_str");
+        let comp_dirs = comp_dirs(section(".debug_info"), section(".debug_abbrev"), debug_str);
+        let mut r = Reader::new(section(".debug_line"));
+        while !r.is_empty() {
+            if info.read_line_program(&mut r, debug_str, section(".debug_line_str"), &comp_dirs).is_none() {
+                return Err(LoadError::Format("bad .debug_line section".to_string()));
+            }
+        }
+        info.lines.sort_by_key(|l| l.start);
+        Ok(info)
+    }
+
+    /// Parses `address [type] name` lines as printed by `nm` or found in
+    /// System.map; anything else is skipped.
+    pub fn from_symbol_map(text: &str) -> DebugInfo {
+        let mut info = DebugInfo::default();
+        for line in text.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() < 2 {
+                continue;
+            }
+            let addr = words[0].trim_start_matches("0x");
+            if let Ok(addr) = u32::from_str_radix(addr, 16) {
+                info.symbols.push(Symbol { addr, name: words[words.len() - 1].to_string() });
+            }
+        }
+        info.sort_symbols();
+        info
+    }
+
+    fn sort_symbols(&mut self) {
+        self.symbols.sort_by(|a, b| a.addr.cmp(&b.addr).then_with(|| a.name.cmp(&b.name)));
+        self.symbols.dedup_by_key(|s| s.addr);
+    }
+
+    /// The symbol at or before `addr`, and the offset from it.
+    pub fn symbolize(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = self.symbols.partition_point(|s| s.addr <= addr).checked_sub(1)?;
+        let sym = &self.symbols[idx];
+        Some((&sym.name, addr - sym.addr))
+    }
+
+    /// The source line `addr` belongs to.
+    pub fn line(&self, addr: u32) -> Option<&LineRange> {
+        let idx = self.lines.partition_point(|l| l.start <= addr).checked_sub(1)?;
+        Some(&self.lines[idx]).filter(|l| addr < l.end)
+    }
+
+    fn add_file(&mut self, path: String) -> usize {
+        match self.files.iter().position(|f| *f == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Runs one line number program, adding its rows as line ranges.
+    fn read_line_program(&mut self, r: &mut Reader, debug_str: &[u8], line_str: &[u8],
+                         comp_dirs: &HashMap<usize, String>) -> Option<()> {
+        let offset = r.pos;
+        let unit_length = r.u32()? as usize;
+        if unit_length >= 0xffff_fff0 {
+            // 64-bit DWARF is not used for i386
+            return None;
+        }
+        let mut unit = Reader::new(r.take(unit_length)?);
+        let version = unit.u16()?;
+        if !(2..=5).contains(&version) {
+            return Some(());
+        }
+        if version >= 5 {
+            unit.u8()?; // address size
+            unit.u8()?; // segment selector size
+        }
+        let header_length = unit.u32()? as usize;
+        let program_start = unit.pos + header_length;
+        let min_inst_length = unit.u8()? as u32;
+        if version >= 4 {
+            unit.u8()?; // maximum operations per instruction
+        }
+        unit.u8()?; // default is_stmt
+        let line_base = unit.u8()? as i8 as i64;
+        let line_range = unit.u8()?;
+        let opcode_base = unit.u8()?;
+        let opcode_lengths = unit.take(opcode_base.saturating_sub(1) as usize)?.to_vec();
+        if line_range == 0 {
+            return None;
+        }
+
+        // file numbers are 1-based before DWARF 5
+        let mut files: Vec<usize> = vec![];
+        if version >= 5 {
+            let dirs = read_entries(&mut unit, debug_str, line_str)?;
+            for (name, dir) in read_entries(&mut unit, debug_str, line_str)? {
+                let dir = dirs.get(dir).map_or("", |d| d.0.as_str());
+                files.push(self.add_file(join_path(dir, &name)));
+            }
+        } else {
+            let comp_dir = comp_dirs.get(&offset).map_or("", |d| d.as_str());
+            let mut dirs = vec![comp_dir.to_string()];
+            loop {
+                let dir = unit.cstr()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(join_path(comp_dir, &dir));
+            }
+            files.push(usize::MAX);
+            loop {
+                let name = unit.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = unit.uleb()? as usize;
+                unit.uleb()?;
+                unit.uleb()?;
+                let dir = dirs.get(dir).map_or("", |d| d.as_str());
+                files.push(self.add_file(join_path(dir, &name)));
+            }
+        }
+        unit.pos = program_start;
+
+        let mut address = 0u32;
+        let mut file = 1usize;
+        let mut line = 1i64;
+        let mut rows: Vec<(u32, usize, u32)> = vec![];
+        while !unit.is_empty() {
+            let op = unit.u8()?;
+            let mut emit = false;
+            let mut end_sequence = false;
+            if op >= opcode_base {
+                let adjusted = op - opcode_base;
+                address = address.wrapping_add((adjusted / line_range) as u32 * min_inst_length);
+                line = line.checked_add(line_base + (adjusted % line_range) as i64)?;
+                emit = true;
+            } else if op == 0 {
+                let len = unit.uleb()? as usize;
+                let mut ext = Reader::new(unit.take(len)?);
+                match ext.u8() {
+                    Some(DW_LNE_END_SEQUENCE) => { end_sequence = true; },
+                    Some(DW_LNE_SET_ADDRESS) => { address = ext.u32()?; },
+                    Some(DW_LNE_DEFINE_FILE) => {
+                        let name = ext.cstr()?;
+                        files.push(self.add_file(name));
+                    },
+                    _ => (),
+                }
+            } else {
+                match op {
+                    DW_LNS_COPY => { emit = true; },
+                    DW_LNS_ADVANCE_PC => {
+                        address = address.wrapping_add((unit.uleb()? as u32).wrapping_mul(min_inst_length));
+                    },
+                    DW_LNS_ADVANCE_LINE => { line = line.checked_add(unit.sleb()?)?; },
+                    DW_LNS_SET_FILE => { file = unit.uleb()? as usize; },
+                    // rows that are not statements still own their addresses
+                    DW_LNS_NEGATE_STMT => (),
+                    DW_LNS_CONST_ADD_PC => {
+                        let adjusted = 255 - opcode_base;
+                        address = address.wrapping_add((adjusted / line_range) as u32 * min_inst_length);
+                    },
+                    DW_LNS_FIXED_ADVANCE_PC => {
+                        address = address.wrapping_add(unit.u16()? as u32);
+                    },
+                    _ => {
+                        for _ in 0..opcode_lengths[op as usize - 1] {
+                            unit.uleb()?;
+                        }
+                    }
+                }
+            }
+
+            if emit {
+                if let Some(&file) = files.get(file).filter(|&&f| f != usize::MAX) {
+                    rows.push((address, file, line.max(0) as u32));
+                }
+            }
+            if end_sequence {
+                for (i, &(start, file, line)) in rows.iter().enumerate() {
+                    let end = rows.get(i + 1).map_or(address, |r| r.0);
+                    if end > start {
+                        self.lines.push(LineRange { start, end, file, line });
+                    }
+                }
+                rows.clear();
+                address = 0;
+                file = 1;
+                line = 1;
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(info: &DebugInfo) -> Vec<(u32, u32, u32)> {
+        info.lines.iter().map(|l| (l.start, l.end, l.line)).collect()
+    }
+
+    #[test]
+    fn leb128() {
+        assert_eq!(Reader::new(&[0x02]).uleb(), Some(2));
+        assert_eq!(Reader::new(&[0xe5, 0x8e, 0x26]).uleb(), Some(624485));
+        assert_eq!(Reader::new(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]).uleb(),
+                   Some(0));
+        assert_eq!(Reader::new(&[0x80]).uleb(), None);
+        assert_eq!(Reader::new(&[0x02]).sleb(), Some(2));
+        assert_eq!(Reader::new(&[0x7f]).sleb(), Some(-1));
+        assert_eq!(Reader::new(&[0x80, 0x7f]).sleb(), Some(-128));
+        assert_eq!(Reader::new(&[0xc0, 0xbb, 0x78]).sleb(), Some(-123456));
+        assert_eq!(Reader::new(&[0xff]).sleb(), None);
+
+        let mut r = Reader::new(&[0x81, 0x01, 0x7e]);
+        assert_eq!(r.uleb(), Some(129));
+        assert_eq!(r.sleb(), Some(-2));
+        assert!(r.is_empty());
+    }
+
+    /// A DWARF 2 line program for `inc/a.c` covering 0x1000..0x1028.
+    fn line_program() -> Vec<u8> {
+        let mut header = vec![1, 1, 0xfb, 14, 10, 0, 1, 1, 1, 1, 0, 0, 0, 1];
+        header.extend_from_slice(b"inc\0\0a.c\0\x01\0\0\0");
+        let program = [
+            0, 5, DW_LNE_SET_ADDRESS, 0x00, 0x10, 0, 0,
+            DW_LNS_COPY,
+            DW_LNS_NEGATE_STMT,
+            // address +2, line +1
+            10 + 6 + 2 * 14,
+            // address +17
+            DW_LNS_CONST_ADD_PC,
+            DW_LNS_ADVANCE_LINE, 0x7f,
+            DW_LNS_FIXED_ADVANCE_PC, 0x10, 0,
+            DW_LNS_COPY,
+            DW_LNS_ADVANCE_PC, 5,
+            0, 1, DW_LNE_END_SEQUENCE,
+        ];
+        let mut unit = vec![2, 0];
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(&program);
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&unit);
+        data
+    }
+
+    fn run(data: &[u8], comp_dirs: &HashMap<usize, String>) -> Option<DebugInfo> {
+        let mut info = DebugInfo::default();
+        let mut r = Reader::new(data);
+        while !r.is_empty() {
+            info.read_line_program(&mut r, &[], &[], comp_dirs)?;
+        }
+        info.lines.sort_by_key(|l| l.start);
+        Some(info)
+    }
+
+    #[test]
+    fn line_program_state_machine() {
+        let info = run(&line_program(), &HashMap::new()).unwrap();
+        assert_eq!(info.files, ["inc/a.c"]);
+        // the row after is_stmt was cleared keeps its range
+        assert_eq!(ranges(&info), [(0x1000, 0x1002, 1), (0x1002, 0x1023, 2), (0x1023, 0x1028, 1)]);
+        assert_eq!(info.line(0x1010).map(|l| l.line), Some(2));
+        assert!(info.line(0x1028).is_none());
+
+        let comp_dirs = HashMap::from([(0, "/src".to_string())]);
+        assert_eq!(run(&line_program(), &comp_dirs).unwrap().files, ["/src/inc/a.c"]);
+
+        let program = line_program();
+        assert!(run(&program[..program.len() - 2], &HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn line_program_overflow() {
+        let mut program = line_program();
+        let end = program.len() - 3;
+        // advance the line to i64::MAX, then once more
+        let mut ops = vec![DW_LNS_ADVANCE_LINE, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+                           DW_LNS_ADVANCE_LINE, 0x01];
+        // a huge address advance wraps instead
+        ops.extend_from_slice(&[DW_LNS_ADVANCE_PC, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+        program.splice(end..end, ops.iter().copied());
+        let len = program.len() as u32 - 4;
+        program[..4].copy_from_slice(&len.to_le_bytes());
+        assert!(run(&program, &HashMap::new()).is_none());
+
+        let mut program = line_program();
+        program.splice(end..end, ops[13..].iter().copied());
+        let len = program.len() as u32 - 4;
+        program[..4].copy_from_slice(&len.to_le_bytes());
+        assert!(run(&program, &HashMap::new()).is_some());
+    }
+
+    #[test]
+    fn debug_line_fixture() {
+        let data = include_bytes!("../../tests/data/lines.debug_line");
+        let comp_dirs = HashMap::from([(0, "/src".to_string())]);
+        let info = run(data, &comp_dirs).unwrap();
+        assert_eq!(info.files, ["/src/lines.c"]);
+        assert_eq!(info.lines.first().map(|l| (l.start, l.line)), Some((0x8049000, 9)));
+        assert_eq!(info.lines.last().map(|l| (l.end, l.line)), Some((0x804903e, 17)));
+        assert_eq!(info.line(0x804900b).map(|l| l.line), Some(14));
+        assert_eq!(info.line(0x8049020).map(|l| l.line), Some(16));
+    }
+
+    #[test]
+    fn elf_with_comp_dir_and_bss() {
+        // .bss is NOBITS with an offset past the end of the file
+        let info = DebugInfo::from_elf(include_bytes!("../../tests/data/lines.elf")).unwrap();
+        assert_eq!(info.files, ["/src/lines.c"]);
+        assert_eq!(info.symbolize(0x8049010), Some(("_start", 5)));
+        assert_eq!(info.symbolize(0x8049004), Some(("square", 4)));
+        assert_eq!(info.line(0x804900b).map(|l| l.line), Some(14));
+    }
+}
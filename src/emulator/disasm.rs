@@ -0,0 +1,428 @@
+use super::{Emulator, modrm::ModRM};
+
+pub const REGS32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+pub const REGS8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const GROUP_FF: [&str; 8] = ["inc", "dec", "call", "call far", "jmp", "jmp far", "push", "(bad)"];
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "c", "nc", "z", "nz", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+/// Executes one instruction, starting with EIP at its opcode byte.
+pub type Handler = fn(&mut Emulator);
+
+/// Conditional short jumps the CPU implements, by condition code.
+const JCC_REL8: [Option<Handler>; 16] = [
+    Some(Emulator::jo), Some(Emulator::jno), Some(Emulator::jc), Some(Emulator::jnc),
+    Some(Emulator::jz), Some(Emulator::jnz), None, None,
+    Some(Emulator::js), Some(Emulator::jns), None, None,
+    Some(Emulator::jl), None, Some(Emulator::jle), None,
+];
+
+/// An entry of an opcode map: what the disassembler prints and, if the CPU
+/// implements the instruction, how the executor runs it.
+#[derive(Clone, Copy)]
+pub(super) struct Opcode {
+    mnemonic: &'static str,
+    form: Form,
+    pub exec: Option<Handler>,
+}
+
+fn row(mnemonic: &'static str, form: Form) -> Opcode {
+    Opcode { mnemonic, form, exec: None }
+}
+
+impl Opcode {
+    fn exec(self, exec: Handler) -> Opcode {
+        Opcode { exec: Some(exec), ..self }
+    }
+}
+
+/// Operand encoding of an opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Form {
+    None,
+    Rm8R8,
+    Rm32R32,
+    R8Rm8,
+    R32Rm32,
+    /// Opcode extension in the reg field picks the mnemonic from a group.
+    Group(&'static [&'static str; 8], Operand, Operand),
+    AlImm8,
+    EaxImm32,
+    /// Register in the low three opcode bits.
+    Reg32,
+    Reg8Imm8,
+    Reg32Imm32,
+    EaxReg32,
+    Rm8Imm8,
+    Rm32Imm32,
+    Imm8,
+    Imm16,
+    Imm32,
+    Rel8,
+    Rel32,
+    AlDx,
+    EaxDx,
+    DxAl,
+    DxEax,
+    AxDx,
+    DxAx,
+    AlImmPort,
+    EaxImmPort,
+    ImmPortAl,
+    ImmPortEax,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    None,
+    Rm8,
+    Rm32,
+    Imm8,
+    SImm8,
+    Imm32,
+}
+
+/// One decoded instruction.
+#[derive(Debug, Default, Clone)]
+pub struct Instruction {
+    pub addr: u32,
+    pub bytes: Vec<u8>,
+    /// Intel-syntax text, e.g. `mov eax, dword ptr [ebx+0x4]`.
+    pub text: String,
+}
+
+impl Instruction {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Whether the instruction may continue anywhere but the next one:
+    /// jumps, calls, returns and software interrupts, taken or not.
+    pub fn is_control_transfer(&self) -> bool {
+        let bytes = match self.bytes.first() {
+            Some(0x65) => &self.bytes[1..],
+            _ => &self.bytes[..],
+        };
+        match *bytes {
+            [0x70..=0x7f, ..] | [0x9a, ..] | [0xc2 | 0xc3 | 0xca | 0xcb, ..]
+                | [0xcc..=0xcf, ..] | [0xe0..=0xe3, ..] | [0xe8..=0xeb, ..] => true,
+            [0x0f, op, ..] => matches!(op, 0x05 | 0x07 | 0x34 | 0x35 | 0x80..=0x8f),
+            [0xff, modrm, ..] => matches!((modrm >> 3) & 0x07, 2..=5),
+            _ => false,
+        }
+    }
+}
+
+/// The one-byte opcode map. The executor's dispatch table is built from it.
+pub(super) fn one_byte(op: u8) -> Option<Opcode> {
+    let opcode = match op {
+        0x00..=0x3f if op & 0x07 < 6 => {
+            let mnemonic = ALU[(op >> 3) as usize];
+            let form = [Form::Rm8R8, Form::Rm32R32, Form::R8Rm8, Form::R32Rm32,
+                        Form::AlImm8, Form::EaxImm32][(op & 0x07) as usize];
+            let exec: Option<Handler> = match op {
+                0x01 => Some(Emulator::add_rm32_r32),
+                0x31 => Some(Emulator::xor_rm32_r32),
+                0x3b => Some(Emulator::cmp_r32_rm32),
+                0x3c => Some(Emulator::cmp_al_imm8),
+                0x3d => Some(Emulator::cmp_eax_imm8),
+                _ => None,
+            };
+            Opcode { exec, ..row(mnemonic, form) }
+        },
+        // the escape byte and the operand-size prefix select another map;
+        // the disassembler handles them before looking here
+        0x0f => row("", Form::None).exec(Emulator::code_0f),
+        0x66 => row("", Form::None).exec(Emulator::code_66),
+        0x40..=0x47 => row("inc", Form::Reg32).exec(Emulator::inc_r32),
+        0x48..=0x4f => row("dec", Form::Reg32),
+        0x50..=0x57 => row("push", Form::Reg32).exec(Emulator::push_r32),
+        0x58..=0x5f => row("pop", Form::Reg32).exec(Emulator::pop_r32),
+        0x60 => row("pushad", Form::None),
+        0x61 => row("popad", Form::None),
+        0x68 => row("push", Form::Imm32).exec(Emulator::push_imm32),
+        0x6a => row("push", Form::Imm8).exec(Emulator::push_imm8),
+        0x70..=0x7f => Opcode {
+            exec: JCC_REL8[(op & 0x0f) as usize],
+            ..row(CONDITIONS[(op & 0x0f) as usize], Form::Rel8)
+        },
+        0x80 => row("", Form::Group(&ALU, Operand::Rm8, Operand::Imm8)),
+        0x81 => row("", Form::Group(&ALU, Operand::Rm32, Operand::Imm32)),
+        0x83 => row("", Form::Group(&ALU, Operand::Rm32, Operand::SImm8)).exec(Emulator::code_83),
+        0x84 => row("test", Form::Rm8R8),
+        0x85 => row("test", Form::Rm32R32),
+        0x86 => row("xchg", Form::Rm8R8),
+        0x87 => row("xchg", Form::Rm32R32),
+        0x88 => row("mov", Form::Rm8R8).exec(Emulator::mov_rm8_r8),
+        0x89 => row("mov", Form::Rm32R32).exec(Emulator::mov_rm32_r32),
+        0x8a => row("mov", Form::R8Rm8).exec(Emulator::mov_r8_rm8),
+        0x8b => row("mov", Form::R32Rm32).exec(Emulator::mov_r32_rm32),
+        0x8d => row("lea", Form::R32Rm32),
+        0x90 => row("nop", Form::None),
+        0x91..=0x97 => row("xchg", Form::EaxReg32),
+        0x98 => row("cwde", Form::None),
+        0x99 => row("cdq", Form::None),
+        0x9c => row("pushfd", Form::None),
+        0x9d => row("popfd", Form::None),
+        0xa8 => row("test", Form::AlImm8),
+        0xa9 => row("test", Form::EaxImm32),
+        0xb0..=0xb7 => row("mov", Form::Reg8Imm8).exec(Emulator::mov_r8_imm8),
+        0xb8..=0xbf => row("mov", Form::Reg32Imm32).exec(Emulator::mov_r32_imm32),
+        0xc2 => row("ret", Form::Imm16),
+        0xc3 => row("ret", Form::None).exec(Emulator::ret),
+        0xc6 => row("mov", Form::Rm8Imm8),
+        0xc7 => row("mov", Form::Rm32Imm32).exec(Emulator::mov_rm32_imm32),
+        0xc9 => row("leave", Form::None).exec(Emulator::leave),
+        0xcb => row("retf", Form::None).exec(Emulator::far_ret),
+        0xcc => row("int3", Form::None),
+        0xcd => row("int", Form::Imm8).exec(Emulator::int),
+        0xcf => row("iretd", Form::None).exec(Emulator::iret),
+        0xe4 => row("in", Form::AlImmPort),
+        0xe5 => row("in", Form::EaxImmPort),
+        0xe6 => row("out", Form::ImmPortAl),
+        0xe7 => row("out", Form::ImmPortEax),
+        0xe8 => row("call", Form::Rel32).exec(Emulator::call_rel32),
+        0xe9 => row("jmp", Form::Rel32).exec(Emulator::near_jump),
+        0xeb => row("jmp", Form::Rel8).exec(Emulator::short_jump),
+        0xec => row("in", Form::AlDx).exec(Emulator::in_al_dx),
+        0xed => row("in", Form::EaxDx).exec(Emulator::in_eax_dx),
+        0xee => row("out", Form::DxAl).exec(Emulator::out_dx_al),
+        0xef => row("out", Form::DxEax).exec(Emulator::out_dx_eax),
+        0xf4 => row("hlt", Form::None),
+        0xfa => row("cli", Form::None),
+        0xfb => row("sti", Form::None),
+        0xfc => row("cld", Form::None),
+        0xfd => row("std", Form::None),
+        0xff => row("", Form::Group(&GROUP_FF, Operand::Rm32, Operand::None)).exec(Emulator::code_ff),
+        _ => { return None; }
+    };
+    Some(opcode)
+}
+
+/// Opcodes after the 0x0f escape byte.
+pub(super) fn two_byte(op: u8) -> Option<Opcode> {
+    match op {
+        0x05 => Some(row("syscall", Form::None)),
+        0x31 => Some(row("rdtsc", Form::None)),
+        0x34 => Some(row("sysenter", Form::None).exec(Emulator::sysenter)),
+        0xa2 => Some(row("cpuid", Form::None)),
+        0x80..=0x8f => Some(row(CONDITIONS[(op & 0x0f) as usize], Form::Rel32)),
+        _ => None,
+    }
+}
+
+/// Opcodes after a 0x66 operand-size prefix.
+pub(super) fn operand_size_prefixed(op: u8) -> Option<Opcode> {
+    match op {
+        0xed => Some(row("in", Form::AxDx).exec(Emulator::in_ax_dx)),
+        0xef => Some(row("out", Form::DxAx).exec(Emulator::out_dx_ax)),
+        _ => None,
+    }
+}
+
+/// Reads instruction bytes for the decoder, remembering how many it used.
+struct Reader<F: Fn(u32) -> Option<u8>> {
+    fetch: F,
+    addr: u32,
+    bytes: Vec<u8>,
+    truncated: bool,
+    /// Whether a GS segment override prefix came first.
+    gs: bool,
+}
+
+impl<F: Fn(u32) -> Option<u8>> Reader<F> {
+    fn peek(&self, i: usize) -> u8 {
+        (self.fetch)(self.addr.wrapping_add((self.bytes.len() + i) as u32)).unwrap_or(0)
+    }
+
+    fn take(&mut self, n: usize) -> u32 {
+        let mut val = 0;
+        for i in 0..n {
+            let addr = self.addr.wrapping_add(self.bytes.len() as u32);
+            let b = (self.fetch)(addr).unwrap_or_else(|| {
+                self.truncated = true;
+                0
+            });
+            self.bytes.push(b);
+            val |= (b as u32) << (i * 8);
+        }
+        val
+    }
+
+    fn modrm(&mut self) -> ModRM {
+        let mut modrm = ModRM::new();
+        let len = modrm.decode(|i| self.peek(i));
+        for _ in 0..len {
+            self.take(1);
+        }
+        modrm
+    }
+
+    fn next_addr(&self) -> u32 {
+        self.addr.wrapping_add(self.bytes.len() as u32)
+    }
+}
+
+fn reg_field(modrm: &ModRM) -> usize {
+    unsafe { modrm.op_reg.reg_idx as usize }
+}
+
+fn hex(val: u32) -> String {
+    format!("{:#x}", val)
+}
+
+/// Decodes the instruction at `addr`, reading memory through `fetch`.
+/// Opcodes it does not know come out as `db 0x..`.
+pub fn disassemble<F: Fn(u32) -> Option<u8>>(fetch: F, addr: u32) -> Instruction {
+    let mut r = Reader { fetch, addr, bytes: vec![], truncated: false, gs: false };
+    let mut op = r.take(1) as u8;
+    if op == 0x65 {
+        r.gs = true;
+        op = r.take(1) as u8;
+    }
+    let decoded = match op {
+        0x0f => two_byte(r.take(1) as u8),
+        0x66 => operand_size_prefixed(r.take(1) as u8),
+        _ => one_byte(op),
+    };
+
+    let text = match decoded {
+        None => {
+            r.bytes.truncate(1);
+            r.gs = false;
+            format!("db {:#04x}", r.bytes[0])
+        },
+        Some(Opcode { mnemonic, form, .. }) => {
+            let last = *r.bytes.last().unwrap() as usize;
+            let operands = match form {
+                Form::None => String::new(),
+                Form::Rm8R8 => {
+                    let m = r.modrm();
+                    format!("{}, {}", m.rm_operand(&REGS8, "byte"), REGS8[reg_field(&m)])
+                },
+                Form::Rm32R32 => {
+                    let m = r.modrm();
+                    format!("{}, {}", m.rm_operand(&REGS32, "dword"), REGS32[reg_field(&m)])
+                },
+                Form::R8Rm8 => {
+                    let m = r.modrm();
+                    format!("{}, {}", REGS8[reg_field(&m)], m.rm_operand(&REGS8, "byte"))
+                },
+                Form::R32Rm32 => {
+                    let m = r.modrm();
+                    format!("{}, {}", REGS32[reg_field(&m)], m.rm_operand(&REGS32, "dword"))
+                },
+                Form::Group(names, dst, src) => {
+                    let m = r.modrm();
+                    let name = names[reg_field(&m)];
+                    let dst = match dst {
+                        Operand::Rm8 => m.rm_operand(&REGS8, "byte"),
+                        _ => m.rm_operand(&REGS32, "dword"),
+                    };
+                    let src = match src {
+                        Operand::Imm8 => format!(", {}", hex(r.take(1))),
+                        Operand::SImm8 => format!(", {}", hex(r.take(1) as u8 as i8 as i32 as u32)),
+                        Operand::Imm32 => format!(", {}", hex(r.take(4))),
+                        _ => String::new(),
+                    };
+                    return finish(r, format!("{} {}{}", name, dst, src));
+                },
+                Form::AlImm8 => format!("al, {}", hex(r.take(1))),
+                Form::EaxImm32 => format!("eax, {}", hex(r.take(4))),
+                Form::Reg32 => REGS32[last & 0x07].to_string(),
+                Form::Reg8Imm8 => format!("{}, {}", REGS8[last & 0x07], hex(r.take(1))),
+                Form::Reg32Imm32 => format!("{}, {}", REGS32[last & 0x07], hex(r.take(4))),
+                Form::EaxReg32 => format!("eax, {}", REGS32[last & 0x07]),
+                Form::Rm8Imm8 => {
+                    let m = r.modrm();
+                    format!("{}, {}", m.rm_operand(&REGS8, "byte"), hex(r.take(1)))
+                },
+                Form::Rm32Imm32 => {
+                    let m = r.modrm();
+                    format!("{}, {}", m.rm_operand(&REGS32, "dword"), hex(r.take(4)))
+                },
+                Form::Imm8 => hex(r.take(1)),
+                Form::Imm16 => hex(r.take(2)),
+                Form::Imm32 => hex(r.take(4)),
+                Form::Rel8 => {
+                    let rel = r.take(1) as u8 as i8 as i32;
+                    hex(r.next_addr().wrapping_add(rel as u32))
+                },
+                Form::Rel32 => {
+                    let rel = r.take(4);
+                    hex(r.next_addr().wrapping_add(rel))
+                },
+                Form::AlDx => "al, dx".to_string(),
+                Form::EaxDx => "eax, dx".to_string(),
+                Form::DxAl => "dx, al".to_string(),
+                Form::DxEax => "dx, eax".to_string(),
+                Form::AxDx => "ax, dx".to_string(),
+                Form::DxAx => "dx, ax".to_string(),
+                Form::AlImmPort => format!("al, {}", hex(r.take(1))),
+                Form::EaxImmPort => format!("eax, {}", hex(r.take(1))),
+                Form::ImmPortAl => format!("{}, al", hex(r.take(1))),
+                Form::ImmPortEax => format!("{}, eax", hex(r.take(1))),
+            };
+            let mnemonic = match form {
+                Form::Rel8 | Form::Rel32 if mnemonic != "jmp" && mnemonic != "call" => {
+                    format!("j{}", mnemonic)
+                },
+                _ => mnemonic.to_string(),
+            };
+            if operands.is_empty() { mnemonic } else { format!("{} {}", mnemonic, operands) }
+        }
+    };
+    finish(r, text)
+}
+
+fn finish<F: Fn(u32) -> Option<u8>>(r: Reader<F>, text: String) -> Instruction {
+    let text = if r.gs { format!("gs {}", text) } else { text };
+    let text = if r.truncated { format!("{} (truncated)", text) } else { text };
+    Instruction { addr: r.addr, bytes: r.bytes, text }
+}
+
+/// Decodes consecutive instructions from a byte buffer mapped at `base`.
+pub fn disassemble_bytes(data: &[u8], base: u32) -> Vec<Instruction> {
+    let mut out = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let inst = disassemble(|addr| data.get(addr.wrapping_sub(base) as usize).copied(),
+                               base.wrapping_add(offset as u32));
+        offset += inst.len().max(1);
+        out.push(inst);
+    }
+    out
+}
+
+impl Emulator {
+    /// Decodes the instruction at `addr` in guest memory.
+    pub fn disassemble(&self, addr: u32) -> Instruction {
+        disassemble(|a| self.peek8(a), addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn executed_opcodes_disassemble() {
+        let instructions = Emulator::new(0x1000, 0, 0).init_instructions();
+        for (op, exec) in instructions.iter().enumerate() {
+            if exec.is_some() && op != 0x0f && op != 0x66 {
+                let inst = disassemble_bytes(&[op as u8, 0, 0, 0, 0, 0], 0).remove(0);
+                assert!(!inst.text.starts_with("db "), "{:#04x}", op);
+            }
+        }
+        assert_eq!(disassemble_bytes(&[0x66, 0xed], 0)[0].text, "in ax, dx");
+        assert_eq!(disassemble_bytes(&[0x8b, 0x04, 0x8b], 0)[0].text,
+                   "mov eax, dword ptr [ebx+ecx*4]");
+    }
+}
@@ -0,0 +1,327 @@
+use std::io;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use super::{Emulator, Eflags, RegIdx};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const DISK_RET_SUCCESS: u8 = 0x00;
+const DISK_RET_EPARAM: u8 = 0x01;
+const DISK_RET_ENOTFOUND: u8 = 0x04;
+const DISK_RET_EBOUNDARY: u8 = 0x09;
+const DISK_RET_EWRITE: u8 = 0xcc;
+
+/// Standard floppy formats as (size in KiB, cylinders, heads, sectors).
+const FLOPPY_GEOMETRIES: [(usize, u32, u32, u32); 8] = [
+    (160, 40, 1, 8), (180, 40, 1, 9), (320, 40, 2, 8), (360, 40, 2, 9),
+    (720, 80, 2, 9), (1200, 80, 2, 15), (1440, 80, 2, 18), (2880, 80, 2, 36),
+];
+
+/// A raw disk image served to the guest through INT 13h. Writes go to the
+/// in-memory copy and straight through to `path` when there is one.
+#[derive(Debug, Default, Clone)]
+pub struct Disk {
+    pub drive: u8,
+    pub image: Vec<u8>,
+    pub path: Option<String>,
+    pub cylinders: u32,
+    pub heads: u32,
+    pub sectors: u32,
+}
+
+impl Disk {
+    pub fn new(drive: u8, image: Vec<u8>) -> Disk {
+        let kib = image.len() / 1024;
+        let total = image.len().div_ceil(SECTOR_SIZE) as u32;
+        let (cylinders, heads, sectors) = match FLOPPY_GEOMETRIES.iter()
+            .find(|g| g.0 == kib && drive < 0x80) {
+            Some(&(_, c, h, s)) => (c, h, s),
+            None if drive < 0x80 => (80, 2, 18),
+            None => (total.div_ceil(16 * 63).clamp(1, 1024), 16, 63),
+        };
+
+        Disk {
+            drive,
+            image,
+            path: None,
+            cylinders,
+            heads,
+            sectors,
+        }
+    }
+
+    pub fn open(drive: u8, path: &str) -> io::Result<Disk> {
+        let mut disk = Disk::new(drive, fs::read(path)?);
+        disk.path = Some(path.to_string());
+        Ok(disk)
+    }
+
+    pub fn is_floppy(&self) -> bool {
+        self.drive < 0x80
+    }
+
+    pub fn total_sectors(&self) -> u64 {
+        (self.image.len() / SECTOR_SIZE) as u64
+    }
+
+    pub fn chs_to_lba(&self, cylinder: u32, head: u32, sector: u32) -> Option<u64> {
+        if sector == 0 || sector > self.sectors || head >= self.heads {
+            return None;
+        }
+        Some(((cylinder * self.heads + head) * self.sectors + sector - 1) as u64)
+    }
+
+    pub fn read_sectors(&self, lba: u64, count: usize) -> Option<&[u8]> {
+        if lba.saturating_add(count as u64) > self.total_sectors() {
+            return None;
+        }
+        let start = lba as usize * SECTOR_SIZE;
+        let end = start + count * SECTOR_SIZE;
+        Some(&self.image[start..end])
+    }
+
+    /// Updates the image, and the file behind it if `write_through`.
+    pub fn write_sectors(&mut self, lba: u64, data: &[u8], write_through: bool) -> io::Result<()> {
+        let start = lba as usize * SECTOR_SIZE;
+        if start + data.len() > self.image.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sector out of range"));
+        }
+        self.image[start..start + data.len()].copy_from_slice(data);
+
+        if let Some(path) = self.path.as_ref().filter(|_| write_through) {
+            let mut f = OpenOptions::new().write(true).open(path)?;
+            f.seek(SeekFrom::Start(start as u64))?;
+            f.write_all(data)?;
+        }
+        Ok(())
+    }
+}
+
+impl Emulator {
+    pub fn attach_disk(&mut self, disk: Disk) {
+        self.disks.retain(|d| d.drive != disk.drive);
+        self.disks.push(disk);
+        self.update_bios_data_area();
+        self.update_cmos();
+    }
+
+    pub fn disk(&self, drive: u8) -> Option<&Disk> {
+        self.disks.iter().find(|d| d.drive == drive)
+    }
+
+    fn disk_index(&self, drive: u8) -> Option<usize> {
+        self.disks.iter().position(|d| d.drive == drive)
+    }
+
+    fn disk_return(&mut self, status: u8) {
+        self.disk_status = status;
+        self.set_register8(RegIdx::ah() as i32, status);
+        self.set_eflags(Eflags::Carry, status != DISK_RET_SUCCESS);
+    }
+
+    /// Copies `count` sectors starting at `lba` between the disk and guest
+    /// memory at `buffer`, returning the INT 13h status. The whole buffer
+    /// has to lie in RAM.
+    fn disk_transfer(&mut self, idx: usize, lba: u64, count: usize, buffer: u32,
+                     write: bool) -> u8 {
+        let len = count * SECTOR_SIZE;
+        if count == 0 {
+            return DISK_RET_EPARAM;
+        }
+        if buffer as usize + len > self.memory.len() {
+            return DISK_RET_EBOUNDARY;
+        }
+        if write {
+            let data: Vec<u8> = (0..len as u32)
+                .map(|i| self.get_memory8(buffer + i) as u8)
+                .collect();
+            if lba.saturating_add(count as u64) > self.disks[idx].total_sectors() {
+                return DISK_RET_ENOTFOUND;
+            }
+            let write_through = !self.repeating();
+            match self.disks[idx].write_sectors(lba, &data, write_through) {
+                Ok(()) => DISK_RET_SUCCESS,
+                Err(_) => DISK_RET_EWRITE,
+            }
+        } else {
+            let data = match self.disks[idx].read_sectors(lba, count) {
+                Some(data) => data.to_vec(),
+                None => { return DISK_RET_ENOTFOUND; }
+            };
+            for (i, b) in data.into_iter().enumerate() {
+                self.set_memory8(buffer + i as u32, b as u32);
+            }
+            DISK_RET_SUCCESS
+        }
+    }
+
+    /// AH=02h/03h. The CPU model has no segmentation, so here and in the
+    /// disk address packet buffers are flat 32-bit addresses: ES:BX is EBX.
+    fn bios_disk_chs(&mut self, idx: usize, write: bool) {
+        let count = self.get_register8(RegIdx::al()) as usize;
+        let cl = self.get_register8(RegIdx::cl()) as u32;
+        let cylinder = self.get_register8(RegIdx::ch()) as u32 | ((cl & 0xc0) << 2);
+        let sector = cl & 0x3f;
+        let head = self.get_register8(RegIdx::dh()) as u32;
+        let buffer = self.get_register32(RegIdx::Ebx as u8);
+
+        let status = match self.disks[idx].chs_to_lba(cylinder, head, sector) {
+            Some(lba) => self.disk_transfer(idx, lba, count, buffer, write),
+            None => DISK_RET_ENOTFOUND,
+        };
+        let done = if status == DISK_RET_SUCCESS { count as u8 } else { 0 };
+        self.set_register8(RegIdx::al() as i32, done);
+        self.disk_return(status);
+    }
+
+    /// AH=42h/43h with the disk address packet at DS:SI (flat ESI). Its
+    /// buffer pointer is read as a flat address like EBX for AH=02h, unless
+    /// it is 0xffffffff and the 64-bit address at offset 16 is used.
+    fn bios_disk_extended(&mut self, idx: usize, write: bool) {
+        let dap = self.get_register32(RegIdx::Esi as u8);
+        let count = self.get_memory8(dap + 2) | self.get_memory8(dap + 3) << 8;
+        let lba = self.get_memory32(dap + 8) as u64
+            | (self.get_memory32(dap + 12) as u64) << 32;
+
+        let buffer = match self.get_memory32(dap + 4) {
+            0xffff_ffff => self.get_memory32(dap + 16),
+            flat => flat,
+        };
+
+        let status = self.disk_transfer(idx, lba, count as usize, buffer, write);
+        if status != DISK_RET_SUCCESS {
+            self.set_memory8(dap + 2, 0);
+            self.set_memory8(dap + 3, 0);
+        }
+        self.disk_return(status);
+    }
+
+    fn bios_disk_parameters(&mut self, idx: usize) {
+        let disk = &self.disks[idx];
+        let max_cylinder = disk.cylinders - 1;
+        let (heads, sectors, floppy) = (disk.heads, disk.sectors, disk.is_floppy());
+        let drives = self.disks.iter().filter(|d| d.is_floppy() == floppy).count();
+
+        self.set_register8(RegIdx::ch() as i32, (max_cylinder & 0xff) as u8);
+        self.set_register8(RegIdx::cl() as i32,
+                           (sectors as u8 & 0x3f) | ((max_cylinder >> 2) & 0xc0) as u8);
+        self.set_register8(RegIdx::dh() as i32, (heads - 1) as u8);
+        self.set_register8(RegIdx::dl() as i32, drives as u8);
+        if floppy {
+            self.set_register8(RegIdx::bl() as i32, 0x04);
+        }
+        self.set_register8(RegIdx::al() as i32, 0);
+        self.disk_return(DISK_RET_SUCCESS);
+    }
+
+    /// AH=48h: fills the result buffer at DS:SI (flat ESI).
+    fn bios_disk_extended_parameters(&mut self, idx: usize) {
+        let buf = self.get_register32(RegIdx::Esi as u8);
+        let size = self.get_memory8(buf) | self.get_memory8(buf + 1) << 8;
+        if size < 0x1a {
+            self.disk_return(DISK_RET_EPARAM);
+            return;
+        }
+
+        let disk = &self.disks[idx];
+        let (cylinders, heads, sectors, total) =
+            (disk.cylinders, disk.heads, disk.sectors, disk.total_sectors());
+        self.set_memory8(buf, 0x1a);
+        self.set_memory8(buf + 1, 0);
+        self.set_memory8(buf + 2, 0x02);
+        self.set_memory8(buf + 3, 0);
+        self.set_memory32(buf + 4, cylinders);
+        self.set_memory32(buf + 8, heads);
+        self.set_memory32(buf + 12, sectors);
+        self.set_memory32(buf + 16, total as u32);
+        self.set_memory32(buf + 20, (total >> 32) as u32);
+        self.set_memory8(buf + 24, (SECTOR_SIZE & 0xff) as u32);
+        self.set_memory8(buf + 25, (SECTOR_SIZE >> 8) as u32);
+        self.disk_return(DISK_RET_SUCCESS);
+    }
+
+    pub fn bios_disk(&mut self) {
+        let f = self.get_register8(RegIdx::ah());
+        let drive = self.get_register8(RegIdx::dl());
+
+        if f == 0x01 {
+            let status = self.disk_status;
+            self.disk_return(status);
+            return;
+        }
+
+        let idx = match self.disk_index(drive) {
+            Some(idx) => idx,
+            None => {
+                self.disk_return(DISK_RET_EPARAM);
+                return;
+            }
+        };
+
+        match f {
+            0x00 => { self.disk_return(DISK_RET_SUCCESS); },
+            0x02 => { self.bios_disk_chs(idx, false); },
+            0x03 => { self.bios_disk_chs(idx, true); },
+            0x08 => { self.bios_disk_parameters(idx); },
+            0x41 => {
+                if self.get_register16(RegIdx::Ebx as u8) == 0x55aa {
+                    self.set_register16(RegIdx::Ebx as u8, 0xaa55);
+                    self.set_register16(RegIdx::Ecx as u8, 0x0001);
+                    self.disk_return(DISK_RET_SUCCESS);
+                    self.set_register8(RegIdx::ah() as i32, 0x30);
+                } else {
+                    self.disk_return(DISK_RET_EPARAM);
+                }
+            },
+            0x42 => { self.bios_disk_extended(idx, false); },
+            0x43 => { self.bios_disk_extended(idx, true); },
+            0x48 => { self.bios_disk_extended_parameters(idx); },
+            _ => {
+                println!("not implemented BIOS disk function: {:#02x}", f);
+                self.disk_return(DISK_RET_EPARAM);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine() -> Emulator {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let image: Vec<u8> = (0..4 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8 + 1).collect();
+        emu.attach_disk(Disk::new(0x80, image));
+        emu
+    }
+
+    /// Issues AH=42h with a disk address packet at 0x1000.
+    fn extended_read(emu: &mut Emulator, count: u16, buffer: u32, lba: u64) -> u8 {
+        emu.memory[0x1000] = 0x10;
+        emu.memory[0x1002..0x1004].copy_from_slice(&count.to_le_bytes());
+        emu.memory[0x1004..0x1008].copy_from_slice(&buffer.to_le_bytes());
+        emu.memory[0x1008..0x1010].copy_from_slice(&lba.to_le_bytes());
+        emu.set_register32(RegIdx::Eax as u8, 0x4200);
+        emu.set_register32(RegIdx::Edx as u8, 0x80);
+        emu.set_register32(RegIdx::Esi as u8, 0x1000);
+        emu.bios_disk();
+        emu.get_register8(RegIdx::ah())
+    }
+
+    #[test]
+    fn extended_read_uses_flat_buffer() {
+        let mut emu = machine();
+        assert_eq!(extended_read(&mut emu, 2, 0x2000, 1), DISK_RET_SUCCESS);
+        assert_eq!(emu.memory[0x2000], 2);
+        assert_eq!(emu.memory[0x2000 + SECTOR_SIZE], 3);
+    }
+
+    #[test]
+    fn transfer_errors() {
+        let mut emu = machine();
+        assert_eq!(extended_read(&mut emu, 0, 0x2000, 0), DISK_RET_EPARAM);
+        assert_eq!(extended_read(&mut emu, 2, 0xff00, 0), DISK_RET_EBOUNDARY);
+        assert_eq!(extended_read(&mut emu, 1, 0x2000, u64::MAX), DISK_RET_ENOTFOUND);
+        assert!(emu.check_eflag(Eflags::Carry));
+    }
+}
@@ -0,0 +1,681 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use super::{Emulator, Instructions, hooks::{Access, StopReason, Watchpoint},
+            reverse::{History, ReverseStop}};
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+/// How many instructions `continue` runs between checks for a Ctrl-C from gdb.
+const INTERRUPT_POLL: u32 = 4096;
+
+/// gdb's i386 register numbering: eax..edi, eip, eflags, cs, ss, ds, es, fs,
+/// gs, st0-st7, then the x87 control registers.
+const GENERAL_REGISTERS: usize = 8;
+const REG_EIP: usize = 8;
+const REG_EFLAGS: usize = 9;
+const SEGMENT_REGISTERS: usize = 6;
+const X87_DATA_REGISTERS: usize = 8;
+const X87_CONTROL_REGISTERS: usize = 8;
+const REG_ST0: usize = REG_EFLAGS + 1 + SEGMENT_REGISTERS;
+const REG_FCTRL: usize = REG_ST0 + X87_DATA_REGISTERS;
+const REGISTER_COUNT: usize = REG_FCTRL + X87_CONTROL_REGISTERS;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>i386</architecture>
+  <feature name="org.gnu.gdb.i386.core">
+    <flags id="i386_eflags" size="4">
+      <field name="CF" start="0" end="0"/>
+      <field name="" start="1" end="1"/>
+      <field name="PF" start="2" end="2"/>
+      <field name="AF" start="4" end="4"/>
+      <field name="ZF" start="6" end="6"/>
+      <field name="SF" start="7" end="7"/>
+      <field name="TF" start="8" end="8"/>
+      <field name="IF" start="9" end="9"/>
+      <field name="DF" start="10" end="10"/>
+      <field name="OF" start="11" end="11"/>
+    </flags>
+    <reg name="eax" bitsize="32" type="int32" regnum="0"/>
+    <reg name="ecx" bitsize="32" type="int32"/>
+    <reg name="edx" bitsize="32" type="int32"/>
+    <reg name="ebx" bitsize="32" type="int32"/>
+    <reg name="esp" bitsize="32" type="data_ptr"/>
+    <reg name="ebp" bitsize="32" type="data_ptr"/>
+    <reg name="esi" bitsize="32" type="int32"/>
+    <reg name="edi" bitsize="32" type="int32"/>
+    <reg name="eip" bitsize="32" type="code_ptr"/>
+    <reg name="eflags" bitsize="32" type="i386_eflags"/>
+    <reg name="cs" bitsize="32" type="int32"/>
+    <reg name="ss" bitsize="32" type="int32"/>
+    <reg name="ds" bitsize="32" type="int32"/>
+    <reg name="es" bitsize="32" type="int32"/>
+    <reg name="fs" bitsize="32" type="int32"/>
+    <reg name="gs" bitsize="32" type="int32"/>
+    <reg name="st0" bitsize="80" type="i387_ext"/>
+    <reg name="st1" bitsize="80" type="i387_ext"/>
+    <reg name="st2" bitsize="80" type="i387_ext"/>
+    <reg name="st3" bitsize="80" type="i387_ext"/>
+    <reg name="st4" bitsize="80" type="i387_ext"/>
+    <reg name="st5" bitsize="80" type="i387_ext"/>
+    <reg name="st6" bitsize="80" type="i387_ext"/>
+    <reg name="st7" bitsize="80" type="i387_ext"/>
+    <reg name="fctrl" bitsize="32" type="int" group="float"/>
+    <reg name="fstat" bitsize="32" type="int" group="float"/>
+    <reg name="ftag" bitsize="32" type="int" group="float"/>
+    <reg name="fiseg" bitsize="32" type="int" group="float"/>
+    <reg name="fioff" bitsize="32" type="int" group="float"/>
+    <reg name="foseg" bitsize="32" type="int" group="float"/>
+    <reg name="fooff" bitsize="32" type="int" group="float"/>
+    <reg name="fop" bitsize="32" type="int" group="float"/>
+  </feature>
+</target>
+"#;
+
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Why the guest stopped running.
+enum Stop {
+    Signal(u8),
+    /// A watchpoint hit: stop reply kind (watch, rwatch, awatch) and address.
+    Watch(&'static str, u32),
+    Exited(i32),
+    /// The guest cannot go on, e.g. at an instruction the CPU does not
+    /// implement; reported as terminated by the signal.
+    Terminated(u8),
+    /// Running backwards reached the oldest checkpoint.
+    HistoryStart,
+}
+
+impl Stop {
+    fn watch(wp: Watchpoint, access: Access, addr: u32) -> Stop {
+        let kind = match access {
+            _ if wp.read && wp.write => "awatch",
+            Access::Read => "rwatch",
+            _ => "watch",
+        };
+        Stop::Watch(kind, addr)
+    }
+}
+
+/// What the command loop should do after replying to a packet.
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Reverse { step: bool },
+    Detach,
+    Kill,
+}
+
+/// A GDB remote serial protocol server for a single debugger connection.
+/// Breakpoints are kept here rather than patched into guest memory, so
+/// software (Z0) and hardware (Z1) breakpoints behave the same.
+pub struct GdbStub {
+    conn: Connection,
+    no_ack: bool,
+    breakpoints: BTreeSet<u32>,
+    pending: Vec<u8>,
+    /// Checkpoints for reverse stepping and continuing, when recording.
+    pub history: Option<History>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Splits "addr,len" as used by the m, M and Z packets.
+fn addr_len(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Signal(sig) => format!("S{:02x}", sig),
+        Stop::Watch(kind, addr) => format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr),
+        Stop::Exited(code) => format!("W{:02x}", code as u8),
+        Stop::Terminated(sig) => format!("X{:02x}", sig),
+        Stop::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))
+}
+
+impl GdbStub {
+    /// Waits for gdb to connect. `addr` is `host:port`, a bare port, or
+    /// `unix:/path/to/socket`.
+    pub fn listen(addr: &str) -> io::Result<GdbStub> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = addr.strip_prefix("unix:") {
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                println!("waiting for gdb on {}", path);
+                let (stream, _) = listener.accept()?;
+                return Ok(GdbStub::new(Connection::Unix(stream)));
+            }
+        }
+
+        let addr = if addr.contains(':') { addr.to_string() } else { format!("127.0.0.1:{}", addr) };
+        let listener = TcpListener::bind(&addr)?;
+        println!("waiting for gdb on {}", addr);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub::new(Connection::Tcp(stream)))
+    }
+
+    fn new(conn: Connection) -> GdbStub {
+        GdbStub {
+            conn,
+            no_ack: false,
+            breakpoints: BTreeSet::new(),
+            pending: vec![],
+            history: None,
+        }
+    }
+
+    /// Serves gdb until it detaches, kills the guest or disconnects. On
+    /// detach the guest keeps running, so the caller should continue its
+    /// own execution loop unless `emu.finished()`.
+    pub fn run(&mut self, emu: &mut Emulator, instructions: &Instructions) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => { return Ok(()); }
+            };
+            match self.handle(emu, &packet) {
+                Action::Reply(reply) => {
+                    self.send(&reply)?;
+                    // the OK is still acknowledged, later packets are not
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                },
+                Action::Resume { step } => {
+                    let stop = self.resume(emu, instructions, step)?;
+                    let terminated = matches!(stop, Stop::Terminated(_));
+                    self.send(&stop_reply(stop))?;
+                    // gdb considers the process gone after an X reply
+                    if terminated {
+                        emu.halted = true;
+                        return Ok(());
+                    }
+                },
+                Action::Reverse { step } => {
+                    let stop = self.reverse(emu, instructions, step);
+                    self.send(&stop_reply(stop))?;
+                },
+                Action::Detach => {
+                    self.send("OK")?;
+                    return Ok(());
+                },
+                Action::Kill => {
+                    emu.halted = true;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.remove(0)));
+        }
+        let mut b = [0u8; 1];
+        match self.conn.read(&mut b)? {
+            0 => Ok(None),
+            _ => Ok(Some(b[0])),
+        }
+    }
+
+    /// Returns the next packet's payload, or `None` once gdb hangs up. A
+    /// stray Ctrl-C while stopped is ignored.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => { return Ok(None); },
+                Some(b'$') => (),
+                Some(_) => { continue; }
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    None => { return Ok(None); },
+                    Some(b'#') => { break; },
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0u8; 2];
+            for b in &mut sum {
+                *b = self.read_byte()?.unwrap_or(0);
+            }
+
+            let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            if !self.no_ack {
+                if expected != Some(checksum(&data)) {
+                    self.conn.write_all(b"-")?;
+                    continue;
+                }
+                self.conn.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut body = vec![];
+        for &b in data.as_bytes() {
+            if matches!(b, b'$' | b'#' | b'}' | b'*') {
+                body.push(b'}');
+                body.push(b ^ 0x20);
+            } else {
+                body.push(b);
+            }
+        }
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&body);
+        packet.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+
+        loop {
+            self.conn.write_all(&packet)?;
+            self.conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => (),
+                Some(b'+') | None => { return Ok(()); },
+                // not an ack; keep it for the next packet
+                Some(b) => {
+                    self.pending.push(b);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Checks without blocking whether gdb sent a Ctrl-C.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.conn.set_nonblocking(true)?;
+        let mut b = [0u8; 1];
+        let res = self.conn.read(&mut b);
+        self.conn.set_nonblocking(false)?;
+        match res {
+            Ok(1) if b[0] == 0x03 => Ok(true),
+            Ok(1) => {
+                self.pending.push(b[0]);
+                Ok(false)
+            },
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn resume(&mut self, emu: &mut Emulator, instructions: &Instructions, step: bool)
+        -> io::Result<Stop> {
+        let mut count = 0u32;
+        loop {
+            if emu.finished() {
+                return Ok(Stop::Exited(emu.exit_code.unwrap_or(0)));
+            }
+            if let Some(history) = self.history.as_mut() {
+                history.record(emu);
+            }
+            if let Err(code) = emu.step(instructions) {
+                println!("Not Implemented Instruction: 0x{:x}", code);
+                return Ok(Stop::Terminated(SIGILL));
+            }
+            match emu.take_stop_reason() {
+                Some(StopReason::Watchpoint(wp, access)) => {
+                    return Ok(Stop::watch(wp, access.kind, access.addr));
+                },
+                Some(StopReason::Hook(_)) => { return Ok(Stop::Signal(SIGTRAP)); },
+                Some(StopReason::Denied(_)) => { return Ok(Stop::Signal(SIGSEGV)); },
+                None => (),
+            }
+            if step || self.breakpoints.contains(&emu.eip) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+            count += 1;
+            if count % INTERRUPT_POLL == 0 && self.interrupted()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    fn read_register(emu: &Emulator, n: usize) -> Option<Vec<u8>> {
+        match n {
+            _ if n < GENERAL_REGISTERS => Some(emu.registers.regs[n].to_le_bytes().to_vec()),
+            REG_EIP => Some(emu.eip.to_le_bytes().to_vec()),
+            REG_EFLAGS => Some(emu.eflags.to_le_bytes().to_vec()),
+            // the CPU has no segment registers or FPU
+            _ if n < REG_ST0 => Some(vec![0; 4]),
+            _ if n < REG_FCTRL => Some(vec![0; 10]),
+            _ if n < REGISTER_COUNT => Some(vec![0; 4]),
+            _ => None,
+        }
+    }
+
+    fn write_register(emu: &mut Emulator, n: usize, bytes: &[u8]) {
+        if bytes.len() < 4 {
+            return;
+        }
+        let val = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        match n {
+            _ if n < GENERAL_REGISTERS => { emu.registers.regs[n] = val; },
+            REG_EIP => { emu.eip = val; },
+            REG_EFLAGS => { emu.eflags = val; },
+            _ => (),
+        }
+    }
+
+    fn reverse(&mut self, emu: &mut Emulator, instructions: &Instructions, step: bool) -> Stop {
+        let history = self.history.as_ref().unwrap();
+        if step {
+            return if history.step_back(emu, instructions) {
+                Stop::Signal(SIGTRAP)
+            } else {
+                Stop::HistoryStart
+            };
+        }
+        match history.reverse_continue(emu, instructions, &self.breakpoints) {
+            ReverseStop::Watch(StopReason::Watchpoint(wp, access)) => Stop::watch(wp, access.kind, access.addr),
+            ReverseStop::Start => Stop::HistoryStart,
+            _ => Stop::Signal(SIGTRAP),
+        }
+    }
+
+    fn handle(&mut self, emu: &mut Emulator, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        let error = reply("E01");
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+
+        match cmd {
+            "?" => reply("S05"),
+            "g" => {
+                let regs: Vec<u8> = (0..REGISTER_COUNT)
+                    .flat_map(|n| GdbStub::read_register(emu, n).unwrap_or_default())
+                    .collect();
+                Action::Reply(hex(&regs))
+            },
+            "G" => match unhex(args) {
+                Some(bytes) => {
+                    for n in 0..=REG_EFLAGS {
+                        if let Some(b) = bytes.get(n * 4..n * 4 + 4) {
+                            GdbStub::write_register(emu, n, b);
+                        }
+                    }
+                    reply("OK")
+                },
+                None => error,
+            },
+            "p" => match parse_hex(args).and_then(|n| GdbStub::read_register(emu, n as usize)) {
+                Some(bytes) => Action::Reply(hex(&bytes)),
+                None => error,
+            },
+            "P" => {
+                let parsed = args.split_once('=')
+                    .and_then(|(n, v)| Some((parse_hex(n)?, unhex(v)?)));
+                match parsed {
+                    Some((n, bytes)) => {
+                        GdbStub::write_register(emu, n as usize, &bytes);
+                        reply("OK")
+                    },
+                    None => error,
+                }
+            },
+            "m" => {
+                let bytes: Option<Vec<u8>> = addr_len(args).and_then(|(addr, len)| {
+                    (0..len).map(|i| emu.peek8(addr.wrapping_add(i))).collect()
+                });
+                match bytes {
+                    Some(bytes) => Action::Reply(hex(&bytes)),
+                    None => error,
+                }
+            },
+            "M" => {
+                let parsed = args.split_once(':')
+                    .and_then(|(range, data)| Some((addr_len(range)?, unhex(data)?)));
+                match parsed {
+                    Some(((addr, _), bytes)) => {
+                        let ok = bytes.iter().enumerate()
+                            .all(|(i, &b)| emu.poke8(addr.wrapping_add(i as u32), b));
+                        if ok { reply("OK") } else { error }
+                    },
+                    None => error,
+                }
+            },
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    emu.eip = addr;
+                }
+                Action::Resume { step: cmd == "s" }
+            },
+            "b" if self.history.is_some() && (args == "c" || args == "s") => {
+                Action::Reverse { step: args == "s" }
+            },
+            "Z" | "z" => {
+                let mut fields = args.splitn(3, ',');
+                let kind = fields.next();
+                let addr = fields.next().and_then(parse_hex);
+                let len = fields.next().and_then(|l| parse_hex(l.split(';').next()?));
+                match (kind, addr) {
+                    (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                        if cmd == "Z" {
+                            self.breakpoints.insert(addr);
+                        } else {
+                            self.breakpoints.remove(&addr);
+                        }
+                        reply("OK")
+                    },
+                    (Some(kind @ ("2" | "3" | "4")), Some(addr)) => {
+                        let wp = Watchpoint {
+                            start: addr,
+                            end: addr.wrapping_add(len.unwrap_or(1).max(1)),
+                            read: kind != "2",
+                            write: kind != "3",
+                            execute: false,
+                        };
+                        if cmd == "Z" {
+                            emu.add_watchpoint(wp);
+                        } else {
+                            emu.remove_watchpoint(&wp);
+                        }
+                        reply("OK")
+                    },
+                    _ => reply(""),
+                }
+            },
+            "H" | "T" => reply("OK"),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "v" => self.handle_v(args),
+            "q" | "Q" => self.handle_query(packet),
+            _ => reply(""),
+        }
+    }
+
+    fn handle_v(&self, args: &str) -> Action {
+        if args == "Cont?" {
+            return Action::Reply("vCont;c;C;s;S".to_string());
+        }
+        match args.strip_prefix("Cont;") {
+            // a single thread, so the first action decides
+            Some(actions) => Action::Resume { step: actions.starts_with(['s', 'S']) },
+            None => Action::Reply(String::new()),
+        }
+    }
+
+    fn handle_query(&self, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        if packet.starts_with("qSupported") {
+            let reverse = if self.history.is_some() { ";ReverseStep+;ReverseContinue+" } else { "" };
+            return Action::Reply(format!("PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+;\
+                                          QStartNoAckMode+;vContSupported+{}", reverse));
+        }
+        if packet == "QStartNoAckMode" {
+            return reply("OK");
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:") {
+            let parsed = rest.split_once(':')
+                .and_then(|(annex, range)| Some((annex, addr_len(range)?)));
+            return match parsed {
+                Some(("target.xml", (offset, len))) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    Action::Reply(format!("{}{}", more, String::from_utf8_lossy(&xml[start..end])))
+                },
+                _ => reply("E00"),
+            };
+        }
+        match packet {
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "qOffsets" => reply("Text=0;Data=0;Bss=0"),
+            _ => reply(""),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stub and the debugger end of its connection.
+    fn connect() -> (GdbStub, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (GdbStub::new(Connection::Tcp(stream)), client)
+    }
+
+    fn packet(data: &str) -> Vec<u8> {
+        format!("${}#{:02x}", data, checksum(data.as_bytes())).into_bytes()
+    }
+
+    fn read_exact(client: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn framing() {
+        let (mut stub, mut client) = connect();
+        client.write_all(b"+$g#00").unwrap();
+        client.write_all(&packet("m7c00,2")).unwrap();
+        client.write_all(&packet("QStartNoAckMode")).unwrap();
+        assert_eq!(stub.read_packet().unwrap().as_deref(), Some("m7c00,2"));
+        assert_eq!(read_exact(&mut client, 2), b"-+");
+        assert_eq!(stub.read_packet().unwrap().as_deref(), Some("QStartNoAckMode"));
+        assert_eq!(read_exact(&mut client, 1), b"+");
+
+        // reserved characters are escaped and the checksum covers the escapes
+        client.write_all(b"+").unwrap();
+        stub.send("a$#}*").unwrap();
+        let reply = read_exact(&mut client, 13);
+        assert_eq!(&reply[..11], b"$a}\x04}\x03}]}\x0a#");
+        let sum = checksum(&reply[1..10]);
+        assert_eq!(&reply[11..], format!("{:02x}", sum).as_bytes());
+
+        drop(client);
+        assert_eq!(stub.read_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn unimplemented_instruction_terminates() {
+        let (mut stub, mut client) = connect();
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x7c00);
+        emu.memory[0x7c00] = 0xf4;
+        let instructions = emu.init_instructions();
+
+        let server = std::thread::spawn(move || {
+            let res = stub.run(&mut emu, &instructions);
+            (res.is_ok(), emu.halted, emu.eip)
+        });
+        client.write_all(&packet("QStartNoAckMode")).unwrap();
+        assert_eq!(read_exact(&mut client, 7), b"+$OK#9a");
+        client.write_all(b"+").unwrap();
+        client.write_all(&packet("c")).unwrap();
+        assert_eq!(read_exact(&mut client, 7), packet("X04"));
+        assert_eq!(server.join().unwrap(), (true, true, 0x7c00));
+    }
+
+    #[test]
+    fn hex_helpers() {
+        assert_eq!(unhex("00ff7c"), Some(vec![0x00, 0xff, 0x7c]));
+        assert_eq!(unhex("0"), None);
+        assert_eq!(unhex("zz"), None);
+        assert_eq!(addr_len("7c00,10"), Some((0x7c00, 0x10)));
+        assert_eq!(addr_len("7c00"), None);
+        assert_eq!(stop_reply(Stop::Exited(-1)), "Wff");
+        assert_eq!(stop_reply(Stop::Watch("awatch", 0x1000)), "T05awatch:1000;");
+    }
+}
@@ -0,0 +1,228 @@
+use super::{Emulator, loader::LoadError};
+
+/// Contents of an Intel HEX or Motorola S-record file.
+#[derive(Debug, Default, Clone)]
+pub struct HexImage {
+    /// Data records as (address, bytes), in file order.
+    pub chunks: Vec<(u32, Vec<u8>)>,
+    /// From the start address record, if the file has one.
+    pub entry: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HexFormat {
+    IntelHex,
+    SRecord,
+}
+
+/// Guesses the format from the first line; flat binaries yield `None`.
+pub fn detect(data: &[u8]) -> Option<HexFormat> {
+    let line = data.split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?.trim();
+    let is_hex = |s: &str| s.len() >= 2 && s.bytes().all(|b| b.is_ascii_hexdigit());
+    match line.as_bytes() {
+        [b':', ..] if is_hex(&line[1..]) => Some(HexFormat::IntelHex),
+        [b'S', b'0'..=b'9', ..] if is_hex(&line[2..]) => Some(HexFormat::SRecord),
+        _ => None,
+    }
+}
+
+fn format_error(line: usize, msg: &str) -> LoadError {
+    LoadError::Format(format!("line {}: {}", line, msg))
+}
+
+fn decode_hex(line: usize, s: &[u8]) -> Result<Vec<u8>, LoadError> {
+    if s.len() % 2 != 0 {
+        return Err(format_error(line, "odd number of hex digits"));
+    }
+    let digit = |b: u8| (b as char).to_digit(16).ok_or_else(|| format_error(line, "bad hex digit"));
+    s.chunks(2).map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8)).collect()
+}
+
+fn be_value(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as u32)
+}
+
+fn lines(data: &[u8]) -> Result<impl Iterator<Item = (usize, &str)>, LoadError> {
+    let text = std::str::from_utf8(data).map_err(|_| LoadError::Format("not a text file".to_string()))?;
+    Ok(text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).filter(|(_, l)| !l.is_empty()))
+}
+
+/// Parses Intel HEX, including extended segment/linear address records.
+pub fn parse_ihex(data: &[u8]) -> Result<HexImage, LoadError> {
+    let mut image = HexImage::default();
+    let mut base = 0u32;
+
+    for (n, line) in lines(data)? {
+        let record = line.strip_prefix(':').ok_or_else(|| format_error(n, "missing ':'"))?;
+        let bytes = decode_hex(n, record.as_bytes())?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(format_error(n, "bad record length"));
+        }
+        if bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) != 0 {
+            return Err(format_error(n, "checksum mismatch"));
+        }
+        let offset = be_value(&bytes[1..3]);
+        let payload = &bytes[4..bytes.len() - 1];
+
+        match bytes[3] {
+            0x00 => { image.chunks.push((base.wrapping_add(offset), payload.to_vec())); },
+            0x01 => { break; },
+            0x02 if payload.len() == 2 => { base = be_value(payload) << 4; },
+            // CS:IP; the CPU is flat so it becomes a linear address
+            0x03 if payload.len() == 4 => {
+                image.entry = Some((be_value(&payload[..2]) << 4) + be_value(&payload[2..]));
+            },
+            0x04 if payload.len() == 2 => { base = be_value(payload) << 16; },
+            0x05 if payload.len() == 4 => { image.entry = Some(be_value(payload)); },
+            kind => { return Err(format_error(n, &format!("bad record type {:02x}", kind))); }
+        }
+    }
+    Ok(image)
+}
+
+/// Parses Motorola S-records (S1/S2/S3 data, S7/S8/S9 start address).
+pub fn parse_srec(data: &[u8]) -> Result<HexImage, LoadError> {
+    let mut image = HexImage::default();
+
+    for (n, line) in lines(data)? {
+        if !line.starts_with('S') || line.len() < 4 {
+            return Err(format_error(n, "not an S-record"));
+        }
+        let kind = line.as_bytes()[1];
+        let bytes = decode_hex(n, &line.as_bytes()[2..])?;
+        if bytes.len() != bytes[0] as usize + 1 {
+            return Err(format_error(n, "bad record length"));
+        }
+        if bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) != 0xff {
+            return Err(format_error(n, "checksum mismatch"));
+        }
+
+        let addr_len = match kind {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => { return Err(format_error(n, &format!("bad record type S{}", kind as char))); }
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(format_error(n, "bad record length"));
+        }
+        let addr = be_value(&bytes[1..1 + addr_len]);
+        let payload = &bytes[1 + addr_len..bytes.len() - 1];
+
+        match kind {
+            b'1' | b'2' | b'3' => { image.chunks.push((addr, payload.to_vec())); },
+            b'7' | b'8' | b'9' => { image.entry = Some(addr); },
+            _ => (),
+        }
+    }
+    Ok(image)
+}
+
+impl Emulator {
+    /// Loads an Intel HEX or S-record file, placing every data record at its
+    /// address. EIP is set to the start address record or, failing that, to
+    /// the first data record.
+    pub fn load_hex(&mut self, format: HexFormat, data: &[u8]) -> Result<HexImage, LoadError> {
+        let image = self.load_hex_at(format, data, 0)?;
+        if let Some(eip) = image.entry.or_else(|| image.chunks.first().map(|c| c.0)) {
+            self.eip = eip;
+        }
+        Ok(image)
+    }
+
+    /// Places every data record at `base` plus its address, for
+    /// `file@address` arguments. The returned image keeps the addresses from
+    /// the file; EIP is left alone.
+    pub fn load_hex_at(&mut self, format: HexFormat, data: &[u8], base: u32)
+        -> Result<HexImage, LoadError> {
+        let image = match format {
+            HexFormat::IntelHex => parse_ihex(data)?,
+            HexFormat::SRecord => parse_srec(data)?,
+        };
+        for (addr, bytes) in &image.chunks {
+            self.load_binary(base.wrapping_add(*addr), bytes)?;
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intel_hex_records() {
+        let data = b":0200100090C39B\n\
+                     :020000040001F9\n\
+                     :01000000AA55\n\
+                     :020000021000EC\n\
+                     :01000400BB40\n\
+                     :0400000500010010E6\n\
+                     :00000001FF\n\
+                     :01000000AA55\n";
+        let image = parse_ihex(data).unwrap();
+        assert_eq!(image.chunks, vec![(0x10, vec![0x90, 0xc3]), (0x10000, vec![0xaa]),
+                                      (0x10004, vec![0xbb])]);
+        assert_eq!(image.entry, Some(0x10010));
+
+        let image = parse_ihex(b":0400000312340005AE\r\n:00000001FF\r\n").unwrap();
+        assert_eq!(image.entry, Some(0x12345));
+        assert_eq!(detect(data), Some(HexFormat::IntelHex));
+    }
+
+    #[test]
+    fn malformed_intel_hex() {
+        let error = |data: &[u8]| match parse_ihex(data) {
+            Err(LoadError::Format(msg)) => msg,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error(b":0200100090C39C\n"), "line 1: checksum mismatch");
+        assert_eq!(error(b":0300100090C39A\n"), "line 1: bad record length");
+        assert_eq!(error(b":0200100090C39\n"), "line 1: odd number of hex digits");
+        assert_eq!(error(b":00000006FA\n"), "line 1: bad record type 06");
+        assert_eq!(error(b":0100000210ED\n"), "line 1: bad record type 02");
+        assert_eq!(error(b":01000000AA55\nS9031000EC\n"), "line 2: missing ':'");
+        assert_eq!(error(":0\u{e9}0000001FF\n".as_bytes()), "line 1: bad hex digit");
+        assert_eq!(error(":0G\n".as_bytes()), "line 1: bad hex digit");
+    }
+
+    #[test]
+    fn s_records() {
+        let data = b"S0060000686472BB\n\
+                     S105100090C397\n\
+                     S205123456AAB4\n\
+                     S30712345678BBCC5D\n\
+                     S70512345678E6\n";
+        let image = parse_srec(data).unwrap();
+        assert_eq!(image.chunks, vec![(0x1000, vec![0x90, 0xc3]), (0x123456, vec![0xaa]),
+                                      (0x12345678, vec![0xbb, 0xcc])]);
+        assert_eq!(image.entry, Some(0x12345678));
+        assert_eq!(parse_srec(b"S9031000EC").unwrap().entry, Some(0x1000));
+        assert_eq!(detect(data), Some(HexFormat::SRecord));
+        assert_eq!(detect(b"\x7fELF"), None);
+    }
+
+    #[test]
+    fn malformed_s_records() {
+        let error = |data: &[u8]| match parse_srec(data) {
+            Err(LoadError::Format(msg)) => msg,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error(b"S105100090C398\n"), "line 1: checksum mismatch");
+        assert_eq!(error(b"S106100090C396\n"), "line 1: bad record length");
+        assert_eq!(error(b"S4031000EC\n"), "line 1: bad record type S4");
+        assert_eq!(error(b"S30300FC\n"), "line 1: bad record length");
+        assert_eq!(error(b":00000001FF\n"), "line 1: not an S-record");
+        assert_eq!(error("S\u{e9}00\n".as_bytes()), "line 1: odd number of hex digits");
+        assert_eq!(error("S1\u{e9}0010\n".as_bytes()), "line 1: bad hex digit");
+    }
+
+    #[test]
+    fn loads_relative_to_base() {
+        let mut emu = Emulator::new(0x20000, 0, 0);
+        emu.load_hex_at(HexFormat::SRecord, b"S105100090C397\n", 0x8000).unwrap();
+        assert_eq!(&emu.memory[0x9000..0x9002], &[0x90, 0xc3]);
+        assert_eq!(emu.eip, 0);
+    }
+}
@@ -0,0 +1,387 @@
+use std::cell::Cell;
+use std::fmt;
+use std::sync::Arc;
+use super::{Emulator, disasm::Instruction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Execution of the instruction at the address, checked once before it
+    /// runs.
+    Fetch,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Fetch => write!(f, "execute"),
+        }
+    }
+}
+
+/// A single byte access as seen by hooks and watchpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: Access,
+    pub addr: u32,
+    /// The byte read, or the byte about to be written.
+    pub value: u8,
+    /// Address of the instruction making the access.
+    pub eip: u32,
+}
+
+/// What a memory hook wants done with an access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    Allow,
+    /// Drop the write, make the read return 0xff, or keep the instruction
+    /// from executing and stop.
+    Deny,
+    /// Allow the access, then stop execution after the instruction.
+    Stop,
+}
+
+pub type MemoryHook = Arc<dyn Fn(&MemoryAccess) -> HookAction + Send + Sync>;
+/// Called with the CPU state and the decoded instruction at EIP.
+pub type CodeHook = Arc<dyn Fn(&Emulator, &Instruction) + Send + Sync>;
+/// Called with the CPU state when EIP enters a basic block at the given
+/// address.
+pub type BlockHook = Arc<dyn Fn(&Emulator, u32) + Send + Sync>;
+
+/// Watches the addresses `start..end` for the selected kinds of access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u32,
+    pub end: u32,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind = match access.kind {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Fetch => self.execute,
+        };
+        kind && access.addr >= self.start && access.addr < self.end
+    }
+}
+
+/// Why execution should stop after the current instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Watchpoint(Watchpoint, MemoryAccess),
+    Hook(MemoryAccess),
+    /// A hook denied executing the instruction, which did not run.
+    Denied(MemoryAccess),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (what, access) = match self {
+            StopReason::Watchpoint(_, access) => ("watchpoint", access),
+            StopReason::Hook(access) | StopReason::Denied(access) => ("hook", access),
+        };
+        if let StopReason::Denied(access) = self {
+            return write!(f, "{}: denied execute at {:#010x}", what, access.addr);
+        }
+        write!(f, "{}: {} of {:#04x} at {:#010x} by instruction at {:#010x}",
+               what, access.kind, access.value, access.addr, access.eip)
+    }
+}
+
+/// Memory hooks and watchpoints. Reads happen through `&self`, so the first
+/// stop request of an instruction is kept in a `Cell`.
+#[derive(Default, Clone)]
+pub struct Hooks {
+    memory: Vec<(usize, MemoryHook)>,
+    before: Vec<(usize, CodeHook)>,
+    after: Vec<(usize, CodeHook)>,
+    block: Vec<(usize, BlockHook)>,
+    /// Whether the next instruction starts a basic block: the first one
+    /// executed, or one following a control transfer.
+    block_pending: bool,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+    /// Start of the instruction being executed.
+    pub(super) eip: u32,
+    /// Set while recorded history is re-executed: the code and memory hooks
+    /// already saw those instructions, so only watchpoints are checked.
+    pub(super) suspended: bool,
+    stop: Cell<Option<StopReason>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("memory", &self.memory.len())
+            .field("before", &self.before.len())
+            .field("after", &self.after.len())
+            .field("block", &self.block.len())
+            .field("watchpoints", &self.watchpoints)
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.watchpoints.is_empty()
+    }
+
+    pub(super) fn has_code_hooks(&self) -> bool {
+        let empty = self.before.is_empty() && self.after.is_empty() && self.block.is_empty();
+        !(self.suspended || empty)
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Runs the hooks and watchpoints for an access. Returns false if a
+    /// hook vetoed it.
+    pub(super) fn check(&self, kind: Access, addr: u32, value: u8) -> bool {
+        let access = MemoryAccess { kind, addr, value, eip: self.eip };
+        let mut allowed = true;
+        let hooks = if self.suspended { &[][..] } else { &self.memory[..] };
+        for (_, hook) in hooks {
+            match hook(&access) {
+                HookAction::Allow => (),
+                HookAction::Deny if kind == Access::Fetch => {
+                    // takes precedence over stops requested by earlier hooks
+                    self.stop.set(Some(StopReason::Denied(access)));
+                    return false;
+                },
+                HookAction::Deny => { allowed = false; },
+                HookAction::Stop => self.request_stop(StopReason::Hook(access)),
+            }
+        }
+        if let Some(wp) = self.watchpoints.iter().find(|wp| wp.matches(&access)) {
+            self.request_stop(StopReason::Watchpoint(*wp, access));
+        }
+        allowed
+    }
+
+    fn request_stop(&self, reason: StopReason) {
+        if self.stop.get().is_none() {
+            self.stop.set(Some(reason));
+        }
+    }
+}
+
+impl Emulator {
+    /// Registers a callback for every guest memory read and write, and for
+    /// each instruction about to execute. Returns an id for `remove_hook`.
+    pub fn add_memory_hook(&mut self, hook: MemoryHook) -> usize {
+        let id = self.hooks.next_id();
+        self.hooks.memory.push((id, hook));
+        id
+    }
+
+    /// Registers a callback run before each instruction executes.
+    pub fn hook_code(&mut self, hook: CodeHook) -> usize {
+        let id = self.hooks.next_id();
+        self.hooks.before.push((id, hook));
+        id
+    }
+
+    /// Registers a callback run after each instruction executed; it still
+    /// gets the instruction that ran, while the CPU state is the new one.
+    pub fn hook_code_after(&mut self, hook: CodeHook) -> usize {
+        let id = self.hooks.next_id();
+        self.hooks.after.push((id, hook));
+        id
+    }
+
+    /// Registers a callback run when execution enters a basic block, before
+    /// its first instruction and its code hooks.
+    pub fn hook_block(&mut self, hook: BlockHook) -> usize {
+        let id = self.hooks.next_id();
+        self.hooks.block.push((id, hook));
+        self.hooks.block_pending = true;
+        id
+    }
+
+    /// Removes a hook added by any of the `add_*_hook`/`hook_*` methods.
+    pub fn remove_hook(&mut self, id: usize) {
+        self.hooks.memory.retain(|(i, _)| *i != id);
+        self.hooks.before.retain(|(i, _)| *i != id);
+        self.hooks.after.retain(|(i, _)| *i != id);
+        self.hooks.block.retain(|(i, _)| *i != id);
+    }
+
+    /// Executes one instruction with the code and block hooks around it.
+    pub(super) fn step_hooked(&mut self, run: impl FnOnce(&mut Emulator) -> Result<(), u8>)
+        -> Result<(), u8> {
+        let inst = self.disassemble(self.eip);
+        if self.hooks.block_pending {
+            self.hooks.block_pending = false;
+            for (_, hook) in self.hooks.block.clone() {
+                hook(self, inst.addr);
+            }
+        }
+        for (_, hook) in self.hooks.before.clone() {
+            hook(self, &inst);
+        }
+
+        let res = run(self);
+
+        if res.is_ok() {
+            for (_, hook) in self.hooks.after.clone() {
+                hook(self, &inst);
+            }
+        }
+        // a not-taken branch ends its block all the same
+        self.hooks.block_pending = inst.is_control_transfer();
+        res
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.hooks.watchpoints.push(watchpoint);
+    }
+
+    /// Removes a watchpoint equal to `watchpoint`. Returns whether there was
+    /// one.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        match self.hooks.watchpoints.iter().position(|wp| wp == watchpoint) {
+            Some(i) => {
+                self.hooks.watchpoints.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.hooks.watchpoints
+    }
+
+    /// The watchpoint hit or stop requested since the last call, if any.
+    pub fn take_stop_reason(&self) -> Option<StopReason> {
+        self.hooks.stop.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::reverse::{History, ReverseStop};
+    use std::collections::BTreeSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// `mov eax, 0x12345678` at 0x7c00.
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x7c00);
+        emu.memory[0x7c00..0x7c05].copy_from_slice(&[0xb8, 0x78, 0x56, 0x34, 0x12]);
+        emu
+    }
+
+    #[test]
+    fn denied_fetch_stops_before_the_instruction() {
+        let mut emu = emulator();
+        let instructions = emu.init_instructions();
+        emu.add_memory_hook(Arc::new(|a: &MemoryAccess| {
+            if a.kind == Access::Fetch { HookAction::Deny } else { HookAction::Allow }
+        }));
+        assert_eq!(emu.step(&instructions), Ok(()));
+        assert_eq!(emu.eip, 0x7c00);
+        assert_eq!(emu.icount, 0);
+        assert_eq!(emu.registers.regs[0], 0);
+        assert!(matches!(emu.take_stop_reason(), Some(StopReason::Denied(a)) if a.addr == 0x7c00));
+    }
+
+    #[test]
+    fn execute_checked_once_per_instruction() {
+        let mut emu = emulator();
+        let instructions = emu.init_instructions();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let count = fetches.clone();
+        emu.add_memory_hook(Arc::new(move |a: &MemoryAccess| {
+            if a.kind == Access::Fetch {
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+            HookAction::Allow
+        }));
+        // covers only the immediate operand
+        emu.add_watchpoint(Watchpoint { start: 0x7c01, end: 0x7c05, read: false, write: false,
+                                        execute: true });
+        emu.step(&instructions).unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(emu.take_stop_reason(), None);
+        assert_eq!(emu.registers.regs[0], 0x12345678);
+
+        emu.eip = 0x7c00;
+        emu.add_watchpoint(Watchpoint { start: 0x7c00, end: 0x7c01, read: false, write: false,
+                                        execute: true });
+        emu.step(&instructions).unwrap();
+        assert!(matches!(emu.take_stop_reason(),
+                         Some(StopReason::Watchpoint(_, a)) if a.kind == Access::Fetch));
+        assert_eq!(emu.eip, 0x7c05);
+    }
+
+    #[test]
+    fn blocks_start_after_control_transfers() {
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x7c00);
+        // jnz to the next instruction, then two movs
+        emu.memory[0x7c00..0x7c0c].copy_from_slice(&[0x75, 0x00, 0xb8, 1, 0, 0, 0,
+                                                      0xbb, 2, 0, 0, 0]);
+        let instructions = emu.init_instructions();
+        let blocks = Arc::new(std::sync::Mutex::new(vec![]));
+        let seen = blocks.clone();
+        emu.hook_block(Arc::new(move |_: &Emulator, addr| seen.lock().unwrap().push(addr)));
+        for _ in 0..3 {
+            emu.step(&instructions).unwrap();
+        }
+        assert_eq!(*blocks.lock().unwrap(), vec![0x7c00, 0x7c02]);
+    }
+
+    #[test]
+    fn history_replay_leaves_hooks_alone() {
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x7c00);
+        // mov eax, 1; mov ebx, 0x8000; mov [ebx], eax; mov eax, 2
+        emu.memory[0x7c00..0x7c11].copy_from_slice(&[0xb8, 1, 0, 0, 0, 0xbb, 0, 0x80, 0, 0,
+                                                      0x89, 0x03, 0xb8, 2, 0, 0, 0]);
+        let instructions = emu.init_instructions();
+        let accesses = Arc::new(AtomicUsize::new(0));
+        let count = accesses.clone();
+        emu.add_memory_hook(Arc::new(move |_: &MemoryAccess| {
+            count.fetch_add(1, Ordering::SeqCst);
+            HookAction::Allow
+        }));
+        let code = Arc::new(AtomicUsize::new(0));
+        let count = code.clone();
+        emu.hook_code(Arc::new(move |_: &Emulator, _: &Instruction| {
+            count.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        let mut history = History::new(100);
+        for _ in 0..4 {
+            history.record(&mut emu);
+            emu.step(&instructions).unwrap();
+        }
+        emu.add_watchpoint(Watchpoint { start: 0x8000, end: 0x8004, read: false, write: true,
+                                        execute: false });
+        emu.take_stop_reason();
+        let (seen, ran) = (accesses.load(Ordering::SeqCst), code.load(Ordering::SeqCst));
+
+        assert!(history.seek(&mut emu, &instructions, 1));
+        assert_eq!(emu.eip, 0x7c05);
+        assert!(matches!(history.reverse_continue(&mut emu, &instructions, &BTreeSet::new()),
+                         ReverseStop::Start));
+        assert!(history.seek(&mut emu, &instructions, 4));
+        assert!(matches!(history.reverse_continue(&mut emu, &instructions, &BTreeSet::new()),
+                         ReverseStop::Watch(StopReason::Watchpoint(..))));
+        assert_eq!(emu.icount, 3);
+        assert_eq!(accesses.load(Ordering::SeqCst), seen);
+        assert_eq!(code.load(Ordering::SeqCst), ran);
+
+        emu.step(&instructions).unwrap();
+        assert!(accesses.load(Ordering::SeqCst) > seen);
+        assert_eq!(code.load(Ordering::SeqCst), ran + 1);
+    }
+}
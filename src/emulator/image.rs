@@ -0,0 +1,129 @@
+use std::io;
+use std::io::Write;
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const DEFLATE_STORED_MAX: usize = 0xffff;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &d in data {
+        a = (a + d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(data.len() as u32)?;
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+    w.write_all(&body)?;
+    w.write_u32::<BigEndian>(crc32(&body))
+}
+
+/// Wraps `data` in a zlib stream made of stored (uncompressed) deflate
+/// blocks. Screenshots are small enough that compression is not worth a
+/// dependency.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut chunks = data.chunks(DEFLATE_STORED_MAX).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(if chunks.peek().is_none() { 1 } else { 0 });
+        out.write_u16::<LittleEndian>(chunk.len() as u16).unwrap();
+        out.write_u16::<LittleEndian>(!(chunk.len() as u16)).unwrap();
+        out.extend_from_slice(chunk);
+    }
+    out.write_u32::<BigEndian>(adler32(data)).unwrap();
+    out
+}
+
+/// Writes `rgb` (3 bytes per pixel, rows top to bottom) as a truecolour PNG.
+pub fn write_png<W: Write>(w: &mut W, width: usize, height: usize, rgb: &[u8])
+    -> io::Result<()> {
+    let mut ihdr = vec![];
+    ihdr.write_u32::<BigEndian>(width as u32)?;
+    ihdr.write_u32::<BigEndian>(height as u32)?;
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    w.write_all(&PNG_SIGNATURE)?;
+    write_chunk(w, b"IHDR", &ihdr)?;
+    write_chunk(w, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(w, b"IEND", &[])
+}
+
+/// Writes `rgb` as a binary (P6) PPM image.
+pub fn write_ppm<W: Write>(w: &mut W, width: usize, height: usize, rgb: &[u8])
+    -> io::Result<()> {
+    write!(w, "P6\n{} {}\n255\n", width, height)?;
+    w.write_all(&rgb[..width * height * 3])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn zlib_stored_blocks() {
+        assert_eq!(zlib_stored(&[]), vec![0x78, 0x01, 1, 0, 0, 0xff, 0xff, 0, 0, 0, 1]);
+
+        let data = vec![0xab; DEFLATE_STORED_MAX + 1];
+        let z = zlib_stored(&data);
+        // two blocks, the second one final
+        assert_eq!(&z[2..7], &[0, 0xff, 0xff, 0, 0]);
+        let second = 7 + DEFLATE_STORED_MAX;
+        assert_eq!(&z[second..second + 5], &[1, 1, 0, 0xfe, 0xff]);
+        assert_eq!(z.len(), 2 + 5 + DEFLATE_STORED_MAX + 5 + 1 + 4);
+    }
+
+    #[test]
+    fn png_of_two_pixels() {
+        let mut png = vec![];
+        write_png(&mut png, 2, 1, &[0xff, 0, 0, 0, 0, 0xff]).unwrap();
+        let expected: &[u8] = &[
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a,
+            0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00, 0x00, 0x7b, 0x40, 0xe8,
+            0xdd, 0x00, 0x00, 0x00, 0x12, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x01,
+            0x07, 0x00, 0xf8, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0x07,
+            0x00, 0x01, 0xff, 0x55, 0x36, 0xba, 0xc7, 0x00, 0x00, 0x00, 0x00, 0x49,
+            0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+        ];
+        assert_eq!(png, expected);
+    }
+
+    #[test]
+    fn ppm_of_two_pixels() {
+        let mut ppm = vec![];
+        write_ppm(&mut ppm, 2, 1, &[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(ppm, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+}
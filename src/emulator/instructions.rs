@@ -1,67 +1,13 @@
-use super::{Emulator, modrm::ModRM, add_i2u_32, Eflags, RegIdx, io_func};
-use std::process;
+use super::{Emulator, Personality, modrm::ModRM, add_i2u_32, Eflags, RegIdx, disasm};
 
-impl Emulator {
-    pub fn init_instructions(&self) -> [Option<fn(&mut Emulator) -> ()>; 256] {
-        let mut instructions: [Option<fn(&mut Emulator) -> ()>; 256] = [None; 256];
-
-        instructions[0x01] = Some(Emulator::add_rm32_r32);
-
-        instructions[0x3b] = Some(Emulator::cmp_r32_rm32);
-        instructions[0x3c] = Some(Emulator::cmp_al_imm8);
-        instructions[0x3d] = Some(Emulator::cmp_eax_imm8);
-
-        for inst in &mut instructions[0x40..0x48] {
-            *inst = Some(Emulator::inc_r32);
-        }
+pub type Instructions = [Option<disasm::Handler>; 256];
 
-        for inst in &mut instructions[0x50..0x58] {
-            *inst = Some(Emulator::push_r32);
-        }
-
-        for inst in &mut instructions[0x58..0x60] {
-            *inst = Some(Emulator::pop_r32);
-        }
-
-        instructions[0x68] = Some(Emulator::push_imm32);
-        instructions[0x6a] = Some(Emulator::push_imm8);
-
-        instructions[0x70] = Some(Emulator::jo);
-        instructions[0x71] = Some(Emulator::jno);
-        instructions[0x72] = Some(Emulator::jc);
-        instructions[0x73] = Some(Emulator::jnc);
-        instructions[0x74] = Some(Emulator::jz);
-        instructions[0x75] = Some(Emulator::jnz);
-        instructions[0x78] = Some(Emulator::js);
-        instructions[0x79] = Some(Emulator::jns);
-        instructions[0x7c] = Some(Emulator::jl);
-        instructions[0x7e] = Some(Emulator::jle);
-
-        instructions[0x83] = Some(Emulator::code_83);
-        instructions[0x88] = Some(Emulator::mov_rm8_r8);
-        instructions[0x89] = Some(Emulator::mov_rm32_r32);
-        instructions[0x8a] = Some(Emulator::mov_r8_rm8);
-        instructions[0x8b] = Some(Emulator::mov_r32_rm32);
-
-        for inst in &mut instructions[0xb0..0xb8] {
-            *inst = Some(Emulator::mov_r8_imm8);
-        }
-
-        for inst in &mut instructions[0xb8..0xc0] {
-            *inst = Some(Emulator::mov_r32_imm32);
+impl Emulator {
+    pub fn init_instructions(&self) -> Instructions {
+        let mut instructions: Instructions = [None; 256];
+        for (op, inst) in instructions.iter_mut().enumerate() {
+            *inst = disasm::one_byte(op as u8).and_then(|o| o.exec);
         }
-
-        instructions[0xc3] = Some(Emulator::ret);
-        instructions[0xc7] = Some(Emulator::mov_rm32_imm32);
-        instructions[0xc9] = Some(Emulator::leave);
-        instructions[0xcd] = Some(Emulator::int);
-        instructions[0xe8] = Some(Emulator::call_rel32);
-        instructions[0xe9] = Some(Emulator::near_jump);
-        instructions[0xec] = Some(Emulator::in_al_dx);
-        instructions[0xee] = Some(Emulator::out_dx_al);
-        instructions[0xeb] = Some(Emulator::short_jump);
-        instructions[0xff] = Some(Emulator::code_ff);
-
         instructions
     }
 
@@ -77,7 +23,7 @@ impl Emulator {
     }
 
     pub fn near_jump(&mut self) {
-        self.eip = add_i2u_32(self.eip, self.get_signed_code32(1) as i32 + 5);
+        self.eip = add_i2u_32(self.eip, self.get_signed_code32(1) + 5);
     }
 
     pub fn mov_rm32_imm32(&mut self) {
@@ -87,7 +33,7 @@ impl Emulator {
         let val: u32 = self.get_code32(0);
         self.eip += 4;
 
-        self.set_rm32(&mut modrm, val);
+        self.set_rm32(&modrm, val);
     }
 
     pub fn mov_rm32_r32(&mut self) {
@@ -95,14 +41,14 @@ impl Emulator {
         let mut modrm = ModRM::new();
         self.parse_modrm(&mut modrm);
         let r32 = self.get_r32(&modrm);
-        self.set_rm32(&mut modrm, r32);
+        self.set_rm32(&modrm, r32);
     }
 
     pub fn mov_r32_rm32(&mut self) {
         self.eip += 1;
         let mut modrm = ModRM::new();
         self.parse_modrm(&mut modrm);
-        let rm32 = self.get_rm32(&mut modrm);
+        let rm32 = self.get_rm32(&modrm);
         self.set_r32(&modrm, rm32);
     }
 
@@ -113,12 +59,12 @@ impl Emulator {
         let r32 = self.get_r32(&modrm);
         let rm32 = self.get_rm32(&modrm);
         let res = rm32 + r32;
-        self.set_rm32(&mut modrm, rm32 + r32);
+        self.set_rm32(&modrm, rm32 + r32);
         self.update_eflags_sub(rm32, r32, res as u64);
     }
 
     pub fn sub_rm32_imm8(&mut self, modrm: &mut ModRM) {
-        let rm32 = self.get_rm32(&modrm);
+        let rm32 = self.get_rm32(modrm);
         let imm8 = self.get_signed_code8(0) as i32 as u32;
         self.eip += 1;
         let res = (rm32 - imm8) as u64;
@@ -139,18 +85,22 @@ impl Emulator {
                 self.sub_rm32_imm8(&mut modrm);
             },
             7 => {
-                self.cmp_rm32_imm8(&mut modrm);
+                self.cmp_rm32_imm8(&modrm);
             },
             _ => {
-                println!("not implemented : 83 /{}", unsafe { modrm.op_reg.opcode });
-                process::exit(1);
+                self.not_implemented(format_args!("83 /{}", unsafe { modrm.op_reg.opcode }));
             }
         }
     }
 
     pub fn inc_rm32(&mut self, modrm: &mut ModRM) {
         let val = self.get_rm32(modrm);
-        self.set_rm32(modrm, val + 1);
+        self.set_rm32(modrm, val.wrapping_add(1));
+    }
+
+    pub fn dec_rm32(&mut self, modrm: &mut ModRM) {
+        let val = self.get_rm32(modrm);
+        self.set_rm32(modrm, val.wrapping_sub(1));
     }
 
     pub fn code_ff(&mut self) {
@@ -160,13 +110,81 @@ impl Emulator {
 
         match unsafe { modrm.op_reg.opcode } {
             0 => { self.inc_rm32(&mut modrm); },
+            1 => { self.dec_rm32(&mut modrm); },
+            2 => {
+                let target = self.get_rm32(&modrm);
+                self.push32(self.eip);
+                self.eip = target;
+            },
+            // Far forms take an m16:32 pointer. Without segmentation the
+            // selector is only pushed, as zero, for a far return to pop.
+            3 if modrm.modu != 3 => {
+                let target = self.get_rm32(&modrm);
+                self.push32(0);
+                self.push32(self.eip);
+                self.eip = target;
+            },
+            4 => { self.eip = self.get_rm32(&modrm); },
+            5 if modrm.modu != 3 => { self.eip = self.get_rm32(&modrm); },
+            6 => {
+                let val = self.get_rm32(&modrm);
+                self.push32(val);
+            },
             _ => {
-                println!("not implemented: ff /{}", unsafe { modrm.op_reg.opcode });
-                process::exit(1);
+                self.not_implemented(format_args!("ff /{}", unsafe { modrm.op_reg.opcode }));
+            }
+        }
+    }
+
+    pub fn xor_rm32_r32(&mut self) {
+        self.eip += 1;
+        let mut modrm = ModRM::new();
+        self.parse_modrm(&mut modrm);
+        let val = self.get_rm32(&modrm) ^ self.get_r32(&modrm);
+        self.set_rm32(&modrm, val);
+        self.set_eflags(Eflags::Carry, false);
+        self.set_eflags(Eflags::Overflow, false);
+        self.set_eflags(Eflags::Zero, val == 0);
+        self.set_eflags(Eflags::Sign, (val >> 31) != 0);
+    }
+
+    pub fn code_0f(&mut self) {
+        let code = self.get_code8(1);
+        self.eip += 1;
+
+        match disasm::two_byte(code).and_then(|o| o.exec) {
+            Some(exec) => exec(self),
+            None => {
+                self.not_implemented(format_args!("0f {:02x}", code));
             }
         }
     }
 
+    /// Operand-size prefix. Only the 16-bit port accesses are implemented;
+    /// they are how guests program the Bochs dispi registers.
+    pub fn code_66(&mut self) {
+        let code = self.get_code8(1);
+        self.eip += 1;
+
+        match disasm::operand_size_prefixed(code).and_then(|o| o.exec) {
+            Some(exec) => exec(self),
+            None => {
+                self.not_implemented(format_args!("66 {:02x}", code));
+            }
+        }
+    }
+
+    /// Without a vDSO to return through, `sysenter` behaves like `int 0x80`
+    /// and resumes at the following instruction.
+    pub fn sysenter(&mut self) {
+        self.eip += 1;
+        if self.personality == Personality::Linux {
+            self.linux_syscall();
+        } else {
+            println!("sysenter outside Linux personality");
+        }
+    }
+
     pub fn push_r32(&mut self) {
         let reg = self.get_code8(0) - 0x50;
         self.push32(self.get_register32(reg));
@@ -334,7 +352,7 @@ impl Emulator {
 
     pub fn in_al_dx(&mut self) {
         let addr = (self.get_register32(2) & 0xffff) as u16;
-        let val = io_func::io_in8(addr);
+        let val = self.io_in8(addr);
         self.set_register8(0, val);
         self.eip += 1;
     }
@@ -342,7 +360,35 @@ impl Emulator {
     pub fn out_dx_al(&mut self) {
         let addr = (self.get_register32(RegIdx::Edx as u8) & 0xffff) as u16;
         let val = self.get_register8(RegIdx::al()); 
-        io_func::io_out8(addr, val);
+        self.io_out8(addr, val);
+        self.eip += 1;
+    }
+
+    pub fn in_eax_dx(&mut self) {
+        let addr = (self.get_register32(RegIdx::Edx as u8) & 0xffff) as u16;
+        let val = self.io_in32(addr);
+        self.set_register32(RegIdx::Eax as u8, val);
+        self.eip += 1;
+    }
+
+    pub fn in_ax_dx(&mut self) {
+        let addr = self.get_register16(RegIdx::Edx as u8);
+        let val = self.io_in16(addr);
+        self.set_register16(RegIdx::Eax as u8, val);
+        self.eip += 1;
+    }
+
+    pub fn out_dx_ax(&mut self) {
+        let addr = self.get_register16(RegIdx::Edx as u8);
+        let val = self.get_register16(RegIdx::Eax as u8);
+        self.io_out16(addr, val);
+        self.eip += 1;
+    }
+
+    pub fn out_dx_eax(&mut self) {
+        let addr = (self.get_register32(RegIdx::Edx as u8) & 0xffff) as u16;
+        let val = self.get_register32(RegIdx::Eax as u8);
+        self.io_out32(addr, val);
         self.eip += 1;
     }
 
@@ -357,7 +403,7 @@ impl Emulator {
         let mut modrm = ModRM::new();
         self.parse_modrm(&mut modrm);
         let r8 = self.get_r8(&modrm);
-        self.set_rm8(&mut modrm, r8);
+        self.set_rm8(&modrm, r8);
     }
 
     pub fn cmp_al_imm8(&mut self) {
@@ -394,9 +440,68 @@ impl Emulator {
         let int_idx = self.get_code8(1);
         self.eip += 2;
 
+        if self.personality == Personality::Linux {
+            if int_idx == 0x80 {
+                self.linux_syscall();
+            } else {
+                println!("unknown interrupt: {:#02x}", int_idx);
+            }
+            return;
+        }
+
         match int_idx {
             0x10 => { self.bios_video(); },
+            0x11 => { self.bios_equipment(); },
+            0x12 => { self.bios_memory_size(); },
+            0x13 => { self.bios_disk(); },
+            0x15 => { self.bios_system(); },
+            0x16 => { self.bios_keyboard(); },
+            0x1a => { self.bios_clock(); },
             _ => { println!("unknown interrupt: {:#02x}", int_idx); }
         }
     }
+
+    /// Pops the return address and discards the selector above it.
+    pub fn far_ret(&mut self) {
+        self.eip = self.pop32();
+        self.pop32();
+    }
+
+    /// Without segmentation the frame is just EIP and EFLAGS.
+    pub fn iret(&mut self) {
+        self.eip = self.pop32();
+        self.eflags = self.pop32();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{disasm, vbe::VBE_DISPI_INDEX_XRES};
+
+    #[test]
+    fn operand_size_port_access() {
+        let code = [
+            0xba, 0xce, 0x01, 0x00, 0x00, // mov edx, 0x1ce
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0x66, 0xef,                   // out dx, ax
+            0xba, 0xcf, 0x01, 0x00, 0x00, // mov edx, 0x1cf
+            0xb8, 0x40, 0x01, 0x00, 0x00, // mov eax, 320
+            0x66, 0xef,                   // out dx, ax
+            0xb8, 0x00, 0x00, 0xff, 0xff, // mov eax, 0xffff0000
+            0x66, 0xed,                   // in ax, dx
+        ];
+        let mut emu = Emulator::new(0x1000, 0, 0x1000);
+        emu.memory[..code.len()].copy_from_slice(&code);
+        let instructions = emu.init_instructions();
+        while (emu.eip as usize) < code.len() {
+            emu.step(&instructions).unwrap();
+        }
+        assert_eq!(emu.vbe.regs[VBE_DISPI_INDEX_XRES], 320);
+        assert_eq!(emu.get_register32(RegIdx::Eax as u8), 0xffff_0140);
+
+        let text: Vec<String> = disasm::disassemble_bytes(&code[10..12], 0).into_iter()
+            .map(|inst| inst.text).collect();
+        assert_eq!(text, ["out dx, ax"]);
+    }
 }
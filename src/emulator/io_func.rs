@@ -1,21 +1,123 @@
 use std::io;
-use std::io::{Write, Read};
-
-pub fn io_in8(addr: u16) -> u8 {
-    match addr {
-        0x03f8 => {
-            io::stdin().bytes().nth(0).unwrap().unwrap()
-        },
-        _ => 0
+use std::io::Write;
+use super::{Emulator, replay::InputKind, vbe::{VBE_DISPI_IOPORT_INDEX, VBE_DISPI_IOPORT_DATA},
+            chipset::{PCI_CONFIG_ADDRESS, PCI_CONFIG_DATA, POST_PORT, DEBUG_PORT,
+                      CMOS_INDEX, CMOS_DATA}};
+
+impl Emulator {
+    pub fn io_in8(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x03f8 => {
+                // at the end of input the line reads as idle, all ones
+                let byte = self.host_input(InputKind::Serial, |emu| {
+                    match emu.keyboard.read_host_bytes(1, false).first() {
+                        Some(&b) => vec![b],
+                        None => vec![0xff],
+                    }
+                });
+                byte.first().copied().unwrap_or(0xff)
+            },
+            CMOS_DATA => {
+                let now = self.clock();
+                self.chipset.read_cmos(now)
+            },
+            0x0cfc..=0x0cff => self.chipset.pci_read8(addr - PCI_CONFIG_DATA),
+            0x03c7 => self.vga.dac_read_index,
+            0x03c8 => self.vga.dac_write_index,
+            0x03c9 => self.vga.read_dac(),
+            0x03d4 => self.vga.crtc_index,
+            0x03d5 => self.vga.read_crtc(),
+            _ => 0
+        }
+    }
+
+    pub fn io_out8(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x03f8 | DEBUG_PORT if self.repeating() => (),
+            0x03f8 => {
+                print!("{}", val as char);
+                io::stdout().flush().unwrap();
+            },
+            CMOS_INDEX => { self.chipset.cmos_index = val; },
+            CMOS_DATA => { self.chipset.write_cmos(val); },
+            POST_PORT => { self.chipset.post_code = Some(val); },
+            DEBUG_PORT => {
+                print!("{}", val as char);
+                io::stdout().flush().unwrap();
+            },
+            0x0cfc..=0x0cff => { self.chipset.pci_write8(addr - PCI_CONFIG_DATA, val); },
+            0x03c7 => {
+                self.vga.dac_read_index = val;
+                self.vga.dac_component = 0;
+            },
+            0x03c8 => {
+                self.vga.dac_write_index = val;
+                self.vga.dac_component = 0;
+            },
+            0x03c9 => { self.vga.write_dac(val); },
+            0x03d4 => { self.vga.crtc_index = val; },
+            0x03d5 => { self.vga.write_crtc(val); },
+            _ => ()
+        }
+    }
+
+    pub fn io_in16(&mut self, addr: u16) -> u16 {
+        match addr {
+            VBE_DISPI_IOPORT_INDEX => self.vbe.index,
+            VBE_DISPI_IOPORT_DATA => self.vbe.read_data(),
+            _ => {
+                self.io_in8(addr) as u16 | (self.io_in8(addr.wrapping_add(1)) as u16) << 8
+            }
+        }
+    }
+
+    pub fn io_out16(&mut self, addr: u16, val: u16) {
+        match addr {
+            VBE_DISPI_IOPORT_INDEX => { self.vbe.index = val; },
+            VBE_DISPI_IOPORT_DATA => { self.vbe.write_data(val); },
+            _ => {
+                self.io_out8(addr, (val & 0xff) as u8);
+                self.io_out8(addr.wrapping_add(1), (val >> 8) as u8);
+            }
+        }
+    }
+
+    /// 32-bit port access. Apart from the PCI configuration ports the
+    /// devices modelled here are at most 16 bits wide, so the upper half
+    /// reads as zero and writes to it are dropped.
+    pub fn io_in32(&mut self, addr: u16) -> u32 {
+        match addr {
+            PCI_CONFIG_ADDRESS => self.chipset.pci_address,
+            PCI_CONFIG_DATA => {
+                self.io_in16(addr) as u32 | (self.io_in16(addr.wrapping_add(2)) as u32) << 16
+            },
+            _ => self.io_in16(addr) as u32
+        }
+    }
+
+    pub fn io_out32(&mut self, addr: u16, val: u32) {
+        match addr {
+            PCI_CONFIG_ADDRESS => { self.chipset.pci_address = val; },
+            PCI_CONFIG_DATA => {
+                self.io_out16(addr, (val & 0xffff) as u16);
+                self.io_out16(addr.wrapping_add(2), (val >> 16) as u16);
+            },
+            _ => { self.io_out16(addr, (val & 0xffff) as u16); }
+        }
     }
 }
 
-pub fn io_out8(addr: u16, val: u8) {
-    match addr {
-        0x03f8 => {
-            print!("{}", val as char);
-            io::stdout().flush().unwrap();
-        },
-        _ => ()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_access_at_the_last_port_wraps() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        emu.io_out16(0xffff, 0x1234);
+        assert_eq!(emu.io_in16(0xffff), 0);
+        emu.io_out16(0x03d4, 0x340e);
+        assert_eq!(emu.vga.crtc_index, 0x0e);
+        assert_eq!(emu.io_in16(0x03d4), 0x340e);
     }
 }
@@ -0,0 +1,470 @@
+use std::io;
+use std::io::{IsTerminal, Read};
+use std::process::{Command, Stdio};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{Emulator, Eflags, RegIdx};
+
+const HOST_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a lone ESC waits for the rest of an escape sequence before it
+/// is taken as the Esc key.
+const ESC_TIMEOUT: Duration = Duration::from_millis(50);
+
+pub const SHIFT_LEFT: u8 = 0x02;
+pub const SHIFT_CTRL: u8 = 0x04;
+
+/// Terminal settings to put back when the emulator exits, saved when the
+/// host terminal was switched to raw mode.
+static SAVED_STTY: Mutex<Option<String>> = Mutex::new(None);
+
+/// US layout scan code rows: (first scan code, unshifted, shifted).
+const SCANCODE_ROWS: [(u8, &[u8], &[u8]); 4] = [
+    (0x02, b"1234567890-=", b"!@#$%^&*()_+"),
+    (0x10, b"qwertyuiop[]", b"QWERTYUIOP{}"),
+    (0x1e, b"asdfghjkl;'`", b"ASDFGHJKL:\"~"),
+    (0x2b, b"\\zxcvbnm,./", b"|ZXCVBNM<>?"),
+];
+
+/// Bytes read from the host terminal by the reader thread.
+#[derive(Debug, Default)]
+pub struct HostInput {
+    pub bytes: VecDeque<u8>,
+    pub eof: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Keyboard {
+    /// Keystrokes waiting to be read, as scan code << 8 | ASCII. Gray keys
+    /// carry 0xe0 as their ASCII byte the way the enhanced functions report
+    /// them.
+    pub buffer: VecDeque<u16>,
+    /// Shift state returned by AH=02h/12h.
+    pub shift_flags: u8,
+    /// Whether the host terminal may be attached once the guest asks for a
+    /// key and no scripted input is left.
+    pub use_host: bool,
+    /// Keep Ctrl-C raising SIGINT while the terminal is in raw mode, so a
+    /// debugger front end can interrupt the guest.
+    pub keep_signals: bool,
+    /// Keep what the host typed for `take_host_input`, so the input log can
+    /// record it.
+    pub record_host: bool,
+    /// Leave the host alone while recorded instructions are re-executed;
+    /// the input log supplies its bytes through `feed_host_input` instead.
+    pub replaying: bool,
+    host: Option<Arc<Mutex<HostInput>>>,
+    /// Whether `attach_host` put the terminal into raw mode.
+    raw: bool,
+    pending: Vec<u8>,
+    /// When the incomplete escape sequence in `pending` started to wait.
+    escape_since: Option<Instant>,
+    received: Vec<u8>,
+    host_eof: bool,
+    received_eof: bool,
+}
+
+fn scancode(ch: u8) -> u8 {
+    for &(first, plain, shifted) in SCANCODE_ROWS.iter() {
+        if let Some(i) = plain.iter().chain(shifted.iter()).position(|&c| c == ch) {
+            return first + (i % plain.len()) as u8;
+        }
+    }
+    match ch {
+        b' ' => 0x39,
+        b'\r' | b'\n' => 0x1c,
+        0x08 | 0x7f => 0x0e,
+        b'\t' => 0x0f,
+        0x1b => 0x01,
+        0x01..=0x1a => scancode(ch + b'a' - 1),
+        _ => 0
+    }
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    if out.status.success() {
+        Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+    } else {
+        None
+    }
+}
+
+/// The bytes read from stdin. One thread is the only reader of stdin for
+/// the whole process, so the BIOS keyboard, the serial port, guest
+/// `read`s and the monitor prompt never race for input.
+fn host_reader() -> Arc<Mutex<HostInput>> {
+    static HOST: OnceLock<Arc<Mutex<HostInput>>> = OnceLock::new();
+    HOST.get_or_init(|| {
+        let input = Arc::new(Mutex::new(HostInput::default()));
+        let feed = input.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                let n = io::stdin().read(&mut buf).unwrap_or(0);
+                let mut input = feed.lock().unwrap();
+                if n == 0 {
+                    input.eof = true;
+                    return;
+                }
+                input.bytes.extend(&buf[..n]);
+            }
+        });
+        input
+    }).clone()
+}
+
+/// Puts the host terminal back the way it was before raw mode. Safe to
+/// call on any exit path, including from a panic hook.
+pub fn restore_terminal() {
+    if let Some(saved) = SAVED_STTY.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        stty(&[&saved]);
+    }
+}
+
+/// Shift state the way AH=02h reports it for a keystroke. The terminal
+/// only delivers characters, so the state is that of the last key read.
+fn shift_flags(key: u16) -> u8 {
+    let ch = (key & 0xff) as u8;
+    let shifted = SCANCODE_ROWS.iter().any(|(_, _, shifted)| shifted.contains(&ch));
+    match ch {
+        0x01..=0x1a if ch != b'\t' && ch != b'\r' && ch != 0x08 => SHIFT_CTRL,
+        _ if shifted => SHIFT_LEFT,
+        _ => 0,
+    }
+}
+
+/// Key for a complete CSI (`ESC [`) or SS3 (`ESC O`) sequence with the
+/// given parameter bytes and final byte, or 0 for sequences without one.
+fn escape_key(params: &[u8], last: u8) -> u16 {
+    match (params, last) {
+        (b"", b'A') => 0x48e0, (b"", b'B') => 0x50e0,
+        (b"", b'C') => 0x4de0, (b"", b'D') => 0x4be0,
+        (b"", b'H') | (b"1", b'~') | (b"7", b'~') => 0x47e0,
+        (b"", b'F') | (b"4", b'~') | (b"8", b'~') => 0x4fe0,
+        (b"", b'P') | (b"11", b'~') => 0x3b00, (b"", b'Q') | (b"12", b'~') => 0x3c00,
+        (b"", b'R') | (b"13", b'~') => 0x3d00, (b"", b'S') | (b"14", b'~') => 0x3e00,
+        (b"2", b'~') => 0x52e0, (b"3", b'~') => 0x53e0,
+        (b"5", b'~') => 0x49e0, (b"6", b'~') => 0x51e0,
+        (b"15", b'~') => 0x3f00, (b"17", b'~') => 0x4000, (b"18", b'~') => 0x4100,
+        (b"19", b'~') => 0x4200, (b"20", b'~') => 0x4300, (b"21", b'~') => 0x4400,
+        _ => 0
+    }
+}
+
+/// Length of the escape sequence at the start of `p`, which begins with
+/// ESC, and its key. `None` while more bytes are needed to tell.
+fn parse_escape(p: &[u8]) -> Option<(u16, usize)> {
+    match p.get(1) {
+        None => None,
+        Some(b'O') => p.get(2).map(|&last| (escape_key(b"", last), 3)),
+        Some(b'[') => {
+            // parameter bytes, then intermediate bytes, then the final byte
+            let params = p[2..].iter().take_while(|b| (0x30..=0x3f).contains(*b)).count();
+            let inter = p[2 + params..].iter().take_while(|b| (0x20..=0x2f).contains(*b)).count();
+            match p.get(2 + params + inter) {
+                None => None,
+                Some(&last) if (0x40..=0x7e).contains(&last) => {
+                    let key = if inter == 0 { escape_key(&p[2..2 + params], last) } else { 0 };
+                    Some((key, 3 + params + inter))
+                },
+                // not a valid sequence: just the Esc key, the rest is typed text
+                Some(_) => Some((0x011b, 1)),
+            }
+        },
+        Some(_) => Some((0x011b, 1)),
+    }
+}
+
+impl Keyboard {
+    /// Queues host-style input: plain ASCII plus the VT100/xterm escape
+    /// sequences for the cursor and editing keys and F1-F10. Other escape
+    /// sequences are dropped whole.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        self.translate_pending(true);
+    }
+
+    fn translate_pending(&mut self, complete: bool) {
+        while !self.pending.is_empty() {
+            let p = &self.pending;
+            let (key, used) = match p[0] {
+                0x1b => match parse_escape(p) {
+                    Some(parsed) => parsed,
+                    None if !complete => { return; },
+                    None => (0x011b, 1),
+                },
+                b'\n' => (0x1c0d, 1),
+                0x7f => (0x0e08, 1),
+                ch => (((scancode(ch) as u16) << 8) | ch as u16, 1),
+            };
+            self.pending.drain(..used);
+            if key != 0 {
+                self.buffer.push_back(key);
+            }
+        }
+    }
+
+    /// Puts the host terminal into raw mode and starts feeding stdin into
+    /// the keyboard buffer.
+    pub fn attach_host(&mut self) {
+        if self.host.is_some() {
+            return;
+        }
+        if io::stdin().is_terminal() {
+            let mut saved = SAVED_STTY.lock().unwrap();
+            if saved.is_none() {
+                *saved = stty(&["-g"]);
+            }
+            self.raw = saved.is_some();
+        }
+        if self.raw {
+            self.raw_mode();
+        }
+        self.host = Some(host_reader());
+    }
+
+    fn raw_mode(&self) {
+        if self.keep_signals {
+            stty(&["raw", "-echo", "isig"]);
+        } else {
+            stty(&["raw", "-echo"]);
+        }
+    }
+
+    /// Reads a line from the host with the terminal in its normal mode.
+    /// Returns `None` at end of input.
+    pub fn read_host_line(&mut self) -> Option<String> {
+        if self.raw {
+            restore_terminal();
+        }
+        let line = self.read_host_bytes(usize::MAX, true);
+        if self.raw {
+            *SAVED_STTY.lock().unwrap() = stty(&["-g"]);
+            self.raw_mode();
+        }
+        if line.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&line).into_owned())
+        }
+    }
+
+    /// Reads up to `max` bytes of stdin that the keyboard has not taken,
+    /// waiting until there is at least one, or through the next newline if
+    /// `line`. Returns nothing at end of input.
+    pub fn read_host_bytes(&mut self, max: usize, line: bool) -> Vec<u8> {
+        let host = host_reader();
+        loop {
+            {
+                let mut input = host.lock().unwrap();
+                let newline = input.bytes.iter().position(|&b| b == b'\n');
+                let ready = if line { newline.map(|i| i + 1) } else { Some(input.bytes.len()) };
+                match ready {
+                    Some(n) if n > 0 => {
+                        return input.bytes.drain(..n.min(max)).collect();
+                    },
+                    _ if input.eof => {
+                        let n = input.bytes.len().min(max);
+                        return input.bytes.drain(..n).collect();
+                    },
+                    _ => (),
+                }
+            }
+            thread::sleep(HOST_POLL_INTERVAL);
+        }
+    }
+
+    /// Restores the terminal settings saved by `attach_host`.
+    pub fn release_host(&mut self) {
+        if self.raw {
+            restore_terminal();
+            self.raw = false;
+        }
+    }
+
+    /// Moves whatever the host has typed into the buffer. Returns false once
+    /// no more input can ever arrive.
+    fn poll_host(&mut self) -> bool {
+        if self.replaying {
+            return false;
+        }
+        if self.host.is_none() {
+            if !self.use_host {
+                return false;
+            }
+            self.attach_host();
+        }
+
+        let (bytes, eof) = {
+            let mut input = self.host.as_ref().unwrap().lock().unwrap();
+            (input.bytes.drain(..).collect::<Vec<u8>>(), input.eof)
+        };
+        if self.record_host {
+            self.received.extend_from_slice(&bytes);
+            self.received_eof |= eof && !self.host_eof;
+        }
+        self.host_eof = eof;
+        let arrived = !bytes.is_empty();
+        self.pending.extend(bytes);
+        self.translate_pending(eof);
+
+        if self.pending.is_empty() {
+            self.escape_since = None;
+        } else if arrived || self.escape_since.is_none() {
+            self.escape_since = Some(Instant::now());
+        } else if self.escape_since.is_some_and(|t| t.elapsed() >= ESC_TIMEOUT) {
+            // A NUL ends the sequence as a lone Esc and makes no key of its
+            // own, so a replayed log sees the same keys.
+            if self.record_host {
+                self.received.push(0);
+            }
+            self.pending.push(0);
+            self.translate_pending(eof);
+            self.escape_since = None;
+        }
+        !eof
+    }
+
+    /// What the host delivered since the last call, and whether it reached
+    /// the end of its input in that time.
+    pub fn take_host_input(&mut self) -> (Vec<u8>, bool) {
+        let eof = self.received_eof;
+        self.received_eof = false;
+        (std::mem::take(&mut self.received), eof)
+    }
+
+    /// Whether host bytes are waiting that have not become keystrokes yet,
+    /// such as the start of an escape sequence.
+    pub fn has_pending_bytes(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Takes over the host terminal from `other`, the keyboard of the
+    /// machine this one replaces.
+    pub fn adopt_host(&mut self, other: &mut Keyboard) {
+        self.host = other.host.take();
+        self.raw = other.raw;
+        other.raw = false;
+    }
+
+    /// Delivers recorded host input the way `poll_host` would have.
+    pub fn feed_host_input(&mut self, bytes: &[u8], eof: bool) {
+        self.host_eof |= eof;
+        self.pending.extend_from_slice(bytes);
+        self.translate_pending(self.host_eof);
+    }
+
+    /// Next keystroke, waiting for the host if needed. `None` means the
+    /// scripted input ran out and no host terminal is available.
+    pub fn read_key(&mut self) -> Option<u16> {
+        loop {
+            if let Some(key) = self.buffer.pop_front() {
+                self.shift_flags = shift_flags(key);
+                return Some(key);
+            }
+            if !self.poll_host() {
+                let key = self.buffer.pop_front()?;
+                self.shift_flags = shift_flags(key);
+                return Some(key);
+            }
+            thread::sleep(HOST_POLL_INTERVAL);
+        }
+    }
+
+    pub fn peek_key(&mut self) -> Option<u16> {
+        if self.buffer.is_empty() {
+            self.poll_host();
+        }
+        self.buffer.front().cloned()
+    }
+}
+
+/// Maps an enhanced keystroke to what the original AH=00h/01h report.
+fn legacy_key(key: u16) -> u16 {
+    if (key & 0xff) == 0xe0 { key & 0xff00 } else { key }
+}
+
+impl Emulator {
+    pub fn bios_keyboard(&mut self) {
+        let f = self.get_register8(RegIdx::ah());
+        match f {
+            0x00 | 0x10 => {
+                match self.keyboard.read_key() {
+                    Some(key) => {
+                        let key = if f == 0x00 { legacy_key(key) } else { key };
+                        self.set_register16(RegIdx::Eax as u8, key);
+                    },
+                    None => {
+                        println!("\nkeyboard input exhausted");
+                        self.halted = true;
+                    }
+                }
+            },
+            0x01 | 0x11 => {
+                match self.keyboard.peek_key() {
+                    Some(key) => {
+                        let key = if f == 0x01 { legacy_key(key) } else { key };
+                        self.set_register16(RegIdx::Eax as u8, key);
+                        self.set_eflags(Eflags::Zero, false);
+                    },
+                    None => { self.set_eflags(Eflags::Zero, true); }
+                }
+            },
+            0x02 => {
+                self.set_register8(RegIdx::al() as i32, self.keyboard.shift_flags);
+            },
+            0x12 => {
+                self.set_register8(RegIdx::al() as i32, self.keyboard.shift_flags);
+                self.set_register8(RegIdx::ah() as i32, 0);
+            },
+            _ => { println!("not implemented BIOS keyboard function: {:#02x}", f); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(bytes: &[u8]) -> Vec<u16> {
+        let mut kbd = Keyboard::default();
+        kbd.push_bytes(bytes);
+        kbd.buffer.into_iter().collect()
+    }
+
+    #[test]
+    fn escape_sequences() {
+        assert_eq!(keys(b"\x1b[A\x1bOP\x1b[2~\x1b[21~"), [0x48e0, 0x3b00, 0x52e0, 0x4400]);
+        // unknown sequences are consumed through their final byte
+        assert_eq!(keys(b"\x1b[200~x\x1b[1;5Cy"), [0x2d78, 0x1579]);
+        assert_eq!(keys(b"\x1b"), [0x011b]);
+        assert_eq!(keys(b"\x1bx"), [0x011b, 0x2d78]);
+        assert_eq!(keys(b"\x1b\x00"), [0x011b]);
+    }
+
+    #[test]
+    fn incomplete_escape_waits() {
+        assert_eq!(parse_escape(b"\x1b"), None);
+        assert_eq!(parse_escape(b"\x1b[1"), None);
+        assert_eq!(parse_escape(b"\x1b[1;"), None);
+        assert_eq!(parse_escape(b"\x1b[1~"), Some((0x47e0, 4)));
+    }
+
+    #[test]
+    fn shift_state_follows_last_key() {
+        let mut kbd = Keyboard::default();
+        kbd.push_bytes(b"A\x03a");
+        kbd.read_key();
+        assert_eq!(kbd.shift_flags, SHIFT_LEFT);
+        kbd.read_key();
+        assert_eq!(kbd.shift_flags, SHIFT_CTRL);
+        kbd.read_key();
+        assert_eq!(kbd.shift_flags, 0);
+    }
+}
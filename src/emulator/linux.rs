@@ -0,0 +1,531 @@
+use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use super::{Emulator, Personality, RegIdx, loader::{ElfImage, LoadError}};
+
+pub const PAGE_SIZE: u32 = 0x1000;
+/// Room kept free below the top of memory for the initial stack; mmap
+/// allocations grow down from here.
+pub const STACK_SIZE: u32 = 0x10_0000;
+
+const EPERM: i32 = 1;
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ENOSYS: i32 = 38;
+
+const O_ACCMODE: u32 = 0x3;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_CREAT: u32 = 0x40;
+const O_TRUNC: u32 = 0x200;
+const O_APPEND: u32 = 0x400;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_RANDOM: u32 = 25;
+
+const GUEST_PID: u32 = 1000;
+const GUEST_UID: u32 = 1000;
+const GUEST_GID: u32 = 1000;
+/// First GDT slot for thread-local storage, handed out by set_thread_area.
+const GDT_ENTRY_TLS_MIN: u32 = 6;
+const UTSNAME_FIELD: u32 = 65;
+
+#[derive(Debug, Clone)]
+pub enum LinuxFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    Host(Arc<File>),
+}
+
+/// Process state of the i386 Linux user-mode personality.
+#[derive(Debug, Default, Clone)]
+pub struct Linux {
+    pub brk_start: u32,
+    pub brk: u32,
+    /// Lowest address handed out by mmap so far.
+    pub mmap_top: u32,
+    pub files: Vec<Option<LinuxFile>>,
+    /// Base address set with set_thread_area, used for GS-relative operands.
+    pub tls_base: u32,
+}
+
+fn errno(e: i32) -> u32 {
+    (-e) as u32
+}
+
+fn io_errno(e: &io::Error) -> u32 {
+    errno(e.raw_os_error().unwrap_or(EINVAL))
+}
+
+fn page_align(addr: u32) -> Option<u32> {
+    addr.checked_add(PAGE_SIZE - 1).map(|a| a & !(PAGE_SIZE - 1))
+}
+
+impl Emulator {
+    /// Loads a static i386 Linux executable and builds the initial process
+    /// stack (argc, argv, envp and the auxiliary vector), so that `int 0x80`
+    /// is served by the emulated system calls below.
+    pub fn start_linux(&mut self, data: &[u8], args: &[String], env: &[String])
+        -> Result<ElfImage, LoadError> {
+        let image = self.load_elf(data)?;
+        let top = (self.memory.len().min(u32::MAX as usize) as u32) & !(PAGE_SIZE - 1);
+        let brk = page_align(image.end).filter(|&brk| top >= STACK_SIZE && brk <= top - STACK_SIZE)
+            .ok_or_else(|| LoadError::Format(
+                format!("memory must hold the program and a {} KiB stack", STACK_SIZE / 1024)))?;
+
+        self.personality = Personality::Linux;
+        self.linux = Linux {
+            brk_start: brk,
+            brk,
+            mmap_top: top - STACK_SIZE,
+            files: vec![Some(LinuxFile::Stdin), Some(LinuxFile::Stdout),
+                        Some(LinuxFile::Stderr)],
+            tls_base: 0,
+        };
+        self.setup_linux_stack(top, &image, args, env);
+        Ok(image)
+    }
+
+    fn push_stack_bytes(&mut self, sp: &mut u32, data: &[u8]) -> u32 {
+        *sp -= data.len() as u32;
+        for (i, &b) in data.iter().enumerate() {
+            self.set_memory8(*sp + i as u32, b as u32);
+        }
+        *sp
+    }
+
+    fn push_stack_strings(&mut self, sp: &mut u32, strings: &[String]) -> Vec<u32> {
+        strings.iter().map(|s| {
+            let mut bytes = s.as_bytes().to_vec();
+            bytes.push(0);
+            self.push_stack_bytes(sp, &bytes)
+        }).collect()
+    }
+
+    fn setup_linux_stack(&mut self, top: u32, image: &ElfImage, args: &[String],
+                         env: &[String]) {
+        let mut sp = top;
+        let argv = self.push_stack_strings(&mut sp, args);
+        let envp = self.push_stack_strings(&mut sp, env);
+        let random = self.push_stack_bytes(&mut sp, &[0x5a; 16]);
+
+        let auxv: [(u32, u32); 11] = [
+            (AT_PHDR, image.phdr), (AT_PHENT, 32), (AT_PHNUM, image.phnum as u32),
+            (AT_PAGESZ, PAGE_SIZE), (AT_ENTRY, image.entry),
+            (AT_UID, GUEST_UID), (AT_EUID, GUEST_UID), (AT_GID, GUEST_GID),
+            (AT_EGID, GUEST_GID), (AT_RANDOM, random), (AT_NULL, 0),
+        ];
+        let mut words = vec![argv.len() as u32];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        for &(key, val) in auxv.iter() {
+            words.push(key);
+            words.push(val);
+        }
+
+        sp = (sp - words.len() as u32 * 4) & !0xf;
+        for (i, &w) in words.iter().enumerate() {
+            self.set_memory32(sp + i as u32 * 4, w);
+        }
+        self.set_register32(RegIdx::Esp as u8, sp);
+    }
+
+    /// Whether `len` bytes at `addr` are guest RAM. System calls fail with
+    /// EFAULT for buffers that are not.
+    fn guest_range(&self, addr: u32, len: u32) -> bool {
+        addr as u64 + len as u64 <= self.memory.len() as u64
+    }
+
+    fn guest_string(&self, addr: u32) -> Option<String> {
+        let mut bytes = vec![];
+        let mut addr = addr;
+        loop {
+            if !self.guest_range(addr, 1) {
+                return None;
+            }
+            let b = self.get_memory8(addr) as u8;
+            if b == 0 {
+                break;
+            }
+            bytes.push(b);
+            addr += 1;
+        }
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn guest_bytes(&self, addr: u32, len: u32) -> Option<Vec<u8>> {
+        if !self.guest_range(addr, len) {
+            return None;
+        }
+        Some((0..len).map(|i| self.get_memory8(addr + i) as u8).collect())
+    }
+
+    fn guest_write(&mut self, addr: u32, data: &[u8]) -> bool {
+        if !self.guest_range(addr, data.len() as u32) {
+            return false;
+        }
+        for (i, &b) in data.iter().enumerate() {
+            self.set_memory8(addr + i as u32, b as u32);
+        }
+        true
+    }
+
+    fn linux_file(&self, fd: u32) -> Option<LinuxFile> {
+        self.linux.files.get(fd as usize).cloned().flatten()
+    }
+
+    fn sys_read(&mut self, fd: u32, buf: u32, count: u32) -> u32 {
+        let file = match self.linux_file(fd) {
+            Some(f @ LinuxFile::Stdin) | Some(f @ LinuxFile::Host(_)) => f,
+            _ => { return errno(EBADF); }
+        };
+        if !self.guest_range(buf, 1) {
+            return errno(EFAULT);
+        }
+        // short read rather than overrunning the end of memory
+        let count = count.min((self.memory.len() - buf as usize).min(u32::MAX as usize) as u32);
+        let res = self.host_read(|emu| {
+            match file {
+                LinuxFile::Host(f) => {
+                    let mut data = vec![0u8; count as usize];
+                    let n = (&*f).read(&mut data)?;
+                    data.truncate(n);
+                    Ok(data)
+                },
+                _ => Ok(emu.keyboard.read_host_bytes(count as usize, false)),
+            }
+        });
+        match res {
+            Ok(data) => {
+                self.guest_write(buf, &data);
+                data.len() as u32
+            },
+            Err(e) => io_errno(&e),
+        }
+    }
+
+    fn sys_write(&mut self, fd: u32, buf: u32, count: u32) -> u32 {
+        let data = match self.guest_bytes(buf, count) {
+            Some(data) => data,
+            None => { return errno(EFAULT); }
+        };
+        let res = match self.linux_file(fd) {
+            Some(LinuxFile::Stdin) | None => { return errno(EBADF); }
+            Some(_) if self.repeating() => Ok(()),
+            Some(LinuxFile::Stdout) => io::stdout().write_all(&data)
+                .and_then(|_| io::stdout().flush()),
+            Some(LinuxFile::Stderr) => io::stderr().write_all(&data),
+            Some(LinuxFile::Host(f)) => (&*f).write_all(&data),
+        };
+        match res {
+            Ok(()) => count,
+            Err(e) => io_errno(&e),
+        }
+    }
+
+    fn sys_writev(&mut self, fd: u32, iov: u32, iovcnt: u32) -> u32 {
+        let mut total = 0u32;
+        if !self.guest_range(iov, iovcnt.saturating_mul(8)) {
+            return errno(EFAULT);
+        }
+        for i in 0..iovcnt {
+            let base = self.get_memory32(iov + i * 8);
+            let len = self.get_memory32(iov + i * 8 + 4);
+            let res = self.sys_write(fd, base, len);
+            if (res as i32) < 0 {
+                return res;
+            }
+            total = total.saturating_add(res);
+        }
+        total
+    }
+
+    fn sys_open(&mut self, path: u32, flags: u32, mode: u32) -> u32 {
+        let path = match self.guest_string(path) {
+            Some(path) => path,
+            None => { return errno(EFAULT); }
+        };
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options.create((flags & O_CREAT) != 0)
+            .truncate((flags & O_TRUNC) != 0)
+            .append((flags & O_APPEND) != 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        match options.open(&path) {
+            Ok(f) => {
+                let file = Some(LinuxFile::Host(Arc::new(f)));
+                match self.linux.files.iter().position(|f| f.is_none()) {
+                    Some(fd) => {
+                        self.linux.files[fd] = file;
+                        fd as u32
+                    },
+                    None => {
+                        self.linux.files.push(file);
+                        self.linux.files.len() as u32 - 1
+                    }
+                }
+            },
+            Err(e) => io_errno(&e),
+        }
+    }
+
+    fn sys_close(&mut self, fd: u32) -> u32 {
+        match self.linux.files.get_mut(fd as usize) {
+            Some(f @ Some(_)) => {
+                *f = None;
+                0
+            },
+            _ => errno(EBADF),
+        }
+    }
+
+    fn sys_lseek(&mut self, fd: u32, offset: u32, whence: u32) -> u32 {
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i32 as i64),
+            2 => SeekFrom::End(offset as i32 as i64),
+            _ => { return errno(EINVAL); }
+        };
+        match self.linux_file(fd) {
+            Some(LinuxFile::Host(f)) => match (&*f).seek(pos) {
+                Ok(n) => n as u32,
+                Err(e) => io_errno(&e),
+            },
+            Some(_) => errno(EINVAL),
+            None => errno(EBADF),
+        }
+    }
+
+    fn sys_brk(&mut self, addr: u32) -> u32 {
+        if addr >= self.linux.brk_start && addr < self.linux.mmap_top {
+            let start = self.linux.brk.min(addr) as usize;
+            for b in &mut self.memory[start..addr as usize] {
+                *b = 0;
+            }
+            self.linux.brk = addr;
+        }
+        self.linux.brk
+    }
+
+    /// Anonymous and private file mappings carved downwards from below the
+    /// stack. Mappings are never given back.
+    fn sys_mmap(&mut self, addr: u32, len: u32, flags: u32, fd: u32, offset: u64) -> u32 {
+        if len == 0 {
+            return errno(EINVAL);
+        }
+        let len = match page_align(len) {
+            Some(len) => len,
+            None => { return errno(ENOMEM); }
+        };
+        let start = if (flags & MAP_FIXED) != 0 {
+            if !self.guest_range(addr, len) {
+                return errno(ENOMEM);
+            }
+            addr
+        } else {
+            match self.linux.mmap_top.checked_sub(len) {
+                Some(start) if start >= self.linux.brk => {
+                    self.linux.mmap_top = start;
+                    start
+                },
+                _ => { return errno(ENOMEM); }
+            }
+        };
+
+        for b in &mut self.memory[start as usize..start as usize + len as usize] {
+            *b = 0;
+        }
+        if (flags & MAP_ANONYMOUS) == 0 {
+            let file = match self.linux_file(fd) {
+                Some(LinuxFile::Host(f)) => f,
+                _ => { return errno(EBADF); }
+            };
+            let res = self.host_read(|_| {
+                let mut data = vec![];
+                (&*file).seek(SeekFrom::Start(offset))?;
+                (&*file).take(len as u64).read_to_end(&mut data)?;
+                Ok(data)
+            });
+            let data = match res {
+                Ok(data) => data,
+                Err(e) => { return io_errno(&e); }
+            };
+            self.memory[start as usize..start as usize + data.len()].copy_from_slice(&data);
+        }
+        start
+    }
+
+    fn sys_uname(&mut self, buf: u32) -> u32 {
+        if buf == 0 || !self.guest_range(buf, 6 * UTSNAME_FIELD) {
+            return errno(EFAULT);
+        }
+        let fields = ["Linux", "x86_emu", "4.4.0", "#1", "i686", ""];
+        for (i, field) in fields.iter().enumerate() {
+            let base = buf + i as u32 * UTSNAME_FIELD;
+            for j in 0..UTSNAME_FIELD {
+                let b = field.as_bytes().get(j as usize).cloned().unwrap_or(0);
+                self.set_memory8(base + j, b as u32);
+            }
+        }
+        0
+    }
+
+    /// Takes the base of a `user_desc` as the GS base; there is no GDT, so
+    /// any entry number works and -1 is answered with the first TLS slot.
+    fn sys_set_thread_area(&mut self, desc: u32) -> u32 {
+        if !self.guest_range(desc, 16) {
+            return errno(EFAULT);
+        }
+        let entry = self.get_memory32(desc);
+        if entry == u32::MAX {
+            self.set_memory32(desc, GDT_ENTRY_TLS_MIN);
+        }
+        self.linux.tls_base = self.get_memory32(desc + 4);
+        0
+    }
+
+    fn sys_exit(&mut self, code: u32) -> u32 {
+        self.exit_code = Some(code as i32);
+        self.halted = true;
+        0
+    }
+
+    /// Dispatches the system call in EAX with arguments in EBX, ECX, EDX,
+    /// ESI, EDI and EBP, returning the result (or -errno) in EAX.
+    pub fn linux_syscall(&mut self) {
+        let nr = self.get_register32(RegIdx::Eax as u8);
+        let a1 = self.get_register32(RegIdx::Ebx as u8);
+        let a2 = self.get_register32(RegIdx::Ecx as u8);
+        let a3 = self.get_register32(RegIdx::Edx as u8);
+        let a4 = self.get_register32(RegIdx::Esi as u8);
+        let a5 = self.get_register32(RegIdx::Edi as u8);
+        let a6 = self.get_register32(RegIdx::Ebp as u8);
+
+        let res = match nr {
+            1 | 252 => self.sys_exit(a1),
+            3 => self.sys_read(a1, a2, a3),
+            4 => self.sys_write(a1, a2, a3),
+            5 => self.sys_open(a1, a2, a3),
+            6 => self.sys_close(a1),
+            13 => {
+                let now = self.clock() as u32;
+                if a1 != 0 && !self.guest_write(a1, &now.to_le_bytes()) {
+                    errno(EFAULT)
+                } else {
+                    now
+                }
+            },
+            19 => self.sys_lseek(a1, a2, a3),
+            20 | 224 | 258 => GUEST_PID,
+            24 | 49 | 199 | 201 => GUEST_UID,
+            47 | 50 | 200 | 202 => GUEST_GID,
+            33 => match self.guest_string(a1) {
+                Some(path) if std::path::Path::new(&path).exists() => 0,
+                Some(_) => errno(ENOENT),
+                None => errno(EFAULT),
+            },
+            45 => self.sys_brk(a1),
+            54 => errno(ENOTTY),
+            90 if !self.guest_range(a1, 24) => errno(EFAULT),
+            90 if !self.guest_range(a1, 24) => errno(EFAULT),
+            90 => {
+                // old_mmap takes its six arguments from a block in memory
+                let args: Vec<u32> = (0..6).map(|i| self.get_memory32(a1 + i * 4)).collect();
+                self.sys_mmap(args[0], args[1], args[3], args[4], args[5] as u64)
+            },
+            91 | 125 => 0,
+            122 => self.sys_uname(a1),
+            146 => self.sys_writev(a1, a2, a3),
+            192 => self.sys_mmap(a1, a2, a4, a5, a6 as u64 * PAGE_SIZE as u64),
+            23 | 213 => if a1 == GUEST_UID { 0 } else { errno(EPERM) },
+            243 => self.sys_set_thread_area(a1),
+            _ => {
+                eprintln!("not implemented Linux system call: {}", nr);
+                errno(ENOSYS)
+            }
+        };
+        self.set_register32(RegIdx::Eax as u8, res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &[u8] = include_bytes!("../../tests/data/linux_e2e.elf");
+
+    fn process(mem: usize) -> Emulator {
+        let mut emu = Emulator::new(mem, 0, 0);
+        emu.start_linux(PROGRAM, &["prog".to_string()], &[]).unwrap();
+        emu
+    }
+
+    fn syscall(emu: &mut Emulator, nr: u32, args: [u32; 6]) -> u32 {
+        emu.set_register32(RegIdx::Eax as u8, nr);
+        let regs = [RegIdx::Ebx as u8, RegIdx::Ecx as u8, RegIdx::Edx as u8,
+                    RegIdx::Esi as u8, RegIdx::Edi as u8, RegIdx::Ebp as u8];
+        for (&reg, &val) in regs.iter().zip(args.iter()) {
+            emu.set_register32(reg, val);
+        }
+        emu.linux_syscall();
+        emu.get_register32(RegIdx::Eax as u8)
+    }
+
+    #[test]
+    fn memory_must_hold_the_stack() {
+        let mut emu = Emulator::new(0x0808_0000, 0, 0);
+        assert!(matches!(emu.start_linux(PROGRAM, &[], &[]), Err(LoadError::Format(_))));
+        let mut emu = Emulator::new(0x8_0000, 0, 0);
+        assert!(emu.start_linux(PROGRAM, &[], &[]).is_err());
+    }
+
+    #[test]
+    fn mmap() {
+        let mut emu = process(0x0900_0000);
+        let flags = MAP_ANONYMOUS | 0x02;
+        let top = emu.linux.mmap_top;
+        assert_eq!(syscall(&mut emu, 192, [0, 0x1001, 3, flags, u32::MAX, 0]), top - 0x2000);
+        assert_eq!(syscall(&mut emu, 192, [0, u32::MAX, 3, flags, u32::MAX, 0]), errno(ENOMEM));
+        assert_eq!(syscall(&mut emu, 192, [0, 0x1000_0000, 3, flags, u32::MAX, 0]), errno(ENOMEM));
+        assert_eq!(syscall(&mut emu, 192, [0xffff_f000, 0x1000, 3, flags | MAP_FIXED, u32::MAX, 0]),
+                   errno(ENOMEM));
+        assert_eq!(syscall(&mut emu, 90, [u32::MAX - 8, 0, 0, 0, 0, 0]), errno(EFAULT));
+    }
+
+    #[test]
+    fn unknown_syscall() {
+        let mut emu = process(0x0900_0000);
+        assert_eq!(syscall(&mut emu, 9999, [0; 6]), errno(ENOSYS));
+    }
+}
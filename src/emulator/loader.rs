@@ -0,0 +1,357 @@
+use std::fmt;
+use std::io;
+use std::error;
+use byteorder::{LittleEndian, ByteOrder};
+use super::{Emulator, RegIdx, disk::SECTOR_SIZE};
+
+/// Where a PC BIOS loads the boot sector and starts executing it.
+pub const BOOT_ADDRESS: u32 = 0x7c00;
+pub const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_386: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const ELF_HEADER_SIZE: usize = 52;
+const ELF_PHDR_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    NoDisk(u8),
+    /// The disk is too small to hold a boot sector.
+    NotBootable(u8),
+    /// The image does not fit into guest memory at the given address.
+    OutOfMemory(u32),
+    /// The file is not a valid image of the expected format.
+    Format(String),
+    NotElf,
+    /// A well-formed ELF file this loader cannot run, e.g. 64-bit or not
+    /// for i386.
+    UnsupportedElf(&'static str),
+    /// A header or segment points past the end of the file.
+    Truncated,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::NoDisk(drive) => write!(f, "no disk attached as drive {:#04x}", drive),
+            LoadError::NotBootable(drive) => {
+                write!(f, "drive {:#04x} has no boot sector", drive)
+            },
+            LoadError::OutOfMemory(addr) => {
+                write!(f, "image does not fit into memory at {:#010x}", addr)
+            },
+            LoadError::Format(msg) => write!(f, "invalid image: {}", msg),
+            LoadError::NotElf => write!(f, "not an ELF file"),
+            LoadError::UnsupportedElf(why) => write!(f, "unsupported ELF file: {}", why),
+            LoadError::Truncated => write!(f, "file is truncated"),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+/// What the ELF loader placed in memory.
+#[derive(Debug, Default, Clone)]
+pub struct ElfImage {
+    pub entry: u32,
+    /// Guest address of the program header table, if a segment maps it.
+    pub phdr: u32,
+    pub phnum: u16,
+    /// First address above every loaded segment.
+    pub end: u32,
+}
+
+pub(super) fn slice_at(data: &[u8], offset: usize, len: usize) -> Result<&[u8], LoadError> {
+    offset.checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(LoadError::Truncated)
+}
+
+impl Emulator {
+    /// Copies `data` into guest memory at `addr`.
+    pub fn load_binary(&mut self, addr: u32, data: &[u8]) -> Result<(), LoadError> {
+        let start = addr as usize;
+        if start + data.len() > self.memory.len() {
+            return Err(LoadError::OutOfMemory(addr));
+        }
+        self.memory[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Loads sector 0 of `drive` at 0x7c00 and hands control to it the way a
+    /// PC BIOS does: DL holds the boot drive, ESP points just below the boot
+    /// sector and interrupts are enabled. A sector without the 0x55 0xaa
+    /// signature is booted anyway, with a warning.
+    ///
+    /// There is no real mode: the sector runs on the flat 32-bit CPU model,
+    /// so it has to be 32-bit code, and CS, DS, ES and SS are implicitly
+    /// zero.
+    pub fn boot_from_disk(&mut self, drive: u8) -> Result<(), LoadError> {
+        let sector = self.disk(drive)
+            .ok_or(LoadError::NoDisk(drive))?
+            .read_sectors(0, 1)
+            .ok_or(LoadError::NotBootable(drive))?
+            .to_vec();
+        if sector[SECTOR_SIZE - 2..] != BOOT_SIGNATURE {
+            println!("warning: drive {:#04x} has no boot signature; booting it anyway", drive);
+        }
+
+        self.load_binary(BOOT_ADDRESS, &sector)?;
+        self.registers.regs = [0; 8];
+        self.set_register8(RegIdx::dl() as i32, drive);
+        self.set_register32(RegIdx::Esp as u8, BOOT_ADDRESS);
+        self.eflags = 0x0202;
+        self.eip = BOOT_ADDRESS;
+        Ok(())
+    }
+
+    /// Maps the PT_LOAD segments of a static i386 ELF executable, zero-filling
+    /// the part of each segment beyond the file data (BSS). EIP is set to the
+    /// entry point and ESP to the top of memory.
+    pub fn load_elf(&mut self, data: &[u8]) -> Result<ElfImage, LoadError> {
+        self.load_elf_segments(data, false)
+    }
+
+    /// Like `load_elf`, but places each segment at its physical address, as
+    /// boot loaders do. An entry point inside a segment is translated too.
+    pub fn load_elf_physical(&mut self, data: &[u8]) -> Result<ElfImage, LoadError> {
+        self.load_elf_segments(data, true)
+    }
+
+    fn load_elf_segments(&mut self, data: &[u8], physical: bool) -> Result<ElfImage, LoadError> {
+        let header = slice_at(data, 0, ELF_HEADER_SIZE).map_err(|_| LoadError::NotElf)?;
+        if header[0..4] != ELF_MAGIC {
+            return Err(LoadError::NotElf);
+        }
+        if header[4] != ELFCLASS32 {
+            return Err(LoadError::UnsupportedElf("not a 32-bit object"));
+        }
+        if header[5] != ELFDATA2LSB {
+            return Err(LoadError::UnsupportedElf("not little-endian"));
+        }
+        if LittleEndian::read_u16(&header[16..]) != ET_EXEC {
+            return Err(LoadError::UnsupportedElf("not an executable"));
+        }
+        if LittleEndian::read_u16(&header[18..]) != EM_386 {
+            return Err(LoadError::UnsupportedElf("not an i386 executable"));
+        }
+
+        let mut image = ElfImage {
+            entry: LittleEndian::read_u32(&header[24..]),
+            phnum: LittleEndian::read_u16(&header[44..]),
+            ..ElfImage::default()
+        };
+        let mut entry = image.entry;
+        let phoff = LittleEndian::read_u32(&header[28..]) as usize;
+        let phentsize = LittleEndian::read_u16(&header[42..]) as usize;
+        if phentsize < ELF_PHDR_SIZE {
+            return Err(LoadError::UnsupportedElf("bad program header size"));
+        }
+
+        for i in 0..image.phnum as usize {
+            let ph = slice_at(data, phoff + i * phentsize, ELF_PHDR_SIZE)?;
+            let kind = LittleEndian::read_u32(&ph[0..]);
+            let offset = LittleEndian::read_u32(&ph[4..]) as usize;
+            let vaddr = LittleEndian::read_u32(&ph[8..]);
+            let paddr = LittleEndian::read_u32(&ph[12..]);
+            let filesz = LittleEndian::read_u32(&ph[16..]) as usize;
+            let memsz = LittleEndian::read_u32(&ph[20..]) as usize;
+
+            if kind == PT_PHDR {
+                image.phdr = vaddr;
+            }
+            if kind != PT_LOAD || memsz == 0 {
+                continue;
+            }
+            if filesz > memsz {
+                return Err(LoadError::UnsupportedElf("segment file size exceeds memory size"));
+            }
+            let addr = if physical { paddr } else { vaddr };
+            let start = addr as usize;
+            if start + memsz > self.memory.len() {
+                return Err(LoadError::OutOfMemory(addr));
+            }
+
+            self.load_binary(addr, slice_at(data, offset, filesz)?)?;
+            for b in &mut self.memory[start + filesz..start + memsz] {
+                *b = 0;
+            }
+            if image.phdr == 0 && phoff >= offset && phoff < offset + filesz {
+                image.phdr = vaddr + (phoff - offset) as u32;
+            }
+            if physical && image.entry.wrapping_sub(vaddr) < memsz as u32 {
+                entry = paddr + (image.entry - vaddr);
+            }
+            image.end = image.end.max((start + memsz) as u32);
+        }
+        image.entry = entry;
+
+        if image.end == 0 {
+            return Err(LoadError::UnsupportedElf("no loadable segments"));
+        }
+        self.eip = image.entry;
+        self.set_register32(RegIdx::Esp as u8, (self.memory.len() & !0xf) as u32);
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::disk::Disk;
+
+    const LOAD_ADDR: u32 = 0x1000;
+
+    /// A minimal i386 executable: one PT_LOAD segment at 0x1000 holding
+    /// `code` followed by `bss` zero bytes.
+    fn elf(code: &[u8], bss: usize) -> Vec<u8> {
+        let mut data = vec![0u8; ELF_HEADER_SIZE + ELF_PHDR_SIZE];
+        data[0..4].copy_from_slice(&ELF_MAGIC);
+        data[4] = ELFCLASS32;
+        data[5] = ELFDATA2LSB;
+        data[6] = 1;
+        LittleEndian::write_u16(&mut data[16..], ET_EXEC);
+        LittleEndian::write_u16(&mut data[18..], EM_386);
+        LittleEndian::write_u32(&mut data[24..], LOAD_ADDR + (ELF_HEADER_SIZE + ELF_PHDR_SIZE) as u32);
+        LittleEndian::write_u32(&mut data[28..], ELF_HEADER_SIZE as u32);
+        LittleEndian::write_u16(&mut data[42..], ELF_PHDR_SIZE as u16);
+        LittleEndian::write_u16(&mut data[44..], 1);
+
+        let ph = &mut data[ELF_HEADER_SIZE..];
+        let filesz = (ELF_HEADER_SIZE + ELF_PHDR_SIZE + code.len()) as u32;
+        LittleEndian::write_u32(&mut ph[0..], PT_LOAD);
+        LittleEndian::write_u32(&mut ph[8..], LOAD_ADDR);
+        LittleEndian::write_u32(&mut ph[12..], LOAD_ADDR);
+        LittleEndian::write_u32(&mut ph[16..], filesz);
+        LittleEndian::write_u32(&mut ph[20..], filesz + bss as u32);
+        data.extend_from_slice(code);
+        data
+    }
+
+    #[test]
+    fn loads_segments_and_clears_bss() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let code_start = LOAD_ADDR as usize + ELF_HEADER_SIZE + ELF_PHDR_SIZE;
+        emu.memory[code_start..code_start + 0x20].iter_mut().for_each(|b| *b = 0xcc);
+
+        let image = emu.load_elf(&elf(&[0x90, 0xc3], 16)).unwrap();
+        assert_eq!(image.entry, code_start as u32);
+        assert_eq!(image.phdr, LOAD_ADDR + ELF_HEADER_SIZE as u32);
+        assert_eq!(image.phnum, 1);
+        assert_eq!(image.end, code_start as u32 + 2 + 16);
+        assert_eq!(&emu.memory[code_start..code_start + 2], &[0x90, 0xc3]);
+        assert!(emu.memory[code_start + 2..code_start + 18].iter().all(|&b| b == 0));
+        assert_eq!(emu.memory[code_start + 18], 0xcc);
+        assert_eq!(emu.eip, code_start as u32);
+        assert_eq!(emu.get_register32(RegIdx::Esp as u8), 0x10000);
+    }
+
+    #[test]
+    fn physical_load_uses_paddr() {
+        // linked to run at 0xc0001000 but loaded at 0x1000
+        let mut data = elf(&[0x90, 0xc3], 0);
+        let entry = LittleEndian::read_u32(&data[24..]);
+        LittleEndian::write_u32(&mut data[24..], entry + 0xc000_0000);
+        LittleEndian::write_u32(&mut data[ELF_HEADER_SIZE + 8..], LOAD_ADDR + 0xc000_0000);
+
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let image = emu.load_elf_physical(&data).unwrap();
+        assert_eq!(image.entry, entry);
+        assert_eq!(emu.eip, entry);
+        assert_eq!(&emu.memory[entry as usize..entry as usize + 2], &[0x90, 0xc3]);
+        assert!(matches!(load(&data), Err(LoadError::OutOfMemory(_))));
+    }
+
+    fn load(data: &[u8]) -> Result<ElfImage, LoadError> {
+        Emulator::new(0x10000, 0, 0).load_elf(data)
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(matches!(load(b"\x7fELF"), Err(LoadError::NotElf)));
+        assert!(matches!(load(&[0; 64]), Err(LoadError::NotElf)));
+
+        let mut data = elf(&[0xc3], 0);
+        data[4] = 2;
+        assert!(matches!(load(&data), Err(LoadError::UnsupportedElf(_))));
+
+        let mut data = elf(&[0xc3], 0);
+        LittleEndian::write_u16(&mut data[18..], 62);
+        assert!(matches!(load(&data), Err(LoadError::UnsupportedElf("not an i386 executable"))));
+
+        let mut data = elf(&[0xc3], 0);
+        LittleEndian::write_u16(&mut data[44..], 2);
+        assert!(matches!(load(&data), Err(LoadError::Truncated)));
+
+        let mut data = elf(&[0xc3], 0);
+        LittleEndian::write_u32(&mut data[ELF_HEADER_SIZE + 16..], 0x1000);
+        assert!(matches!(load(&data), Err(LoadError::UnsupportedElf(_))));
+
+        let data = elf(&[0xc3], 0x20000);
+        assert!(matches!(load(&data), Err(LoadError::OutOfMemory(LOAD_ADDR))));
+
+        let mut data = elf(&[0xc3], 0);
+        LittleEndian::write_u32(&mut data[ELF_HEADER_SIZE..], PT_PHDR);
+        assert!(matches!(load(&data), Err(LoadError::UnsupportedElf("no loadable segments"))));
+
+        let data = elf(&[0xc3], 0);
+        assert!(matches!(load(&data[..data.len() - 1]), Err(LoadError::Truncated)));
+    }
+
+    fn boot_disk(drive: u8, sector: &[u8], size: usize) -> Emulator {
+        let mut image = vec![0xf4; size];
+        image[..sector.len()].copy_from_slice(sector);
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        emu.attach_disk(Disk::new(drive, image));
+        emu
+    }
+
+    #[test]
+    fn boots_sector_zero() {
+        let mut sector = vec![0x90; SECTOR_SIZE];
+        sector[SECTOR_SIZE - 2..].copy_from_slice(&BOOT_SIGNATURE);
+        let mut emu = boot_disk(0x80, &sector, 2 * SECTOR_SIZE);
+        emu.registers.regs = [0xdead_beef; 8];
+        emu.boot_from_disk(0x80).unwrap();
+
+        let start = BOOT_ADDRESS as usize;
+        assert_eq!(&emu.memory[start..start + SECTOR_SIZE], &sector[..]);
+        assert_eq!(emu.memory[start + SECTOR_SIZE], 0);
+        assert_eq!(emu.get_register32(RegIdx::Edx as u8), 0x80);
+        assert_eq!(emu.get_register32(RegIdx::Eax as u8), 0);
+        assert_eq!(emu.get_register32(RegIdx::Esp as u8), BOOT_ADDRESS);
+        assert_eq!((emu.eip, emu.eflags), (BOOT_ADDRESS, 0x0202));
+    }
+
+    #[test]
+    fn boots_unsigned_sector_with_a_warning() {
+        let mut emu = boot_disk(0x00, &[0xeb, 0xfe], SECTOR_SIZE);
+        emu.boot_from_disk(0x00).unwrap();
+        assert_eq!(&emu.memory[BOOT_ADDRESS as usize..][..2], &[0xeb, 0xfe]);
+        assert_eq!(emu.get_register8(RegIdx::dl()), 0x00);
+    }
+
+    #[test]
+    fn refuses_missing_or_short_disks() {
+        let mut emu = boot_disk(0x80, &[], SECTOR_SIZE - 1);
+        assert!(matches!(emu.boot_from_disk(0x81), Err(LoadError::NoDisk(0x81))));
+        assert!(matches!(emu.boot_from_disk(0x80), Err(LoadError::NotBootable(0x80))));
+
+        let mut emu = Emulator::new(0x7d00, 0, 0);
+        emu.attach_disk(Disk::new(0x80, vec![0; SECTOR_SIZE]));
+        assert!(matches!(emu.boot_from_disk(0x80), Err(LoadError::OutOfMemory(BOOT_ADDRESS))));
+    }
+}
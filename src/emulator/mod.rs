@@ -1,11 +1,32 @@
+use std::cell::Cell;
 use std::fmt;
 extern crate byteorder;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 mod modrm;
 mod instructions;
 mod io_func;
 mod bios;
+pub mod vga;
+pub mod image;
+pub mod vbe;
+pub mod disk;
+pub mod keyboard;
+pub mod loader;
+pub mod chipset;
+pub mod linux;
+pub mod multiboot;
+pub mod hexfile;
+pub mod gdb;
+pub mod disasm;
+pub mod hooks;
+pub mod trace;
+pub mod snapshot;
+pub mod replay;
+pub mod reverse;
+pub mod debuginfo;
+pub mod coverage;
+
+pub use instructions::Instructions;
 
 #[derive(Copy, Debug, Default, Clone)]
 pub struct Regs32 {
@@ -15,7 +36,7 @@ pub struct Regs32 {
 impl Regs32 {
     pub fn new(regs: [u32; 8]) -> Regs32 {
         Regs32 {
-            regs: regs
+            regs
         }
     }
 }
@@ -36,6 +57,7 @@ impl fmt::Display for Regs32 {
     }
 }
 
+#[allow(dead_code)]
 enum RegIdx {
     Eax = 0,
     Ecx = 1,
@@ -47,6 +69,7 @@ enum RegIdx {
     Edi = 7,
 }
 
+#[allow(dead_code)]
 impl RegIdx {
     pub fn al() -> usize { Self::Eax as usize }
     pub fn cl() -> usize { Self::Ecx as usize }
@@ -65,55 +88,233 @@ enum Eflags {
     Overflow
 }
 
+/// Which operating environment `int` instructions are served by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Personality {
+    /// The built-in PC BIOS services.
+    #[default]
+    Bios,
+    /// i386 Linux system calls through `int 0x80` and `sysenter`.
+    Linux,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Emulator {
     pub registers: Regs32,
     pub eflags: u32,
     pub memory: Vec<u8>,
-    pub eip: u32
+    pub eip: u32,
+    pub vga: vga::Vga,
+    pub vbe: vbe::Vbe,
+    pub disks: Vec<disk::Disk>,
+    /// Status of the last INT 13h call, returned by AH=01h.
+    pub disk_status: u8,
+    pub keyboard: keyboard::Keyboard,
+    /// Set when the guest can make no further progress, e.g. it waits for a
+    /// key after the scripted input ran out.
+    pub halted: bool,
+    pub chipset: chipset::Chipset,
+    pub personality: Personality,
+    pub linux: linux::Linux,
+    pub hooks: hooks::Hooks,
+    /// Exit status once the guest has terminated itself.
+    pub exit_code: Option<i32>,
+    /// Instructions executed so far.
+    pub icount: u64,
+    pub input_log: replay::InputLog,
+    /// Set by an instruction handler that met a form it does not support.
+    unimplemented: Cell<bool>,
+    /// Added to memory operands while an instruction with a GS override
+    /// prefix runs.
+    segment_base: u32,
 }
 
 impl Emulator {
     pub fn new(size: usize, eip: u32, esp: u32) -> Emulator {
-        Emulator {
+        let mut emu = Emulator {
             registers: Regs32::new([0, 0, 0, 0, esp, 0, 0, 0]),
             eflags: 0,
             memory: vec![0; size],
-            eip: eip,
+            eip,
+            vga: vga::Vga::default(),
+            vbe: vbe::Vbe::new(),
+            disks: vec![],
+            disk_status: 0,
+            keyboard: keyboard::Keyboard::default(),
+            halted: false,
+            chipset: chipset::Chipset::new(),
+            personality: Personality::Bios,
+            linux: linux::Linux::default(),
+            hooks: hooks::Hooks::default(),
+            exit_code: None,
+            icount: 0,
+            input_log: replay::InputLog::default(),
+            unimplemented: Cell::new(false),
+            segment_base: 0,
+        };
+
+        if emu.has_text_buffer() {
+            emu.set_video_mode(vga::MODE_TEXT);
+            emu.vga.dirty = false;
+        }
+        emu.update_bios_data_area();
+        emu.update_cmos();
+        emu
+    }
+
+    /// Executes the instruction at EIP. Fails with the opcode if it is not
+    /// implemented, leaving EIP on it.
+    pub fn step(&mut self, instructions: &Instructions) -> Result<(), u8> {
+        self.hooks.eip = self.eip;
+        if !self.hooks.is_empty()
+            && !self.hooks.check(hooks::Access::Fetch, self.eip, self.read_phys8(self.eip)) {
+            // vetoed by a hook; `take_stop_reason` says so
+            return Ok(());
+        }
+        let icount = self.icount;
+        if self.input_log.enabled {
+            self.begin_logged_step();
+        }
+        let res = if self.hooks.has_code_hooks() {
+            self.step_hooked(|emu| emu.execute(instructions))
+        } else {
+            self.execute(instructions)
+        };
+        if res.is_ok() {
+            self.icount += 1;
+            if self.icount / bios::INSTRUCTIONS_PER_TICK != icount / bios::INSTRUCTIONS_PER_TICK {
+                self.advance_bios_ticks();
+            }
+        }
+        if self.input_log.enabled {
+            self.end_logged_step(icount);
+        }
+        res
+    }
+
+    fn execute(&mut self, instructions: &Instructions) -> Result<(), u8> {
+        let eip = self.eip;
+        let mut code = self.get_code8(0);
+        if code == 0x65 {
+            // GS is the only segment with a base: the Linux thread area
+            self.segment_base = self.linux.tls_base;
+            self.eip += 1;
+            code = self.get_code8(0);
+        }
+        let res = match instructions[code as usize] {
+            Some(inst) => {
+                inst(self);
+                if self.unimplemented.take() { Err(code) } else { Ok(()) }
+            },
+            None => Err(code),
+        };
+        self.segment_base = 0;
+        if res.is_err() {
+            self.eip = eip;
+        }
+        res
+    }
+
+    /// Reports an operand form or group member the handler does not
+    /// support. The instruction then fails like an unknown opcode instead
+    /// of exiting, so the caller still gets to repaint, save state or
+    /// answer a debugger.
+    pub(super) fn not_implemented(&self, what: fmt::Arguments) {
+        println!("not implemented: {}", what);
+        self.unimplemented.set(true);
+    }
+
+    /// True once the guest returned to address 0, halted or left memory.
+    pub fn finished(&self) -> bool {
+        self.eip == 0 || self.halted || (self.eip as usize) >= self.memory.len()
+    }
+
+    /// Reads a byte for a debugger, without panicking on unmapped addresses.
+    pub fn peek8(&self, addr: u32) -> Option<u8> {
+        if self.vbe.lfb_offset(addr).is_some() || (addr as usize) < self.memory.len() {
+            Some(self.read_phys8(addr))
+        } else {
+            None
+        }
+    }
+
+    /// Writes a byte for a debugger; false if nothing is mapped at `addr`.
+    pub fn poke8(&mut self, addr: u32, val: u8) -> bool {
+        if self.peek8(addr).is_none() {
+            return false;
+        }
+        self.write_phys8(addr, val);
+        true
+    }
+
+    /// Reads a byte of the physical address space, routing it to the VBE
+    /// framebuffer or RAM.
+    fn read_phys8(&self, addr: u32) -> u8 {
+        if let Some(offset) = self.vbe.lfb_offset(addr) {
+            return self.vbe.lfb[offset];
         }
+        self.memory[addr as usize]
+    }
+
+    fn write_phys8(&mut self, addr: u32, val: u8) {
+        if let Some(offset) = self.vbe.lfb_offset(addr) {
+            self.vbe.lfb[offset] = val;
+            return;
+        }
+        self.memory[addr as usize] = val;
+        if vga::Vga::is_text_address(addr) {
+            self.vga.dirty = true;
+        }
+    }
+
+    /// A guest access: like `read_phys8`, but seen by hooks and watchpoints.
+    fn load8(&self, addr: u32, kind: hooks::Access) -> u8 {
+        let val = self.read_phys8(addr);
+        if !self.hooks.is_empty() && !self.hooks.check(kind, addr, val) {
+            return 0xff;
+        }
+        val
+    }
+
+    fn load32(&self, addr: u32, kind: hooks::Access) -> u32 {
+        (0..4).fold(0, |acc, i| acc | (self.load8(addr.wrapping_add(i), kind) as u32) << (i * 8))
+    }
+
+    fn store8(&mut self, addr: u32, val: u8) {
+        if !self.hooks.is_empty() && !self.hooks.check(hooks::Access::Write, addr, val) {
+            return;
+        }
+        self.write_phys8(addr, val);
     }
 
     pub fn get_signed_code8(&self, idx: usize) -> i8 {
-        self.memory[self.eip as usize + idx] as i8
+        self.get_code8(idx) as i8
     }
 
+    /// Instruction bytes are not seen by hooks; `step` reports execution of
+    /// the instruction as a whole.
     pub fn get_code8(&self, idx: usize) -> u8 {
-        self.memory[self.eip as usize + idx]
+        self.read_phys8(self.eip.wrapping_add(idx as u32))
     }
 
     pub fn get_code32(&self, idx: usize) -> u32 {
-        let idx = idx + self.eip as usize;
-        let mut m = &self.memory[idx..idx+4];
-        m.read_u32::<LittleEndian>().unwrap()
+        (0..4).fold(0, |acc, i| acc | (self.get_code8(idx + i) as u32) << (i * 8))
     }
 
     pub fn get_signed_code32(&self, idx: usize) -> i32 {
-        let idx = idx + self.eip as usize;
-        let mut m = &self.memory[idx..idx+4];
-        m.read_u32::<LittleEndian>().unwrap() as i32
+        self.get_code32(idx) as i32
     }
 
     pub fn set_memory8(&mut self, addr: u32, val: u32) {
-        self.memory[addr as usize] = (val & 0xff) as u8;
+        self.store8(addr, (val & 0xff) as u8);
     }
 
     pub fn set_memory32(&mut self, addr: u32, val: u32) {
-        let mut valm = vec![];
-        valm.write_u32::<LittleEndian>(val).unwrap();
-        let idx = addr as usize;
-        self.memory.splice(idx..idx+4, valm);
+        for i in 0..4 {
+            self.store8(addr.wrapping_add(i), (val >> (i * 8)) as u8);
+        }
     }
-    
+
     pub fn get_register32(&self, idx: u8) -> u32 {
         self.registers.regs[idx as usize]
     }
@@ -122,17 +323,25 @@ impl Emulator {
         self.registers.regs[idx as usize] = val;
     }
 
+    pub fn get_register16(&self, idx: u8) -> u16 {
+        (self.registers.regs[idx as usize] & 0xffff) as u16
+    }
+
+    pub fn set_register16(&mut self, idx: u8, val: u16) {
+        let r = self.registers.regs[idx as usize] & 0xffff0000;
+        self.registers.regs[idx as usize] = r | (val as u32);
+    }
+
     pub fn get_memory8(&self, addr: u32) -> u32 {
-        self.memory[addr as usize] as u32
+        self.load8(addr, hooks::Access::Read) as u32
     }
 
     pub fn get_memory32(&self, addr: u32) -> u32 {
-        (&(self.memory[addr as usize..]))
-            .read_u32::<LittleEndian>().unwrap()
+        self.load32(addr, hooks::Access::Read)
     }
 
     pub fn push32(&mut self, val: u32) {
-        let addr = self.get_register32(4) - 4; // registers.regs[4] = ESP
+        let addr = self.get_register32(4).wrapping_sub(4); // registers.regs[4] = ESP
         self.set_register32(4, addr);
         self.set_memory32(addr, val);
     }
@@ -140,7 +349,7 @@ impl Emulator {
     pub fn pop32(&mut self) -> u32 {
         let addr = self.get_register32(4);
         let ret = self.get_memory32(addr);
-        self.set_register32(4, addr + 4);
+        self.set_register32(4, addr.wrapping_add(4));
         ret
     }
 
@@ -1,5 +1,4 @@
-use std::process;
-use super::add_i2u_32;
+use super::disasm::REGS32;
 
 #[repr(C)]
 pub union OpcodeOrRgndx {
@@ -33,28 +32,81 @@ impl ModRM {
     }
 }
 
-impl super::Emulator {
-    pub fn parse_modrm(&mut self, modrm: &mut ModRM) {
-
-        let code = self.get_code8(0);
-        modrm.modu = (code & 0xc0) >> 6;
-        modrm.op_reg = OpcodeOrRgndx { opcode: (code & 0x38) >> 3 };
-        modrm.rm = code & 0x07;
+impl ModRM {
+    /// Decodes a ModR/M byte and the SIB and displacement bytes following
+    /// it, fetching byte `i` of the encoding with `fetch(i)`. Returns how
+    /// many bytes it consumed. Shared by the executor and the disassembler.
+    pub fn decode<F: Fn(usize) -> u8>(&mut self, fetch: F) -> usize {
+        let code = fetch(0);
+        self.modu = (code & 0xc0) >> 6;
+        self.op_reg = OpcodeOrRgndx { opcode: (code & 0x38) >> 3 };
+        self.rm = code & 0x07;
+        let mut len = 1;
+
+        if self.modu != 3 && self.rm == 4 {
+            self.sib = fetch(len);
+            len += 1;
+        }
 
-        self.eip += 1;
+        let sib_disp32 = self.modu == 0 && self.rm == 4 && (self.sib & 0x07) == 5;
+        if (self.modu == 0 && self.rm == 5) || self.modu == 2 || sib_disp32 {
+            self.disp.disp32 = (0..4).fold(0, |acc, i| acc | (fetch(len + i) as u32) << (i * 8));
+            len += 4;
+        } else if self.modu == 1 {
+            self.disp.disp8 = fetch(len) as i8;
+            len += 1;
+        }
+        len
+    }
 
-        if modrm.modu != 3 && modrm.rm == 4 {
-            modrm.sib = self.get_code8(0);
-            self.eip += 1;
+    /// The r/m operand in Intel syntax, naming registers from `regs`.
+    pub fn rm_operand(&self, regs: &[&str; 8], size: &str) -> String {
+        if self.modu == 3 {
+            return regs[self.rm as usize].to_string();
+        }
+        let mut parts = vec![];
+        if self.rm == 4 {
+            let scale = 1 << (self.sib >> 6);
+            let index = (self.sib >> 3) & 0x07;
+            let base = self.sib & 0x07;
+            if !(base == 5 && self.modu == 0) {
+                parts.push(REGS32[base as usize].to_string());
+            }
+            if index != 4 {
+                parts.push(if scale == 1 {
+                    REGS32[index as usize].to_string()
+                } else {
+                    format!("{}*{}", REGS32[index as usize], scale)
+                });
+            }
+        } else if !(self.modu == 0 && self.rm == 5) {
+            parts.push(REGS32[self.rm as usize].to_string());
         }
 
-        if (modrm.modu == 0 && modrm.rm == 5) || modrm.modu == 2 {
-            modrm.disp.disp32 = self.get_signed_code32(0) as u32;
-            self.eip += 4;
-        } else if modrm.modu == 1 {
-            modrm.disp.disp8 = self.get_signed_code8(0);
-            self.eip += 1;
+        let mut addr = parts.join("+");
+        let disp = match self.modu {
+            1 => unsafe { self.disp.disp8 as i64 },
+            2 => unsafe { self.disp.disp32 as i32 as i64 },
+            _ if addr.is_empty() || (self.rm == 4 && (self.sib & 0x07) == 5) => unsafe {
+                self.disp.disp32 as i64
+            },
+            _ => 0,
+        };
+        if addr.is_empty() {
+            addr = format!("{:#x}", disp);
+        } else if disp < 0 {
+            addr = format!("{}-{:#x}", addr, -disp);
+        } else if disp > 0 {
+            addr = format!("{}+{:#x}", addr, disp);
         }
+        format!("{} ptr [{}]", size, addr)
+    }
+}
+
+impl super::Emulator {
+    pub fn parse_modrm(&mut self, modrm: &mut ModRM) {
+        let len = modrm.decode(|i| self.get_code8(i));
+        self.eip += len as u32;
     }
 
     pub fn set_rm32(&mut self, modrm: &ModRM, val: u32) {
@@ -96,32 +148,38 @@ impl super::Emulator {
         self.get_register32(unsafe { modrm.op_reg.reg_idx })
     }
 
+    /// Effective address of a memory operand, plus the segment base of a
+    /// segment override prefix.
     pub fn calc_memory_address(&self, modrm: &ModRM) -> u32 {
-        match modrm.modu {
-            0 => {
-                match modrm.rm {
-                    4 => {
-                        println!("not implemented ModRM mod = 0, rm = 4");
-                        process::exit(0);
-                    },
-                    5 => unsafe { modrm.disp.disp32 },
-                    _ => self.get_register32(modrm.rm)
-                }
-            },
-            1 => {
-                if modrm.rm == 4 {
-                        println!("not implemented ModRM mod = 1, rm = 4");
-                        process::exit(0);
-                } else {
-                    unsafe { add_i2u_32(self.get_register32(modrm.rm), 
-                                 modrm.disp.disp8 as i32) }
-                }
-            },
-            _ => {
-                println!("not implemented ModRM mod = 3");
-                process::exit(0);
-            }
-        }
+        let disp = match modrm.modu {
+            1 => unsafe { modrm.disp.disp8 as i32 as u32 },
+            2 => unsafe { modrm.disp.disp32 },
+            _ => 0,
+        };
+        let base = if modrm.rm == 4 {
+            self.sib_address(modrm)
+        } else if modrm.modu == 0 && modrm.rm == 5 {
+            unsafe { modrm.disp.disp32 }
+        } else {
+            self.get_register32(modrm.rm)
+        };
+        self.segment_base.wrapping_add(base).wrapping_add(disp)
+    }
+
+    /// Base plus scaled index of the SIB byte. An index of 4 means none,
+    /// and with mod 0 a base of 5 means a disp32 instead of EBP.
+    fn sib_address(&self, modrm: &ModRM) -> u32 {
+        let scale = modrm.sib >> 6;
+        let index = (modrm.sib >> 3) & 0x07;
+        let base = modrm.sib & 0x07;
+
+        let base = if base == 5 && modrm.modu == 0 {
+            unsafe { modrm.disp.disp32 }
+        } else {
+            self.get_register32(base)
+        };
+        let index = if index == 4 { 0 } else { self.get_register32(index) << scale };
+        base.wrapping_add(index)
     }
 
     pub fn get_r8(&mut self, modrm: &ModRM) -> u8 {
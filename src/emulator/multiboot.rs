@@ -0,0 +1,363 @@
+use byteorder::{LittleEndian, ByteOrder};
+use super::{Emulator, RegIdx, loader::LoadError};
+use super::vbe::{VBE_LFB_ADDRESS, VBE_DISPI_INDEX_XRES, VBE_DISPI_INDEX_YRES,
+                 VBE_DISPI_INDEX_BPP, VBE_DISPI_INDEX_ENABLE, VBE_DISPI_ENABLED,
+                 VBE_DISPI_LFB_ENABLED};
+
+pub const MULTIBOOT_HEADER_MAGIC: u32 = 0x1bad_b002;
+/// Left in EAX for the kernel to recognise a Multiboot loader.
+pub const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2bad_b002;
+/// The header must sit 32-bit aligned within the first 8 KiB of the file.
+const HEADER_SEARCH: usize = 8192;
+
+const FLAG_PAGE_ALIGN: u32 = 1 << 0;
+const FLAG_VIDEO_MODE: u32 = 1 << 2;
+const FLAG_AOUT_KLUDGE: u32 = 1 << 16;
+/// Bits 0-15 are requirements a loader must understand or refuse the kernel.
+const SUPPORTED_REQUIREMENTS: u32 = 0x0000_0003;
+
+const INFO_MEMORY: u32 = 1 << 0;
+const INFO_CMDLINE: u32 = 1 << 2;
+const INFO_MODS: u32 = 1 << 3;
+const INFO_MEM_MAP: u32 = 1 << 6;
+const INFO_LOADER_NAME: u32 = 1 << 9;
+const INFO_FRAMEBUFFER: u32 = 1 << 12;
+const INFO_SIZE: u32 = 116;
+const MODULE_ENTRY_SIZE: u32 = 16;
+const MMAP_ENTRY_SIZE: u32 = 24;
+const PAGE_SIZE: u32 = 0x1000;
+
+const LOADER_NAME: &str = "px86";
+
+/// `mode_type` values of the header's graphics fields.
+const MODE_LINEAR: u32 = 0;
+const MODE_EGA_TEXT: u32 = 1;
+/// `framebuffer_type` values of the information structure.
+const FRAMEBUFFER_RGB: u8 = 1;
+const FRAMEBUFFER_EGA_TEXT: u8 = 2;
+
+/// A boot module: file contents plus the command line handed to the kernel.
+#[derive(Debug, Default, Clone)]
+pub struct Module {
+    pub data: Vec<u8>,
+    pub cmdline: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    flags: u32,
+    offset: usize,
+    header_addr: u32,
+    load_addr: u32,
+    load_end_addr: u32,
+    bss_end_addr: u32,
+    entry_addr: u32,
+    mode_type: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+}
+
+/// The framebuffer handed to a kernel that set FLAG_VIDEO_MODE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Framebuffer {
+    addr: u32,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bpp: u8,
+    kind: u8,
+    /// Position and size of the red, green and blue fields for RGB.
+    colors: [u8; 6],
+}
+
+fn find_header(data: &[u8]) -> Option<Header> {
+    let end = data.len().min(HEADER_SEARCH);
+    (0..end.saturating_sub(11)).step_by(4).find_map(|offset| {
+        let word = |i: usize| data.get(offset + i * 4..offset + i * 4 + 4)
+            .map_or(0, LittleEndian::read_u32);
+        let (magic, flags, checksum) = (word(0), word(1), word(2));
+        if magic != MULTIBOOT_HEADER_MAGIC || magic.wrapping_add(flags).wrapping_add(checksum) != 0 {
+            return None;
+        }
+        Some(Header {
+            flags,
+            offset,
+            header_addr: word(3),
+            load_addr: word(4),
+            load_end_addr: word(5),
+            bss_end_addr: word(6),
+            entry_addr: word(7),
+            mode_type: word(8),
+            width: word(9),
+            height: word(10),
+            depth: word(11),
+        })
+    })
+}
+
+fn align_up(addr: u32, align: u32) -> u32 {
+    (addr + align - 1) & !(align - 1)
+}
+
+impl Emulator {
+    /// Loads a Multiboot (v1) kernel and its modules and enters it the way a
+    /// Multiboot loader does: EAX holds 0x2BADB002, EBX the address of the
+    /// Multiboot information structure and interrupts are disabled. The CPU
+    /// is flat 32-bit already, so no BIOS code runs beforehand.
+    pub fn load_multiboot(&mut self, data: &[u8], cmdline: &str, modules: &[Module])
+        -> Result<(), LoadError> {
+        let header = find_header(data)
+            .ok_or_else(|| LoadError::Format("no Multiboot header".to_string()))?;
+        if header.flags & 0xffff & !SUPPORTED_REQUIREMENTS & !FLAG_VIDEO_MODE != 0 {
+            return Err(LoadError::Format(
+                format!("unsupported Multiboot requirements {:#06x}", header.flags & 0xffff)));
+        }
+
+        let (entry, kernel_end) = if header.flags & FLAG_AOUT_KLUDGE != 0 {
+            self.load_multiboot_aout(data, &header)?
+        } else {
+            let image = self.load_elf_physical(data)?;
+            (image.entry, image.end)
+        };
+        let framebuffer = if header.flags & FLAG_VIDEO_MODE != 0 {
+            Some(self.set_multiboot_video(&header)?)
+        } else {
+            None
+        };
+
+        // modules, then the information structure and its strings, go above
+        // the kernel
+        let mut next = align_up(kernel_end, PAGE_SIZE);
+        let mut module_ranges = vec![];
+        for module in modules {
+            if header.flags & FLAG_PAGE_ALIGN != 0 {
+                next = align_up(next, PAGE_SIZE);
+            }
+            self.load_binary(next, &module.data)?;
+            module_ranges.push((next, next + module.data.len() as u32));
+            next = align_up(next + module.data.len() as u32, 4);
+        }
+
+        let info = align_up(next, 8);
+        let mods_addr = info + INFO_SIZE;
+        let mmap_addr = mods_addr + MODULE_ENTRY_SIZE * modules.len() as u32;
+        let map = self.memory_map();
+        let mut strings = mmap_addr + MMAP_ENTRY_SIZE * map.len() as u32;
+        if strings as usize >= self.memory.len() {
+            return Err(LoadError::OutOfMemory(info));
+        }
+        for i in (0..INFO_SIZE).step_by(4) {
+            self.set_memory32(info + i, 0);
+        }
+
+        let mut put_string = |emu: &mut Emulator, s: &str| -> Result<u32, LoadError> {
+            let addr = strings;
+            let mut bytes = s.as_bytes().to_vec();
+            bytes.push(0);
+            emu.load_binary(addr, &bytes)?;
+            strings += bytes.len() as u32;
+            Ok(addr)
+        };
+
+        for (i, (module, (start, end))) in modules.iter().zip(module_ranges).enumerate() {
+            let entry = mods_addr + i as u32 * MODULE_ENTRY_SIZE;
+            let string = put_string(self, &module.cmdline)?;
+            self.set_memory32(entry, start);
+            self.set_memory32(entry + 4, end);
+            self.set_memory32(entry + 8, string);
+            self.set_memory32(entry + 12, 0);
+        }
+
+        for (i, (base, len, kind)) in map.iter().enumerate() {
+            let entry = mmap_addr + i as u32 * MMAP_ENTRY_SIZE;
+            // `size` does not count itself
+            self.set_memory32(entry, MMAP_ENTRY_SIZE - 4);
+            self.set_memory32(entry + 4, *base as u32);
+            self.set_memory32(entry + 8, (*base >> 32) as u32);
+            self.set_memory32(entry + 12, *len as u32);
+            self.set_memory32(entry + 16, (*len >> 32) as u32);
+            self.set_memory32(entry + 20, *kind);
+        }
+
+        let cmdline_addr = put_string(self, cmdline)?;
+        let loader_name = put_string(self, LOADER_NAME)?;
+
+        let upper_kb = (self.memory.len() as u32).saturating_sub(0x10_0000) / 1024;
+        self.set_memory32(info, INFO_MEMORY | INFO_CMDLINE | INFO_MODS | INFO_MEM_MAP
+                          | INFO_LOADER_NAME);
+        self.set_memory32(info + 4, self.base_memory_kb() as u32);
+        self.set_memory32(info + 8, upper_kb);
+        self.set_memory32(info + 12, 0xffff_ffff);
+        self.set_memory32(info + 16, cmdline_addr);
+        self.set_memory32(info + 20, modules.len() as u32);
+        self.set_memory32(info + 24, mods_addr);
+        self.set_memory32(info + 44, MMAP_ENTRY_SIZE * map.len() as u32);
+        self.set_memory32(info + 48, mmap_addr);
+        self.set_memory32(info + 64, loader_name);
+        if let Some(fb) = framebuffer {
+            self.set_memory32(info, self.get_memory32(info) | INFO_FRAMEBUFFER);
+            self.set_memory32(info + 88, fb.addr);
+            self.set_memory32(info + 92, 0);
+            self.set_memory32(info + 96, fb.pitch);
+            self.set_memory32(info + 100, fb.width);
+            self.set_memory32(info + 104, fb.height);
+            self.set_memory8(info + 108, fb.bpp as u32);
+            self.set_memory8(info + 109, fb.kind as u32);
+            for (i, &b) in fb.colors.iter().enumerate() {
+                self.set_memory8(info + 110 + i as u32, b as u32);
+            }
+        }
+
+        self.registers.regs = [0; 8];
+        self.set_register32(RegIdx::Eax as u8, MULTIBOOT_BOOTLOADER_MAGIC);
+        self.set_register32(RegIdx::Ebx as u8, info);
+        self.set_register32(RegIdx::Esp as u8, (self.memory.len() & !0xf) as u32);
+        self.eflags = 0x0002;
+        self.eip = entry;
+        Ok(())
+    }
+
+    /// Sets up the display a kernel asked for with FLAG_VIDEO_MODE. The
+    /// header only states a preference, so a linear mode falls back to
+    /// 640x480 and to 32 bits per pixel for depths VBE does not offer as RGB.
+    fn set_multiboot_video(&mut self, header: &Header) -> Result<Framebuffer, LoadError> {
+        match header.mode_type {
+            MODE_EGA_TEXT => Ok(Framebuffer {
+                addr: 0xb8000,
+                pitch: 160,
+                width: 80,
+                height: 25,
+                bpp: 16,
+                kind: FRAMEBUFFER_EGA_TEXT,
+                colors: [0; 6],
+            }),
+            MODE_LINEAR => {
+                let width = if header.width == 0 { 640 } else { header.width };
+                let height = if header.height == 0 { 480 } else { header.height };
+                let depth = if [15, 16, 24, 32].contains(&header.depth) { header.depth } else { 32 };
+                for (index, val) in [(VBE_DISPI_INDEX_XRES, width.min(0xffff)),
+                                     (VBE_DISPI_INDEX_YRES, height.min(0xffff)),
+                                     (VBE_DISPI_INDEX_BPP, depth),
+                                     (VBE_DISPI_INDEX_ENABLE,
+                                      (VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED) as u32)] {
+                    self.vbe.index = index as u16;
+                    self.vbe.write_data(val as u16);
+                }
+
+                let width = self.vbe.regs[VBE_DISPI_INDEX_XRES] as u32;
+                let bpp = self.vbe.bpp() as u8;
+                let colors = match bpp {
+                    15 => [10, 5, 5, 5, 0, 5],
+                    16 => [11, 5, 5, 6, 0, 5],
+                    _ => [16, 8, 8, 8, 0, 8],
+                };
+                Ok(Framebuffer {
+                    addr: VBE_LFB_ADDRESS,
+                    pitch: width * (bpp as u32).div_ceil(8),
+                    width,
+                    height: self.vbe.regs[VBE_DISPI_INDEX_YRES] as u32,
+                    bpp,
+                    kind: FRAMEBUFFER_RGB,
+                    colors,
+                })
+            },
+            mode => Err(LoadError::Format(format!("unsupported Multiboot video mode type {}", mode))),
+        }
+    }
+
+    /// Loads a kernel by the address fields of its Multiboot header rather
+    /// than by its ELF program headers. Returns the entry point and the end
+    /// of the kernel image including BSS.
+    fn load_multiboot_aout(&mut self, data: &[u8], header: &Header)
+        -> Result<(u32, u32), LoadError> {
+        if header.load_addr > header.header_addr {
+            return Err(LoadError::Format("load_addr above header_addr".to_string()));
+        }
+        let file_start = header.offset
+            .checked_sub((header.header_addr - header.load_addr) as usize)
+            .ok_or(LoadError::Truncated)?;
+        let file_end = if header.load_end_addr == 0 {
+            data.len()
+        } else {
+            file_start + header.load_end_addr.saturating_sub(header.load_addr) as usize
+        };
+        let image = data.get(file_start..file_end).ok_or(LoadError::Truncated)?;
+        self.load_binary(header.load_addr, image)?;
+
+        let load_end = header.load_addr + image.len() as u32;
+        let bss_end = header.bss_end_addr.max(load_end);
+        if bss_end as usize > self.memory.len() {
+            return Err(LoadError::OutOfMemory(load_end));
+        }
+        for b in &mut self.memory[load_end as usize..bss_end as usize] {
+            *b = 0;
+        }
+        Ok((header.entry_addr, bss_end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOAD_ADDR: u32 = 0x10_0000;
+
+    /// A kernel using the a.out kludge, with `flags` and the graphics fields.
+    fn kernel(flags: u32, video: [u32; 4]) -> Vec<u8> {
+        let flags = flags | FLAG_AOUT_KLUDGE;
+        let mut words = vec![MULTIBOOT_HEADER_MAGIC, flags,
+                             0u32.wrapping_sub(MULTIBOOT_HEADER_MAGIC).wrapping_sub(flags),
+                             LOAD_ADDR, LOAD_ADDR, 0, 0, LOAD_ADDR + 48];
+        words.extend_from_slice(&video);
+        let mut data: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        data.push(0xf4);
+        data
+    }
+
+    fn info(emu: &Emulator) -> u32 {
+        emu.get_register32(RegIdx::Ebx as u8)
+    }
+
+    #[test]
+    fn linear_framebuffer() {
+        let mut emu = Emulator::new(0x20_0000, 0, 0);
+        let video = [MODE_LINEAR, 800, 600, 32];
+        emu.load_multiboot(&kernel(FLAG_VIDEO_MODE, video), "k", &[]).unwrap();
+
+        let info = info(&emu);
+        assert_ne!(emu.get_memory32(info) & INFO_FRAMEBUFFER, 0);
+        assert_eq!(emu.get_memory32(info + 88), VBE_LFB_ADDRESS);
+        assert_eq!(emu.get_memory32(info + 96), 800 * 4);
+        assert_eq!(emu.get_memory32(info + 100), 800);
+        assert_eq!(emu.get_memory32(info + 104), 600);
+        assert_eq!(emu.get_memory8(info + 108), 32);
+        assert_eq!(emu.get_memory8(info + 109), FRAMEBUFFER_RGB as u32);
+        assert_eq!(emu.get_memory8(info + 110), 16);
+        assert!(emu.vbe.enabled());
+        assert_eq!(emu.eip, LOAD_ADDR + 48);
+    }
+
+    #[test]
+    fn text_framebuffer() {
+        let mut emu = Emulator::new(0x20_0000, 0, 0);
+        emu.load_multiboot(&kernel(FLAG_VIDEO_MODE, [MODE_EGA_TEXT, 0, 0, 0]), "k", &[]).unwrap();
+
+        let info = info(&emu);
+        assert_eq!(emu.get_memory32(info + 88), 0xb8000);
+        assert_eq!(emu.get_memory32(info + 100), 80);
+        assert_eq!(emu.get_memory8(info + 109), FRAMEBUFFER_EGA_TEXT as u32);
+        assert!(!emu.vbe.enabled());
+    }
+
+    #[test]
+    fn video_flags() {
+        let mut emu = Emulator::new(0x20_0000, 0, 0);
+        emu.load_multiboot(&kernel(0, [0; 4]), "k", &[]).unwrap();
+        assert_eq!(emu.get_memory32(info(&emu)) & INFO_FRAMEBUFFER, 0);
+
+        let mut emu = Emulator::new(0x20_0000, 0, 0);
+        assert!(matches!(emu.load_multiboot(&kernel(FLAG_VIDEO_MODE, [2, 0, 0, 0]), "k", &[]),
+                         Err(LoadError::Format(_))));
+    }
+}
@@ -0,0 +1,419 @@
+//! The log of nondeterministic inputs: everything the host hands to the
+//! guest (serial and keyboard bytes, the clock, console and file reads),
+//! tagged with the number of the instruction that received it.
+//!
+//! While the log is enabled every instruction up to the `horizon` has run
+//! once for real. Re-executing any of them, e.g. after restoring an earlier
+//! checkpoint or when replaying a saved log, takes the inputs from the log
+//! instead of the host, so the guest sees exactly the same run again.
+//! Output to the host is only skipped for instructions this process has
+//! already run. There are no asynchronous interrupts; every input belongs
+//! to the instruction that asked for it.
+//!
+//! Saved logs are the magic `PX86RPL\0` and a u32 version, the u64
+//! instruction counts where the recording started and ended, the u64
+//! FNV-1a hash of the machine state it started from, then per input, all
+//! little-endian: u64 instruction count, u8 kind, u32 length and the data.
+//! Version 1 logs have no hash.
+
+use std::io::{self, Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::{Emulator, bios::host_seconds, loader::LoadError};
+
+pub const LOG_MAGIC: &[u8; 8] = b"PX86RPL\0";
+pub const LOG_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    /// A byte read from the serial port.
+    Serial,
+    /// Bytes the host terminal delivered to the keyboard.
+    Keyboard,
+    /// The host terminal reached the end of its input.
+    KeyboardEof,
+    /// Host time in seconds since the epoch, as a little-endian u64.
+    Clock,
+    /// The result of a console or file read: 0 and the data, or 1 and the
+    /// OS error code as a little-endian i32.
+    Read,
+}
+
+impl InputKind {
+    const ALL: [InputKind; 5] = [
+        InputKind::Serial, InputKind::Keyboard, InputKind::KeyboardEof,
+        InputKind::Clock, InputKind::Read,
+    ];
+
+    /// Keyboard input is delivered before its instruction runs rather than
+    /// asked for by it.
+    fn is_keyboard(self) -> bool {
+        self == InputKind::Keyboard || self == InputKind::KeyboardEof
+    }
+
+    /// Whether `data` is something this kind of input can be.
+    fn is_valid(self, data: &[u8]) -> bool {
+        match self {
+            InputKind::Serial => data.len() == 1,
+            InputKind::Keyboard => true,
+            InputKind::KeyboardEof => data.is_empty(),
+            InputKind::Clock => data.len() == 8,
+            InputKind::Read => match data.first() {
+                Some(0) => true,
+                Some(1) => data.len() == 5,
+                _ => false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input {
+    /// Instruction count when the input arrived.
+    pub icount: u64,
+    pub kind: InputKind,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct InputLog {
+    pub enabled: bool,
+    pub inputs: Vec<Input>,
+    /// Instruction count when recording started.
+    pub start: u64,
+    /// `Emulator::state_hash` when recording started; unknown for logs
+    /// from version 1.
+    pub state_hash: Option<u64>,
+    /// Instructions whose inputs are known; running one below this replays.
+    pub horizon: u64,
+    /// Instructions this process has executed; running one below this
+    /// again skips output to the host.
+    pub executed: u64,
+    /// Next input to replay.
+    cursor: usize,
+}
+
+impl InputLog {
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(LOG_MAGIC)?;
+        out.write_u32::<LittleEndian>(LOG_VERSION)?;
+        out.write_u64::<LittleEndian>(self.start)?;
+        out.write_u64::<LittleEndian>(self.horizon)?;
+        out.write_u64::<LittleEndian>(self.state_hash.unwrap_or(0))?;
+        for input in &self.inputs {
+            out.write_u64::<LittleEndian>(input.icount)?;
+            out.write_u8(InputKind::ALL.iter().position(|&k| k == input.kind).unwrap() as u8)?;
+            out.write_u32::<LittleEndian>(input.data.len() as u32)?;
+            out.write_all(&input.data)?;
+        }
+        out.flush()
+    }
+
+    /// Reads a log written by `save`, ready for `Emulator::replay`.
+    pub fn load(input: &mut dyn Read) -> Result<InputLog, LoadError> {
+        let truncated = || LoadError::Format("truncated input log".to_string());
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic).map_err(|_| truncated())?;
+        if &magic != LOG_MAGIC {
+            return Err(LoadError::Format("not an input log".to_string()));
+        }
+        let version = input.read_u32::<LittleEndian>().map_err(|_| truncated())?;
+        if version > LOG_VERSION {
+            return Err(LoadError::Format(format!("unsupported input log version {}", version)));
+        }
+        let start = input.read_u64::<LittleEndian>().map_err(|_| truncated())?;
+        let horizon = input.read_u64::<LittleEndian>().map_err(|_| truncated())?;
+        let state_hash = if version >= 2 {
+            Some(input.read_u64::<LittleEndian>().map_err(|_| truncated())?)
+        } else {
+            None
+        };
+
+        let mut data = vec![];
+        input.read_to_end(&mut data)?;
+        let mut rest = &data[..];
+        let mut inputs = vec![];
+        while !rest.is_empty() {
+            let icount = rest.read_u64::<LittleEndian>().map_err(|_| truncated())?;
+            let kind = *InputKind::ALL.get(rest.read_u8().map_err(|_| truncated())? as usize)
+                .ok_or_else(|| LoadError::Format("unknown input kind".to_string()))?;
+            let len = rest.read_u32::<LittleEndian>().map_err(|_| truncated())? as usize;
+            if len > rest.len() {
+                return Err(truncated());
+            }
+            let (data, tail) = rest.split_at(len);
+            if !kind.is_valid(data) {
+                return Err(LoadError::Format(format!("malformed {:?} input at instruction {}",
+                                                     kind, icount)));
+            }
+            inputs.push(Input { icount, kind, data: data.to_vec() });
+            rest = tail;
+        }
+        Ok(InputLog { enabled: true, inputs, start, state_hash, horizon, executed: start, cursor: 0 })
+    }
+
+    /// Positions the log for re-executing from instruction `icount`.
+    pub fn rewind(&mut self, icount: u64) {
+        self.cursor = self.inputs.partition_point(|i| i.icount < icount);
+    }
+}
+
+impl Emulator {
+    /// True while the current instruction takes its inputs from the log.
+    pub fn replaying(&self) -> bool {
+        self.icount < self.input_log.horizon
+    }
+
+    /// True while re-running an instruction that already ran in this
+    /// process, when output to the host is skipped.
+    pub fn repeating(&self) -> bool {
+        self.icount < self.input_log.executed
+    }
+
+    /// FNV-1a hash of the memory, the registers and EIP, which a replayed
+    /// log must start from.
+    pub fn state_hash(&self) -> u64 {
+        let regs = self.registers.regs.iter().flat_map(|r| r.to_le_bytes());
+        self.memory.iter().copied().chain(regs).chain(self.eip.to_le_bytes())
+            .fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+    }
+
+    /// Starts logging inputs from the current instruction on.
+    pub fn start_recording(&mut self) {
+        if self.input_log.enabled {
+            return;
+        }
+        let icount = self.icount;
+        self.input_log = InputLog { enabled: true, start: icount, state_hash: Some(self.state_hash()),
+                                    horizon: icount, executed: icount, ..Default::default() };
+    }
+
+    /// Replays a saved log, which must start at the current instruction and
+    /// machine state.
+    pub fn replay(&mut self, mut log: InputLog) -> Result<(), LoadError> {
+        if log.start != self.icount {
+            return Err(LoadError::Format(format!("input log starts at instruction {}, not {}",
+                                                 log.start, self.icount)));
+        }
+        if log.state_hash.is_some_and(|hash| hash != self.state_hash()) {
+            return Err(LoadError::Format("input log was recorded from a different machine state"
+                                         .to_string()));
+        }
+        log.enabled = true;
+        log.executed = self.icount;
+        log.rewind(self.icount);
+        self.input_log = log;
+        Ok(())
+    }
+
+    /// Gets an input from the host with `read`, or from the log when
+    /// replaying. Falls back to the host if the guest asks for something
+    /// the log does not have, and records from there on.
+    pub(crate) fn host_input<F>(&mut self, kind: InputKind, read: F) -> Vec<u8>
+        where F: FnOnce(&mut Emulator) -> Vec<u8> {
+        if !self.input_log.enabled {
+            return read(self);
+        }
+        if self.replaying() {
+            let icount = self.icount;
+            let log = &mut self.input_log;
+            while log.inputs.get(log.cursor).is_some_and(|i| i.icount == icount && i.kind.is_keyboard()) {
+                log.cursor += 1;
+            }
+            match log.inputs.get(log.cursor) {
+                Some(input) if input.icount == icount && input.kind == kind => {
+                    log.cursor += 1;
+                    return input.data.clone();
+                },
+                _ => {
+                    println!("input log diverged at instruction {}", icount);
+                    log.inputs.truncate(log.cursor);
+                    log.horizon = icount;
+                    self.keyboard.replaying = false;
+                }
+            }
+        }
+
+        let data = read(self);
+        self.input_log.inputs.push(Input { icount: self.icount, kind, data: data.clone() });
+        self.input_log.cursor = self.input_log.inputs.len();
+        data
+    }
+
+    /// `host_input` for reads that can fail.
+    pub(crate) fn host_read<F>(&mut self, read: F) -> io::Result<Vec<u8>>
+        where F: FnOnce(&mut Emulator) -> io::Result<Vec<u8>> {
+        let data = self.host_input(InputKind::Read, |emu| match read(emu) {
+            Ok(mut data) => {
+                data.insert(0, 0);
+                data
+            },
+            Err(e) => {
+                let mut data = vec![1];
+                data.extend_from_slice(&e.raw_os_error().unwrap_or(0).to_le_bytes());
+                data
+            }
+        });
+        match data.split_first() {
+            Some((0, data)) => Ok(data.to_vec()),
+            Some((_, code)) if code.len() == 4 => {
+                Err(io::Error::from_raw_os_error(i32::from_le_bytes([code[0], code[1], code[2], code[3]])))
+            },
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    /// Host time in seconds since the epoch.
+    pub(crate) fn clock(&mut self) -> u64 {
+        let data = self.host_input(InputKind::Clock, |_| host_seconds().to_le_bytes().to_vec());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[..8.min(data.len())]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Called by `step` before each instruction while the log is enabled.
+    pub(super) fn begin_logged_step(&mut self) {
+        let replaying = self.replaying();
+        self.keyboard.replaying = replaying;
+        self.keyboard.record_host = !replaying;
+        if !replaying {
+            return;
+        }
+        let icount = self.icount;
+        let log = &self.input_log;
+        let inputs = log.inputs[log.cursor..].iter()
+            .take_while(|i| i.icount == icount)
+            .filter(|i| i.kind.is_keyboard());
+        for input in inputs {
+            self.keyboard.feed_host_input(&input.data, input.kind == InputKind::KeyboardEof);
+        }
+    }
+
+    /// Called by `step` after an instruction ran while the log is enabled;
+    /// `icount` is the number it ran as.
+    pub(super) fn end_logged_step(&mut self, icount: u64) {
+        if self.keyboard.replaying {
+            let log = &mut self.input_log;
+            log.cursor += log.inputs[log.cursor..].iter().take_while(|i| i.icount <= icount).count();
+            log.executed = log.executed.max(self.icount);
+            return;
+        }
+        let (bytes, eof) = self.keyboard.take_host_input();
+        if !bytes.is_empty() {
+            self.input_log.inputs.push(Input { icount, kind: InputKind::Keyboard, data: bytes });
+        }
+        if eof {
+            self.input_log.inputs.push(Input { icount, kind: InputKind::KeyboardEof, data: vec![] });
+        }
+        self.input_log.cursor = self.input_log.inputs.len();
+        self.input_log.horizon = self.input_log.horizon.max(self.icount);
+        self.input_log.executed = self.input_log.executed.max(self.icount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(0x1000, 0x100, 0xff0);
+        emu.memory[0x100] = 0xf4;
+        emu
+    }
+
+    fn recorded() -> InputLog {
+        let mut emu = emulator();
+        emu.start_recording();
+        emu.input_log.inputs = vec![
+            Input { icount: 0, kind: InputKind::Clock, data: 1234u64.to_le_bytes().to_vec() },
+            Input { icount: 0, kind: InputKind::Serial, data: vec![b'x'] },
+            Input { icount: 1, kind: InputKind::Keyboard, data: b"ab".to_vec() },
+            Input { icount: 1, kind: InputKind::KeyboardEof, data: vec![] },
+            Input { icount: 2, kind: InputKind::Read, data: vec![0, b'h', b'i'] },
+            Input { icount: 2, kind: InputKind::Read, data: vec![1, 2, 0, 0, 0] },
+        ];
+        emu.input_log.horizon = 3;
+        emu.input_log
+    }
+
+    fn saved(log: &InputLog) -> Vec<u8> {
+        let mut out = vec![];
+        log.save(&mut out).unwrap();
+        out
+    }
+
+    fn load(data: &[u8]) -> Result<InputLog, LoadError> {
+        InputLog::load(&mut &data[..])
+    }
+
+    #[test]
+    fn round_trip() {
+        let log = recorded();
+        let loaded = load(&saved(&log)).unwrap();
+        assert_eq!(loaded.inputs, log.inputs);
+        assert_eq!((loaded.start, loaded.horizon), (0, 3));
+        assert_eq!(loaded.state_hash, Some(emulator().state_hash()));
+    }
+
+    #[test]
+    fn malformed_logs() {
+        let good = saved(&recorded());
+        assert!(load(b"PX86SNAP\x02\0\0\0").is_err());
+        let mut newer = good.clone();
+        newer[8] = 3;
+        assert!(load(&newer).is_err());
+        // cut inside the header and inside the last input
+        assert!(load(&good[..20]).is_err());
+        assert!(load(&good[..good.len() - 1]).is_err());
+
+        let header = &good[..36];
+        let entry = |kind: u8, data: &[u8]| {
+            let mut log = header.to_vec();
+            log.write_u64::<LittleEndian>(0).unwrap();
+            log.write_u8(kind).unwrap();
+            log.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            log.extend_from_slice(data);
+            load(&log)
+        };
+        assert!(entry(0, b"x").is_ok());
+        assert!(entry(0, b"xy").is_err());
+        assert!(entry(2, b"x").is_err());
+        assert!(entry(3, &[0; 4]).is_err());
+        assert!(entry(4, &[]).is_err());
+        assert!(entry(4, &[1, 2]).is_err());
+        assert!(entry(4, &[2]).is_err());
+        assert!(entry(5, &[]).is_err());
+    }
+
+    #[test]
+    fn version_1_logs_have_no_hash() {
+        let mut log = saved(&recorded());
+        log[8] = 1;
+        log.drain(28..36);
+        let loaded = load(&log).unwrap();
+        assert_eq!(loaded.state_hash, None);
+        assert!(emulator().replay(loaded).is_ok());
+    }
+
+    #[test]
+    fn replays_recorded_inputs() {
+        let mut emu = emulator();
+        emu.replay(load(&saved(&recorded())).unwrap()).unwrap();
+        assert!(emu.replaying());
+        assert_eq!(emu.clock(), 1234);
+        assert_eq!(emu.host_input(InputKind::Serial, |_| panic!("read the host")), b"x");
+    }
+
+    #[test]
+    fn replay_checks_the_start() {
+        let log = load(&saved(&recorded())).unwrap();
+        let mut changed = emulator();
+        changed.memory[0x200] = 1;
+        assert!(changed.replay(log.clone()).is_err());
+        let mut moved = emulator();
+        moved.eip = 0x104;
+        assert!(moved.replay(log.clone()).is_err());
+        let mut later = emulator();
+        later.icount = 5;
+        assert!(later.replay(log).is_err());
+    }
+}
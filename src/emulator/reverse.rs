@@ -0,0 +1,249 @@
+//! Reverse execution. A `History` keeps periodic checkpoints of the machine
+//! while the input log records what the host provided; going back to an
+//! earlier instruction restores the closest checkpoint before it and
+//! replays forward from there.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+use super::{Emulator, Instructions, hooks::StopReason};
+
+const PAGE_SIZE: usize = 4096;
+/// When there are more checkpoints, every other one is dropped and the
+/// interval doubles, so the whole run stays reachable in bounded memory.
+const MAX_CHECKPOINTS: usize = 64;
+
+/// A buffer split into pages that are shared with the previous checkpoint
+/// when they did not change.
+struct Pages {
+    pages: Vec<Arc<[u8]>>,
+}
+
+impl Pages {
+    fn capture(data: &[u8], prev: Option<&Pages>, zero: &Arc<[u8]>) -> Pages {
+        let pages = data.chunks(PAGE_SIZE).enumerate().map(|(i, chunk)| {
+            match prev.and_then(|p| p.pages.get(i)) {
+                Some(page) if page[..] == *chunk => page.clone(),
+                _ if zero[..] == *chunk => zero.clone(),
+                _ => Arc::from(chunk),
+            }
+        }).collect();
+        Pages { pages }
+    }
+
+    fn restore(&self, data: &mut Vec<u8>) {
+        data.clear();
+        for page in &self.pages {
+            data.extend_from_slice(page);
+        }
+    }
+}
+
+struct Checkpoint {
+    /// The machine without its memory, framebuffer and disk images, which
+    /// are kept as pages.
+    emu: Emulator,
+    memory: Pages,
+    lfb: Pages,
+    disks: Vec<Pages>,
+}
+
+/// Where a reverse continue stopped.
+#[derive(Debug, Clone, Copy)]
+pub enum ReverseStop {
+    Breakpoint(u32),
+    Watch(StopReason),
+    /// Nothing was hit before the start of the recorded history.
+    Start,
+}
+
+impl fmt::Display for ReverseStop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReverseStop::Breakpoint(addr) => write!(f, "breakpoint at {:#010x}", addr),
+            ReverseStop::Watch(reason) => write!(f, "{}", reason),
+            ReverseStop::Start => write!(f, "start of history"),
+        }
+    }
+}
+
+pub struct History {
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    zero: Arc<[u8]>,
+}
+
+impl fmt::Debug for History {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("History")
+            .field("checkpoints", &self.checkpoints.len())
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+impl History {
+    /// Records a checkpoint about every `interval` instructions.
+    pub fn new(interval: u64) -> History {
+        History {
+            checkpoints: vec![],
+            interval: interval.max(1),
+            zero: Arc::from(&[0u8; PAGE_SIZE][..]),
+        }
+    }
+
+    /// Instruction count of the oldest point that can be returned to.
+    pub fn start(&self) -> Option<u64> {
+        self.checkpoints.first().map(|c| c.emu.icount)
+    }
+
+    /// Call before every instruction: enables the input log and takes a
+    /// checkpoint when one is due.
+    pub fn record(&mut self, emu: &mut Emulator) {
+        emu.start_recording();
+        // a replay that diverged cut the log short
+        let horizon = emu.input_log.horizon.max(emu.icount);
+        while self.checkpoints.last().is_some_and(|c| c.emu.icount > horizon) {
+            self.checkpoints.pop();
+        }
+        let due = match self.checkpoints.last() {
+            Some(last) => emu.icount >= last.emu.icount + self.interval,
+            None => true,
+        };
+        if !due {
+            return;
+        }
+
+        let prev = self.checkpoints.last();
+        let memory = Pages::capture(&emu.memory, prev.map(|c| &c.memory), &self.zero);
+        let lfb = Pages::capture(&emu.vbe.lfb, prev.map(|c| &c.lfb), &self.zero);
+        let disks = emu.disks.iter().enumerate()
+            .map(|(i, d)| Pages::capture(&d.image, prev.and_then(|c| c.disks.get(i)), &self.zero))
+            .collect();
+
+        let memory_buf = mem::take(&mut emu.memory);
+        let lfb_buf = mem::take(&mut emu.vbe.lfb);
+        let images: Vec<Vec<u8>> = emu.disks.iter_mut().map(|d| mem::take(&mut d.image)).collect();
+        let hooks = mem::take(&mut emu.hooks);
+        let log = mem::take(&mut emu.input_log);
+        let copy = emu.clone();
+        emu.memory = memory_buf;
+        emu.vbe.lfb = lfb_buf;
+        for (d, image) in emu.disks.iter_mut().zip(images) {
+            d.image = image;
+        }
+        emu.hooks = hooks;
+        emu.input_log = log;
+
+        self.checkpoints.push(Checkpoint { emu: copy, memory, lfb, disks });
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            let mut keep = false;
+            self.checkpoints.retain(|_| {
+                keep = !keep;
+                keep
+            });
+            self.interval *= 2;
+        }
+    }
+
+    /// Replaces the machine with checkpoint `idx`, keeping the hooks, the
+    /// input log and the host terminal of the running one.
+    fn restore(&self, idx: usize, emu: &mut Emulator) {
+        let cp = &self.checkpoints[idx];
+        let hooks = mem::take(&mut emu.hooks);
+        let log = mem::take(&mut emu.input_log);
+        let mut memory = mem::take(&mut emu.memory);
+        let mut keyboard = mem::take(&mut emu.keyboard);
+
+        *emu = cp.emu.clone();
+        cp.memory.restore(&mut memory);
+        emu.memory = memory;
+        cp.lfb.restore(&mut emu.vbe.lfb);
+        for (d, pages) in emu.disks.iter_mut().zip(&cp.disks) {
+            pages.restore(&mut d.image);
+        }
+        emu.keyboard.adopt_host(&mut keyboard);
+        emu.hooks = hooks;
+        emu.input_log = log;
+        emu.input_log.rewind(emu.icount);
+        emu.take_stop_reason();
+    }
+
+    /// Returns the machine to the point before instruction `target` ran.
+    /// False if that is outside the recorded history.
+    pub fn seek(&self, emu: &mut Emulator, instructions: &Instructions, target: u64) -> bool {
+        if target > emu.input_log.horizon.max(emu.icount) {
+            return false;
+        }
+        let idx = match self.checkpoints.iter().rposition(|c| c.emu.icount <= target) {
+            Some(idx) => idx,
+            None => { return false; }
+        };
+        self.restore(idx, emu);
+        let suspended = mem::replace(&mut emu.hooks.suspended, true);
+        let mut reached = true;
+        while emu.icount < target {
+            let icount = emu.icount;
+            if emu.step(instructions).is_err() || emu.icount == icount {
+                reached = false;
+                break;
+            }
+            emu.take_stop_reason();
+        }
+        emu.hooks.suspended = suspended;
+        reached
+    }
+
+    /// Undoes the last instruction. False at the start of the history.
+    pub fn step_back(&self, emu: &mut Emulator, instructions: &Instructions) -> bool {
+        emu.icount > 0 && self.seek(emu, instructions, emu.icount - 1)
+    }
+
+    /// Runs backwards to the latest earlier point where a breakpoint would
+    /// stop the guest or a watchpoint was hit.
+    pub fn reverse_continue(&self, emu: &mut Emulator, instructions: &Instructions,
+                            breakpoints: &BTreeSet<u32>) -> ReverseStop {
+        let suspended = mem::replace(&mut emu.hooks.suspended, true);
+        let stop = self.find_reverse_stop(emu, instructions, breakpoints);
+        emu.hooks.suspended = suspended;
+        stop
+    }
+
+    fn find_reverse_stop(&self, emu: &mut Emulator, instructions: &Instructions,
+                         breakpoints: &BTreeSet<u32>) -> ReverseStop {
+        let current = emu.icount;
+        for idx in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[idx].emu.icount;
+            if start >= current {
+                continue;
+            }
+            let end = self.checkpoints.get(idx + 1).map_or(current, |c| c.emu.icount.min(current));
+
+            self.restore(idx, emu);
+            let mut hit = None;
+            while emu.icount < end {
+                if breakpoints.contains(&emu.eip) {
+                    hit = Some((emu.icount, ReverseStop::Breakpoint(emu.eip)));
+                }
+                let icount = emu.icount;
+                if emu.step(instructions).is_err() || emu.icount == icount {
+                    break;
+                }
+                if let Some(reason) = emu.take_stop_reason() {
+                    if emu.icount < current {
+                        hit = Some((emu.icount, ReverseStop::Watch(reason)));
+                    }
+                }
+            }
+            if let Some((target, stop)) = hit {
+                self.seek(emu, instructions, target);
+                return stop;
+            }
+        }
+        if let Some(start) = self.start() {
+            self.seek(emu, instructions, start);
+        }
+        ReverseStop::Start
+    }
+}
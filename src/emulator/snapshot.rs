@@ -0,0 +1,490 @@
+//! Whole-machine snapshots.
+//!
+//! A snapshot is the magic `PX86SNAP` and a u32 version, followed by
+//! sections of a four-byte tag, a u32 length and the payload, all
+//! little-endian. Unknown sections are skipped, so later versions can add
+//! devices without breaking older snapshots. Memory-like buffers are stored
+//! sparsely as their size plus the 4 KiB pages that are not all zero.
+//!
+//! Host resources are not part of the machine: files the guest opened are
+//! closed on restore, disks are restored as in-memory images without
+//! write-through, and hooks, watchpoints and the host terminal are left as
+//! they are in the restoring emulator.
+
+use std::fmt;
+use std::error;
+use std::io::{self, Cursor, Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::{Emulator, Personality, disk::Disk, linux::LinuxFile};
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"PX86SNAP";
+pub const SNAPSHOT_VERSION: u32 = 2;
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    /// Written by a newer version of the emulator.
+    UnsupportedVersion(u32),
+    /// A section is truncated or inconsistent.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::Corrupt(section) => write!(f, "corrupt {} section", section),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        SnapshotError::Io(e)
+    }
+}
+
+fn write_sparse(out: &mut Vec<u8>, data: &[u8]) {
+    out.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+    for (i, page) in data.chunks(PAGE_SIZE).enumerate() {
+        if page.iter().any(|&b| b != 0) {
+            out.write_u32::<LittleEndian>(i as u32).unwrap();
+            out.write_u32::<LittleEndian>(page.len() as u32).unwrap();
+            out.extend_from_slice(page);
+        }
+    }
+    out.write_u32::<LittleEndian>(u32::MAX).unwrap();
+}
+
+fn remaining(input: &Cursor<&[u8]>) -> usize {
+    input.get_ref().len().saturating_sub(input.position() as usize)
+}
+
+fn read_sparse(input: &mut Cursor<&[u8]>) -> io::Result<Vec<u8>> {
+    let size = input.read_u32::<LittleEndian>()? as usize;
+    let mut data = vec![0; size];
+    loop {
+        let page = input.read_u32::<LittleEndian>()?;
+        if page == u32::MAX {
+            return Ok(data);
+        }
+        let start = page as usize * PAGE_SIZE;
+        let len = input.read_u32::<LittleEndian>()? as usize;
+        if len > PAGE_SIZE || len > remaining(input) {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let dest = data.get_mut(start..start + len)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        input.read_exact(dest)?;
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, data: &[u8]) {
+    out.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+    out.extend_from_slice(data);
+}
+
+fn read_bytes(input: &mut Cursor<&[u8]>) -> io::Result<Vec<u8>> {
+    let len = input.read_u32::<LittleEndian>()? as usize;
+    if len > remaining(input) {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut data = vec![0; len];
+    input.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn write_section(out: &mut dyn Write, tag: &[u8; 4], payload: &[u8]) -> io::Result<()> {
+    out.write_all(tag)?;
+    out.write_u32::<LittleEndian>(payload.len() as u32)?;
+    out.write_all(payload)
+}
+
+impl Emulator {
+    /// Host state that a snapshot of the machine would leave behind.
+    pub fn unsaved_host_state(&self) -> Vec<&'static str> {
+        let mut lost = vec![];
+        if self.linux.files.iter().any(|f| matches!(f, Some(LinuxFile::Host(_)))) {
+            lost.push("open host files");
+        }
+        if self.disks.iter().any(|d| d.path.is_some()) {
+            lost.push("disk write-through");
+        }
+        if self.keyboard.has_pending_bytes() {
+            lost.push("pending keyboard bytes");
+        }
+        lost
+    }
+
+    /// Writes the complete machine state, warning about the host state it
+    /// cannot keep.
+    pub fn save_snapshot(&self, out: &mut dyn Write) -> io::Result<()> {
+        for what in self.unsaved_host_state() {
+            println!("warning: snapshot does not keep {}", what);
+        }
+
+        out.write_all(SNAPSHOT_MAGIC)?;
+        out.write_u32::<LittleEndian>(SNAPSHOT_VERSION)?;
+
+        let mut cpu = vec![];
+        for r in &self.registers.regs {
+            cpu.write_u32::<LittleEndian>(*r)?;
+        }
+        cpu.write_u32::<LittleEndian>(self.eflags)?;
+        cpu.write_u32::<LittleEndian>(self.eip)?;
+        cpu.write_u8(self.halted as u8)?;
+        cpu.write_u8(self.exit_code.is_some() as u8)?;
+        cpu.write_i32::<LittleEndian>(self.exit_code.unwrap_or(0))?;
+        cpu.write_u8(match self.personality {
+            Personality::Bios => 0,
+            Personality::Linux => 1,
+        })?;
+        cpu.write_u64::<LittleEndian>(self.icount)?;
+        write_section(out, b"CPU ", &cpu)?;
+
+        let mut mem = vec![];
+        write_sparse(&mut mem, &self.memory);
+        write_section(out, b"MEM ", &mem)?;
+
+        let mut vga = vec![self.vga.mode, self.vga.crtc_index];
+        write_bytes(&mut vga, &self.vga.crtc);
+        let palette: Vec<u8> = self.vga.palette.iter().flatten().copied().collect();
+        write_bytes(&mut vga, &palette);
+        vga.extend_from_slice(&[self.vga.dac_read_index, self.vga.dac_write_index,
+                                self.vga.dac_component, self.vga.dirty as u8]);
+        write_section(out, b"VGA ", &vga)?;
+
+        let mut vbe = vec![];
+        vbe.write_u16::<LittleEndian>(self.vbe.index)?;
+        write_bytes(&mut vbe, &self.vbe.regs.iter().flat_map(|r| r.to_le_bytes()).collect::<Vec<u8>>());
+        write_sparse(&mut vbe, &self.vbe.lfb);
+        write_section(out, b"VBE ", &vbe)?;
+
+        let mut disks = vec![self.disk_status];
+        disks.write_u32::<LittleEndian>(self.disks.len() as u32)?;
+        for disk in &self.disks {
+            disks.write_u8(disk.drive)?;
+            disks.write_u32::<LittleEndian>(disk.cylinders)?;
+            disks.write_u32::<LittleEndian>(disk.heads)?;
+            disks.write_u32::<LittleEndian>(disk.sectors)?;
+            write_sparse(&mut disks, &disk.image);
+        }
+        write_section(out, b"DISK", &disks)?;
+
+        let mut kbd = vec![self.keyboard.shift_flags];
+        kbd.write_u32::<LittleEndian>(self.keyboard.buffer.len() as u32)?;
+        for key in &self.keyboard.buffer {
+            kbd.write_u16::<LittleEndian>(*key)?;
+        }
+        write_section(out, b"KBD ", &kbd)?;
+
+        let chipset = &self.chipset;
+        let mut chip = vec![];
+        chip.write_u32::<LittleEndian>(chipset.pci_address)?;
+        write_bytes(&mut chip, &chipset.host_bridge);
+        chip.write_u8(chipset.cmos_index)?;
+        write_bytes(&mut chip, &chipset.cmos);
+        chip.write_u8(chipset.post_code.is_some() as u8)?;
+        chip.write_u8(chipset.post_code.unwrap_or(0))?;
+        write_section(out, b"CHIP", &chip)?;
+
+        let mut linux = vec![];
+        linux.write_u32::<LittleEndian>(self.linux.brk_start)?;
+        linux.write_u32::<LittleEndian>(self.linux.brk)?;
+        linux.write_u32::<LittleEndian>(self.linux.mmap_top)?;
+        let files: Vec<u8> = self.linux.files.iter().map(|f| match f {
+            None => 0,
+            Some(LinuxFile::Stdin) => 1,
+            Some(LinuxFile::Stdout) => 2,
+            Some(LinuxFile::Stderr) => 3,
+            Some(LinuxFile::Host(_)) => 4,
+        }).collect();
+        write_bytes(&mut linux, &files);
+        linux.write_u32::<LittleEndian>(self.linux.tls_base)?;
+        write_section(out, b"LNX ", &linux)?;
+
+        out.flush()
+    }
+
+    /// Builds an emulator from a snapshot written by `save_snapshot`.
+    pub fn load_snapshot(input: &mut dyn Read) -> Result<Emulator, SnapshotError> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic).map_err(|_| SnapshotError::BadMagic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = input.read_u32::<LittleEndian>()?;
+        if version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut emu = Emulator::new(0, 0, 0);
+        let mut seen_cpu = false;
+        loop {
+            let mut tag = [0u8; 4];
+            match input.read_exact(&mut tag) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => { break; },
+                Err(e) => { return Err(e.into()); }
+            }
+            let name = match &tag {
+                b"CPU " => "CPU",
+                b"MEM " => "memory",
+                b"VGA " => "VGA",
+                b"VBE " => "VBE",
+                b"DISK" => "disk",
+                b"KBD " => "keyboard",
+                b"CHIP" => "chipset",
+                b"LNX " => "Linux",
+                _ => "unknown",
+            };
+            let len = input.read_u32::<LittleEndian>().map_err(|_| SnapshotError::Corrupt(name))?;
+            let mut payload = vec![];
+            input.take(len as u64).read_to_end(&mut payload)?;
+            if payload.len() != len as usize {
+                return Err(SnapshotError::Corrupt(name));
+            }
+
+            let mut p = Cursor::new(&payload[..]);
+            let res = match &tag {
+                b"CPU " => {
+                    seen_cpu = true;
+                    emu.restore_cpu(&mut p, version)
+                },
+                b"MEM " => read_sparse(&mut p).map(|m| emu.memory = m),
+                b"VGA " => emu.restore_vga(&mut p),
+                b"VBE " => emu.restore_vbe(&mut p),
+                b"DISK" => emu.restore_disks(&mut p),
+                b"KBD " => emu.restore_keyboard(&mut p),
+                b"CHIP" => emu.restore_chipset(&mut p),
+                b"LNX " => emu.restore_linux(&mut p),
+                _ => Ok(()),
+            };
+            res.map_err(|_| SnapshotError::Corrupt(name))?;
+        }
+
+        if !seen_cpu {
+            return Err(SnapshotError::Corrupt("CPU"));
+        }
+        Ok(emu)
+    }
+
+    fn restore_cpu(&mut self, p: &mut Cursor<&[u8]>, version: u32) -> io::Result<()> {
+        for r in &mut self.registers.regs {
+            *r = p.read_u32::<LittleEndian>()?;
+        }
+        self.eflags = p.read_u32::<LittleEndian>()?;
+        self.eip = p.read_u32::<LittleEndian>()?;
+        self.halted = p.read_u8()? != 0;
+        let has_exit_code = p.read_u8()? != 0;
+        let exit_code = p.read_i32::<LittleEndian>()?;
+        self.exit_code = if has_exit_code { Some(exit_code) } else { None };
+        self.personality = match p.read_u8()? {
+            0 => Personality::Bios,
+            1 => Personality::Linux,
+            _ => { return Err(io::ErrorKind::InvalidData.into()); }
+        };
+        if version >= 2 {
+            self.icount = p.read_u64::<LittleEndian>()?;
+        }
+        Ok(())
+    }
+
+    fn restore_vga(&mut self, p: &mut Cursor<&[u8]>) -> io::Result<()> {
+        self.vga.mode = p.read_u8()?;
+        self.vga.crtc_index = p.read_u8()?;
+        let crtc = read_bytes(p)?;
+        if crtc.len() != self.vga.crtc.len() {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        self.vga.crtc.copy_from_slice(&crtc);
+        self.vga.palette = read_bytes(p)?.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+        self.vga.dac_read_index = p.read_u8()?;
+        self.vga.dac_write_index = p.read_u8()?;
+        self.vga.dac_component = p.read_u8()?;
+        self.vga.dirty = p.read_u8()? != 0;
+        Ok(())
+    }
+
+    fn restore_vbe(&mut self, p: &mut Cursor<&[u8]>) -> io::Result<()> {
+        self.vbe.index = p.read_u16::<LittleEndian>()?;
+        let regs = read_bytes(p)?;
+        if regs.len() != self.vbe.regs.len() * 2 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        for (r, b) in self.vbe.regs.iter_mut().zip(regs.chunks_exact(2)) {
+            *r = u16::from_le_bytes([b[0], b[1]]);
+        }
+        self.vbe.lfb = read_sparse(p)?;
+        Ok(())
+    }
+
+    fn restore_disks(&mut self, p: &mut Cursor<&[u8]>) -> io::Result<()> {
+        self.disk_status = p.read_u8()?;
+        let count = p.read_u32::<LittleEndian>()?;
+        self.disks.clear();
+        for _ in 0..count {
+            let drive = p.read_u8()?;
+            let cylinders = p.read_u32::<LittleEndian>()?;
+            let heads = p.read_u32::<LittleEndian>()?;
+            let sectors = p.read_u32::<LittleEndian>()?;
+            let image = read_sparse(p)?;
+            self.disks.push(Disk { drive, image, path: None, cylinders, heads, sectors });
+        }
+        Ok(())
+    }
+
+    fn restore_keyboard(&mut self, p: &mut Cursor<&[u8]>) -> io::Result<()> {
+        self.keyboard.shift_flags = p.read_u8()?;
+        let count = p.read_u32::<LittleEndian>()?;
+        self.keyboard.buffer.clear();
+        for _ in 0..count {
+            self.keyboard.buffer.push_back(p.read_u16::<LittleEndian>()?);
+        }
+        Ok(())
+    }
+
+    fn restore_chipset(&mut self, p: &mut Cursor<&[u8]>) -> io::Result<()> {
+        let chipset = &mut self.chipset;
+        chipset.pci_address = p.read_u32::<LittleEndian>()?;
+        chipset.host_bridge = read_bytes(p)?;
+        chipset.cmos_index = p.read_u8()?;
+        chipset.cmos = read_bytes(p)?;
+        let has_post_code = p.read_u8()? != 0;
+        let post_code = p.read_u8()?;
+        chipset.post_code = if has_post_code { Some(post_code) } else { None };
+        Ok(())
+    }
+
+    fn restore_linux(&mut self, p: &mut Cursor<&[u8]>) -> io::Result<()> {
+        self.linux.brk_start = p.read_u32::<LittleEndian>()?;
+        self.linux.brk = p.read_u32::<LittleEndian>()?;
+        self.linux.mmap_top = p.read_u32::<LittleEndian>()?;
+        self.linux.files = read_bytes(p)?.iter().map(|f| match f {
+            1 => Some(LinuxFile::Stdin),
+            2 => Some(LinuxFile::Stdout),
+            3 => Some(LinuxFile::Stderr),
+            // host files cannot be reopened
+            _ => None,
+        }).collect();
+        // added after version 2 snapshots were first written
+        self.linux.tls_base = p.read_u32::<LittleEndian>().unwrap_or(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(emu: &Emulator) -> Vec<u8> {
+        let mut out = vec![];
+        emu.save_snapshot(&mut out).unwrap();
+        out
+    }
+
+    fn load(data: &[u8]) -> Result<Emulator, SnapshotError> {
+        Emulator::load_snapshot(&mut &data[..])
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut emu = Emulator::new(0x3000, 0x1000, 0x2ff0);
+        emu.registers.regs = [1, 2, 3, 4, 0x2ff0, 6, 7, 8];
+        emu.eflags = 0x46;
+        emu.icount = 1234;
+        emu.memory[0x1000] = 0xf4;
+        emu.memory[0x2fff] = 0x55;
+        emu.personality = Personality::Linux;
+        emu.linux.tls_base = 0x2000;
+        emu.linux.files = vec![Some(LinuxFile::Stdin), None, Some(LinuxFile::Stderr)];
+        emu.disks.push(Disk::new(0x80, vec![0x5a; 1024]));
+        emu.keyboard.buffer.push_back(0x1c0d);
+
+        let restored = load(&snapshot(&emu)).unwrap();
+        assert_eq!(restored.registers.regs, emu.registers.regs);
+        assert_eq!(restored.eflags, 0x46);
+        assert_eq!(restored.eip, 0x1000);
+        assert_eq!(restored.icount, 1234);
+        assert_eq!(restored.memory, emu.memory);
+        assert_eq!(restored.personality, Personality::Linux);
+        assert_eq!(restored.linux.tls_base, 0x2000);
+        assert!(matches!(restored.linux.files[..],
+                         [Some(LinuxFile::Stdin), None, Some(LinuxFile::Stderr)]));
+        assert_eq!(restored.disks.len(), 1);
+        assert_eq!(restored.disks[0].image, emu.disks[0].image);
+        assert_eq!(restored.keyboard.buffer, emu.keyboard.buffer);
+    }
+
+    #[test]
+    fn sparse_pages() {
+        let mut data = vec![0; 3 * PAGE_SIZE + 10];
+        data[PAGE_SIZE + 1] = 1;
+        data[3 * PAGE_SIZE + 9] = 2;
+        let mut out = vec![];
+        write_sparse(&mut out, &data);
+        assert!(out.len() < 2 * PAGE_SIZE);
+        assert_eq!(read_sparse(&mut Cursor::new(&out[..])).unwrap(), data);
+    }
+
+    #[test]
+    fn malformed_snapshots() {
+        let good = snapshot(&Emulator::new(0x2000, 0, 0));
+
+        assert!(matches!(load(b"NOTASNAP\x02\0\0\0"), Err(SnapshotError::BadMagic)));
+        assert!(matches!(load(b"PX86"), Err(SnapshotError::BadMagic)));
+
+        let mut newer = good.clone();
+        newer[8] = 99;
+        assert!(matches!(load(&newer), Err(SnapshotError::UnsupportedVersion(99))));
+
+        // the header alone has no CPU section
+        assert!(matches!(load(&good[..12]), Err(SnapshotError::Corrupt("CPU"))));
+        // cut inside the CPU section
+        assert!(matches!(load(&good[..30]), Err(SnapshotError::Corrupt("CPU"))));
+
+        let mut unknown = good.clone();
+        unknown.extend_from_slice(b"XTRA\x02\0\0\0ab");
+        assert!(load(&unknown).is_ok());
+    }
+
+    #[test]
+    fn oversized_sparse_pages() {
+        let mut page = vec![];
+        page.write_u32::<LittleEndian>(2 * PAGE_SIZE as u32).unwrap();
+        page.write_u32::<LittleEndian>(0).unwrap();
+        page.write_u32::<LittleEndian>(PAGE_SIZE as u32).unwrap();
+        page.extend_from_slice(&[1; 16]);
+        assert!(read_sparse(&mut Cursor::new(&page[..])).is_err());
+
+        let mut long = vec![];
+        long.write_u32::<LittleEndian>(4 * PAGE_SIZE as u32).unwrap();
+        long.write_u32::<LittleEndian>(0).unwrap();
+        long.write_u32::<LittleEndian>(2 * PAGE_SIZE as u32).unwrap();
+        long.extend_from_slice(&[1; 2 * PAGE_SIZE]);
+        long.write_u32::<LittleEndian>(u32::MAX).unwrap();
+        assert!(read_sparse(&mut Cursor::new(&long[..])).is_err());
+
+        let mut bytes = vec![];
+        bytes.write_u32::<LittleEndian>(100).unwrap();
+        bytes.extend_from_slice(&[0; 8]);
+        assert!(read_bytes(&mut Cursor::new(&bytes[..])).is_err());
+    }
+
+    #[test]
+    fn host_state_is_reported() {
+        let mut emu = Emulator::new(0x1000, 0, 0);
+        assert!(emu.unsaved_host_state().is_empty());
+        let mut disk = Disk::new(0x80, vec![0; 512]);
+        disk.path = Some("disk.img".to_string());
+        emu.disks.push(disk);
+        emu.keyboard.feed_host_input(b"\x1b[", false);
+        assert_eq!(emu.unsaved_host_state(), ["disk write-through", "pending keyboard bytes"]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Machine-readable execution traces, built on the code and memory hooks.
+//!
+//! JSON Lines: one object per instruction, e.g.
+//! `{"n":0,"eip":31744,"bytes":"b801000000","asm":"mov eax, 0x1","regs":{"eax":1},"flags":{},"mem":[]}`.
+//! `regs` and `flags` only list what the instruction changed; `mem` lists
+//! its writes as runs of bytes, `{"addr":32768,"data":"05000000"}`.
+//!
+//! Binary: the magic `PX86TRC\0` and a u32 version, then per instruction,
+//! all little-endian: u32 eip, u8 length and the instruction bytes, a u16
+//! mask of changed registers (bits 0-7 eax..edi, bit 8 eflags) followed by
+//! a u32 per set bit, then a u16 count of write runs, each a u32 address,
+//! a u8 length and the bytes.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use super::{Emulator, disasm::{Instruction, REGS32}, hooks::{Access, HookAction}};
+
+pub const TRACE_MAGIC: &[u8; 8] = b"PX86TRC\0";
+pub const TRACE_VERSION: u32 = 1;
+
+const FLAG_NAMES: [(u32, &str); 9] = [
+    (0, "CF"), (2, "PF"), (4, "AF"), (6, "ZF"), (7, "SF"),
+    (8, "TF"), (9, "IF"), (10, "DF"), (11, "OF"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    JsonLines,
+    Binary,
+}
+
+struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    count: u64,
+    regs: [u32; 8],
+    eflags: u32,
+    /// Bytes written by the current instruction, in order.
+    writes: Vec<(u32, u8)>,
+    error: Option<io::Error>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Merges consecutive byte writes into (address, bytes) runs.
+fn write_runs(writes: &[(u32, u8)]) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, Vec<u8>)> = vec![];
+    for &(addr, val) in writes {
+        match runs.last_mut() {
+            Some((start, data)) if start.wrapping_add(data.len() as u32) == addr && data.len() < 255 => {
+                data.push(val);
+            },
+            _ => runs.push((addr, vec![val])),
+        }
+    }
+    runs
+}
+
+impl Tracer {
+    fn before(&mut self, emu: &Emulator) {
+        self.regs = emu.registers.regs;
+        self.eflags = emu.eflags;
+        self.writes.clear();
+    }
+
+    fn after(&mut self, emu: &Emulator, inst: &Instruction) {
+        if self.error.is_some() {
+            return;
+        }
+        let res = match self.format {
+            TraceFormat::JsonLines => self.write_json(emu, inst),
+            TraceFormat::Binary => self.write_binary(emu, inst),
+        };
+        if let Err(e) = res {
+            self.error = Some(e);
+        }
+        self.count += 1;
+    }
+
+    fn changed_registers(&self, emu: &Emulator) -> Vec<(usize, u32)> {
+        (0..8).filter(|&i| emu.registers.regs[i] != self.regs[i])
+            .map(|i| (i, emu.registers.regs[i]))
+            .collect()
+    }
+
+    fn write_json(&mut self, emu: &Emulator, inst: &Instruction) -> io::Result<()> {
+        let regs: Vec<String> = self.changed_registers(emu).iter()
+            .map(|(i, val)| format!("\"{}\":{}", REGS32[*i], val))
+            .collect();
+        let changed = emu.eflags ^ self.eflags;
+        let flags: Vec<String> = FLAG_NAMES.iter()
+            .filter(|(bit, _)| changed & (1 << bit) != 0)
+            .map(|(bit, name)| format!("\"{}\":{}", name, (emu.eflags >> bit) & 1))
+            .collect();
+        let mem: Vec<String> = write_runs(&self.writes).iter()
+            .map(|(addr, data)| format!("{{\"addr\":{},\"data\":\"{}\"}}", addr, hex(data)))
+            .collect();
+
+        writeln!(self.out, "{{\"n\":{},\"eip\":{},\"bytes\":\"{}\",\"asm\":{},\"regs\":{{{}}},\"flags\":{{{}}},\"mem\":[{}]}}",
+                 self.count, inst.addr, hex(&inst.bytes), json_string(&inst.text),
+                 regs.join(","), flags.join(","), mem.join(","))
+    }
+
+    fn write_binary(&mut self, emu: &Emulator, inst: &Instruction) -> io::Result<()> {
+        let mut record = vec![];
+        record.extend_from_slice(&inst.addr.to_le_bytes());
+        record.push(inst.bytes.len() as u8);
+        record.extend_from_slice(&inst.bytes);
+
+        let mut changed = self.changed_registers(emu);
+        if emu.eflags != self.eflags {
+            changed.push((8, emu.eflags));
+        }
+        let mask = changed.iter().fold(0u16, |acc, (i, _)| acc | 1 << i);
+        record.extend_from_slice(&mask.to_le_bytes());
+        for (_, val) in &changed {
+            record.extend_from_slice(&val.to_le_bytes());
+        }
+
+        let runs = write_runs(&self.writes);
+        record.extend_from_slice(&(runs.len() as u16).to_le_bytes());
+        for (addr, data) in &runs {
+            record.extend_from_slice(&addr.to_le_bytes());
+            record.push(data.len() as u8);
+            record.extend_from_slice(data);
+        }
+        self.out.write_all(&record)
+    }
+}
+
+/// A running trace; pass it back to `Emulator::stop_trace`.
+pub struct TraceHandle {
+    tracer: Arc<Mutex<Tracer>>,
+    hooks: [usize; 3],
+}
+
+impl Emulator {
+    /// Starts writing a record for every instruction executed by `step`.
+    pub fn start_trace(&mut self, mut out: Box<dyn Write + Send>, format: TraceFormat)
+        -> io::Result<TraceHandle> {
+        if format == TraceFormat::Binary {
+            out.write_all(TRACE_MAGIC)?;
+            out.write_all(&TRACE_VERSION.to_le_bytes())?;
+        }
+        let tracer = Arc::new(Mutex::new(Tracer {
+            out,
+            format,
+            count: 0,
+            regs: [0; 8],
+            eflags: 0,
+            writes: vec![],
+            error: None,
+        }));
+
+        let t = tracer.clone();
+        let before = self.hook_code(Arc::new(move |emu, _| t.lock().unwrap().before(emu)));
+        let t = tracer.clone();
+        let after = self.hook_code_after(Arc::new(move |emu, inst| t.lock().unwrap().after(emu, inst)));
+        let t = tracer.clone();
+        let writes = self.add_memory_hook(Arc::new(move |access| {
+            if access.kind == Access::Write {
+                t.lock().unwrap().writes.push((access.addr, access.value));
+            }
+            HookAction::Allow
+        }));
+
+        Ok(TraceHandle { tracer, hooks: [before, after, writes] })
+    }
+
+    /// Removes the trace hooks and flushes the output. Reports the first
+    /// write error of the whole trace, if there was one.
+    pub fn stop_trace(&mut self, handle: TraceHandle) -> io::Result<()> {
+        for id in handle.hooks {
+            self.remove_hook(id);
+        }
+        let mut tracer = handle.tracer.lock().unwrap();
+        if let Some(e) = tracer.error.take() {
+            return Err(e);
+        }
+        tracer.out.flush()
+    }
+}
@@ -0,0 +1,176 @@
+use super::Emulator;
+
+/// Fixed physical address of the linear framebuffer. Bochs and QEMU report
+/// it through PCI BAR0; without a PCI bus the guest has to assume it.
+pub const VBE_LFB_ADDRESS: u32 = 0xe000_0000;
+pub const VBE_LFB_SIZE: usize = 16 * 1024 * 1024;
+
+pub const VBE_DISPI_IOPORT_INDEX: u16 = 0x01ce;
+pub const VBE_DISPI_IOPORT_DATA: u16 = 0x01cf;
+
+pub const VBE_DISPI_INDEX_ID: usize = 0x0;
+pub const VBE_DISPI_INDEX_XRES: usize = 0x1;
+pub const VBE_DISPI_INDEX_YRES: usize = 0x2;
+pub const VBE_DISPI_INDEX_BPP: usize = 0x3;
+pub const VBE_DISPI_INDEX_ENABLE: usize = 0x4;
+pub const VBE_DISPI_INDEX_BANK: usize = 0x5;
+pub const VBE_DISPI_INDEX_VIRT_WIDTH: usize = 0x6;
+pub const VBE_DISPI_INDEX_VIRT_HEIGHT: usize = 0x7;
+pub const VBE_DISPI_INDEX_X_OFFSET: usize = 0x8;
+pub const VBE_DISPI_INDEX_Y_OFFSET: usize = 0x9;
+pub const VBE_DISPI_INDEX_VIDEO_MEMORY_64K: usize = 0xa;
+const VBE_DISPI_NUM_REGISTERS: usize = 0xb;
+
+pub const VBE_DISPI_ID5: u16 = 0xb0c5;
+pub const VBE_DISPI_MAX_XRES: u16 = 1920;
+pub const VBE_DISPI_MAX_YRES: u16 = 1200;
+
+pub const VBE_DISPI_ENABLED: u16 = 0x01;
+pub const VBE_DISPI_GETCAPS: u16 = 0x02;
+pub const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+pub const VBE_DISPI_NOCLEARMEM: u16 = 0x80;
+
+/// Bochs "dispi" display interface, the register file behind ports
+/// 0x1ce/0x1cf.
+#[derive(Debug, Default, Clone)]
+pub struct Vbe {
+    pub index: u16,
+    pub regs: [u16; VBE_DISPI_NUM_REGISTERS],
+    /// Video memory, allocated the first time the display is enabled.
+    pub lfb: Vec<u8>,
+}
+
+impl Vbe {
+    pub fn new() -> Vbe {
+        let mut vbe = Vbe::default();
+        vbe.regs[VBE_DISPI_INDEX_ID] = VBE_DISPI_ID5;
+        vbe.regs[VBE_DISPI_INDEX_XRES] = 640;
+        vbe.regs[VBE_DISPI_INDEX_YRES] = 480;
+        vbe.regs[VBE_DISPI_INDEX_BPP] = 8;
+        vbe.regs[VBE_DISPI_INDEX_VIDEO_MEMORY_64K] = (VBE_LFB_SIZE / 0x10000) as u16;
+        vbe
+    }
+
+    pub fn enabled(&self) -> bool {
+        (self.regs[VBE_DISPI_INDEX_ENABLE] & VBE_DISPI_ENABLED) != 0
+    }
+
+    pub fn bpp(&self) -> usize {
+        self.regs[VBE_DISPI_INDEX_BPP] as usize
+    }
+
+    pub fn read_data(&self) -> u16 {
+        let idx = self.index as usize;
+        if idx >= VBE_DISPI_NUM_REGISTERS {
+            return 0;
+        }
+
+        let getcaps = (self.regs[VBE_DISPI_INDEX_ENABLE] & VBE_DISPI_GETCAPS) != 0;
+        match idx {
+            VBE_DISPI_INDEX_XRES if getcaps => VBE_DISPI_MAX_XRES,
+            VBE_DISPI_INDEX_YRES if getcaps => VBE_DISPI_MAX_YRES,
+            VBE_DISPI_INDEX_BPP if getcaps => 32,
+            _ => self.regs[idx]
+        }
+    }
+
+    pub fn write_data(&mut self, val: u16) {
+        let idx = self.index as usize;
+        match idx {
+            VBE_DISPI_INDEX_ID if (0xb0c0..=VBE_DISPI_ID5).contains(&val) => {
+                self.regs[idx] = val;
+            },
+            VBE_DISPI_INDEX_XRES => {
+                self.regs[idx] = val.min(VBE_DISPI_MAX_XRES);
+            },
+            VBE_DISPI_INDEX_YRES => {
+                self.regs[idx] = val.min(VBE_DISPI_MAX_YRES);
+            },
+            VBE_DISPI_INDEX_BPP if [8, 15, 16, 24, 32].contains(&val) => {
+                self.regs[idx] = val;
+            },
+            VBE_DISPI_INDEX_ENABLE => { self.enable(val); },
+            VBE_DISPI_INDEX_ID | VBE_DISPI_INDEX_BPP
+                | VBE_DISPI_INDEX_VIDEO_MEMORY_64K => (),
+            _ if idx < VBE_DISPI_NUM_REGISTERS => { self.regs[idx] = val; },
+            _ => ()
+        }
+    }
+
+    fn enable(&mut self, val: u16) {
+        self.regs[VBE_DISPI_INDEX_ENABLE] = val;
+        if (val & VBE_DISPI_ENABLED) == 0 {
+            return;
+        }
+
+        self.regs[VBE_DISPI_INDEX_VIRT_WIDTH] = self.regs[VBE_DISPI_INDEX_XRES];
+        self.regs[VBE_DISPI_INDEX_VIRT_HEIGHT] = self.regs[VBE_DISPI_INDEX_YRES];
+        self.regs[VBE_DISPI_INDEX_X_OFFSET] = 0;
+        self.regs[VBE_DISPI_INDEX_Y_OFFSET] = 0;
+
+        if self.lfb.is_empty() {
+            self.lfb = vec![0; VBE_LFB_SIZE];
+        } else if (val & VBE_DISPI_NOCLEARMEM) == 0 {
+            for b in self.lfb.iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+
+    /// Offset into `lfb` for a physical address inside the framebuffer
+    /// aperture, once video memory exists.
+    pub fn lfb_offset(&self, addr: u32) -> Option<usize> {
+        if addr >= VBE_LFB_ADDRESS && ((addr - VBE_LFB_ADDRESS) as usize) < self.lfb.len() {
+            Some((addr - VBE_LFB_ADDRESS) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn pixel_rgb(&self, emu: &Emulator, offset: usize) -> [u8; 3] {
+        let byte = |i: usize| self.lfb.get(offset + i).cloned().unwrap_or(0);
+        match self.bpp() {
+            8 => emu.vga.palette_rgb(byte(0)),
+            15 => {
+                let p = byte(0) as u32 | (byte(1) as u32) << 8;
+                [scale(p >> 10, 0x1f), scale(p >> 5, 0x1f), scale(p, 0x1f)]
+            },
+            16 => {
+                let p = byte(0) as u32 | (byte(1) as u32) << 8;
+                [scale(p >> 11, 0x1f), scale(p >> 5, 0x3f), scale(p, 0x1f)]
+            },
+            _ => [byte(2), byte(1), byte(0)]
+        }
+    }
+}
+
+/// Expands a colour component of `mask` width to 8 bits.
+fn scale(val: u32, mask: u32) -> u8 {
+    ((val & mask) * 255 / mask) as u8
+}
+
+impl Emulator {
+    /// The enabled VBE display converted to 8-bit RGB.
+    pub fn vbe_framebuffer_rgb(&self) -> Option<(usize, usize, Vec<u8>)> {
+        if !self.vbe.enabled() {
+            return None;
+        }
+
+        let width = self.vbe.regs[VBE_DISPI_INDEX_XRES] as usize;
+        let height = self.vbe.regs[VBE_DISPI_INDEX_YRES] as usize;
+        let virt_width = self.vbe.regs[VBE_DISPI_INDEX_VIRT_WIDTH] as usize;
+        let x_offset = self.vbe.regs[VBE_DISPI_INDEX_X_OFFSET] as usize;
+        let y_offset = self.vbe.regs[VBE_DISPI_INDEX_Y_OFFSET] as usize;
+        let bytes_per_pixel = self.vbe.bpp().div_ceil(8);
+
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let offset = ((y + y_offset) * virt_width.max(width) + x + x_offset)
+                    * bytes_per_pixel;
+                rgb.extend_from_slice(&self.vbe.pixel_rgb(self, offset));
+            }
+        }
+        Some((width, height, rgb))
+    }
+}
@@ -0,0 +1,399 @@
+use std::io;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use super::{Emulator, image};
+
+/// Physical address of the colour text-mode buffer.
+pub const TEXT_BUFFER: u32 = 0xb8000;
+pub const TEXT_COLUMNS: usize = 80;
+pub const TEXT_ROWS: usize = 25;
+
+/// Physical address of the mode 13h framebuffer (one palette index per pixel).
+pub const GRAPHICS_BUFFER: u32 = 0xa0000;
+pub const MODE13_WIDTH: usize = 320;
+pub const MODE13_HEIGHT: usize = 200;
+
+pub const MODE_TEXT: u8 = 0x03;
+pub const MODE_13H: u8 = 0x13;
+
+/// Maps the 3-bit VGA colour index to the ANSI SGR foreground colour.
+pub const VGA_TO_TERMINAL: [u8; 8] = [30, 34, 32, 36, 31, 35, 33, 37];
+
+const CRTC_REGISTERS: usize = 0x19;
+const CRTC_CURSOR_START: usize = 0x0a;
+const CRTC_CURSOR_END: usize = 0x0b;
+const CRTC_CURSOR_HIGH: usize = 0x0e;
+const CRTC_CURSOR_LOW: usize = 0x0f;
+
+#[derive(Debug, Clone)]
+pub struct Vga {
+    pub mode: u8,
+    pub crtc_index: u8,
+    pub crtc: [u8; CRTC_REGISTERS],
+    /// 256 DAC entries of 6-bit red, green and blue components.
+    pub palette: Vec<[u8; 3]>,
+    pub dac_read_index: u8,
+    pub dac_write_index: u8,
+    /// Which component (0 = red, 1 = green, 2 = blue) the next 0x3c9 access
+    /// touches.
+    pub dac_component: u8,
+    /// Set whenever the text buffer or the cursor changes and cleared by
+    /// whoever repaints the screen.
+    pub dirty: bool,
+}
+
+impl Default for Vga {
+    fn default() -> Vga {
+        Vga {
+            mode: MODE_TEXT,
+            crtc_index: 0,
+            crtc: [0; CRTC_REGISTERS],
+            palette: default_palette(),
+            dac_read_index: 0,
+            dac_write_index: 0,
+            dac_component: 0,
+            dirty: false,
+        }
+    }
+}
+
+/// Approximation of the palette the VGA BIOS loads for mode 13h: the 16 EGA
+/// colours, a 16-step grey ramp and nine 24-hue rings.
+fn default_palette() -> Vec<[u8; 3]> {
+    const EGA: [[u8; 3]; 16] = [
+        [0, 0, 0], [0, 0, 42], [0, 42, 0], [0, 42, 42],
+        [42, 0, 0], [42, 0, 42], [42, 21, 0], [42, 42, 42],
+        [21, 21, 21], [21, 21, 63], [21, 63, 21], [21, 63, 63],
+        [63, 21, 21], [63, 21, 63], [63, 63, 21], [63, 63, 63],
+    ];
+    const GREY: [u8; 16] = [0, 5, 8, 11, 14, 17, 20, 24, 28, 32, 36, 40, 45, 50, 56, 63];
+    const RINGS: [(u8, u8); 9] = [
+        (63, 0), (63, 31), (63, 45),
+        (28, 0), (28, 14), (28, 20),
+        (16, 0), (16, 8), (16, 11),
+    ];
+
+    let mut palette: Vec<[u8; 3]> = EGA.to_vec();
+    palette.extend(GREY.iter().map(|&g| [g, g, g]));
+    for &(hi, lo) in RINGS.iter() {
+        let step = |i: u8| lo + (hi - lo) * i / 4;
+        let down = |i: u8| hi - (hi - lo) * i / 4;
+        for i in 0..4 { palette.push([step(i), lo, hi]); }
+        for i in 0..4 { palette.push([hi, lo, down(i)]); }
+        for i in 0..4 { palette.push([hi, step(i), lo]); }
+        for i in 0..4 { palette.push([down(i), hi, lo]); }
+        for i in 0..4 { palette.push([lo, hi, step(i)]); }
+        for i in 0..4 { palette.push([lo, down(i), hi]); }
+    }
+    palette.resize(256, [0, 0, 0]);
+    palette
+}
+
+impl Vga {
+    pub fn read_dac(&mut self) -> u8 {
+        let val = self.palette[self.dac_read_index as usize][self.dac_component as usize];
+        self.next_dac_component(true);
+        val
+    }
+
+    pub fn write_dac(&mut self, val: u8) {
+        self.palette[self.dac_write_index as usize][self.dac_component as usize] = val & 0x3f;
+        self.next_dac_component(false);
+    }
+
+    fn next_dac_component(&mut self, read: bool) {
+        self.dac_component += 1;
+        if self.dac_component == 3 {
+            self.dac_component = 0;
+            if read {
+                self.dac_read_index = self.dac_read_index.wrapping_add(1);
+            } else {
+                self.dac_write_index = self.dac_write_index.wrapping_add(1);
+            }
+        }
+    }
+
+    pub fn is_text_mode(&self) -> bool {
+        self.mode != MODE_13H
+    }
+
+    /// 8-bit RGB value of a palette entry.
+    pub fn palette_rgb(&self, idx: u8) -> [u8; 3] {
+        let [r, g, b] = self.palette[idx as usize];
+        [r << 2 | r >> 4, g << 2 | g >> 4, b << 2 | b >> 4]
+    }
+
+    pub fn read_crtc(&self) -> u8 {
+        let idx = self.crtc_index as usize;
+        if idx < CRTC_REGISTERS { self.crtc[idx] } else { 0xff }
+    }
+
+    pub fn write_crtc(&mut self, val: u8) {
+        let idx = self.crtc_index as usize;
+        if idx < CRTC_REGISTERS {
+            self.crtc[idx] = val;
+            if idx == CRTC_CURSOR_HIGH || idx == CRTC_CURSOR_LOW {
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Cursor position as a linear cell offset into the text buffer.
+    pub fn cursor(&self) -> u16 {
+        ((self.crtc[CRTC_CURSOR_HIGH] as u16) << 8)
+            | self.crtc[CRTC_CURSOR_LOW] as u16
+    }
+
+    /// Cursor start and end scan lines as returned in CH/CL by INT 10h.
+    pub fn cursor_shape(&self) -> u16 {
+        ((self.crtc[CRTC_CURSOR_START] as u16) << 8)
+            | self.crtc[CRTC_CURSOR_END] as u16
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: u16) {
+        self.crtc[CRTC_CURSOR_START] = (shape >> 8) as u8;
+        self.crtc[CRTC_CURSOR_END] = (shape & 0xff) as u8;
+    }
+
+    pub fn set_cursor(&mut self, pos: u16) {
+        self.crtc[CRTC_CURSOR_HIGH] = (pos >> 8) as u8;
+        self.crtc[CRTC_CURSOR_LOW] = (pos & 0xff) as u8;
+        self.dirty = true;
+    }
+
+    pub fn is_text_address(addr: u32) -> bool {
+        addr >= TEXT_BUFFER
+            && addr < TEXT_BUFFER + (TEXT_COLUMNS * TEXT_ROWS * 2) as u32
+    }
+}
+
+fn printable(ch: u8) -> char {
+    match ch {
+        0x20..=0x7e => ch as char,
+        _ => ' '
+    }
+}
+
+impl Emulator {
+    /// Character and attribute byte of the cell at `row`, `col`.
+    pub fn text_cell(&self, row: usize, col: usize) -> (u8, u8) {
+        let addr = TEXT_BUFFER as usize + (row * TEXT_COLUMNS + col) * 2;
+        if addr + 1 < self.memory.len() {
+            (self.memory[addr], self.memory[addr + 1])
+        } else {
+            (0, 0)
+        }
+    }
+
+    pub fn set_text_cell(&mut self, row: usize, col: usize, ch: u8, attr: u8) {
+        let addr = TEXT_BUFFER + ((row * TEXT_COLUMNS + col) * 2) as u32;
+        self.set_memory8(addr, ch as u32);
+        self.set_memory8(addr + 1, attr as u32);
+    }
+
+    /// Renders the whole text buffer as ANSI escape sequences, ending with
+    /// the terminal cursor placed where the CRTC cursor points.
+    pub fn render_text(&self) -> String {
+        let mut buf = String::from("\x1b[H");
+        for row in 0..TEXT_ROWS {
+            let mut last_attr = None;
+            for col in 0..TEXT_COLUMNS {
+                let (ch, attr) = self.text_cell(row, col);
+                if last_attr != Some(attr) {
+                    let bright = if (attr & 0x08) != 0 { 1 } else { 22 };
+                    buf += &format!("\x1b[{};{};{}m",
+                                    bright,
+                                    VGA_TO_TERMINAL[(attr & 0x7) as usize],
+                                    VGA_TO_TERMINAL[((attr >> 4) & 0x7) as usize] + 10);
+                    last_attr = Some(attr);
+                }
+                buf.push(printable(ch));
+            }
+            buf += "\x1b[0m\r\n";
+        }
+
+        let cursor = self.vga.cursor() as usize;
+        buf += &format!("\x1b[{};{}H",
+                        cursor / TEXT_COLUMNS + 1, cursor % TEXT_COLUMNS + 1);
+        buf
+    }
+
+    /// Plain-text dump of the text buffer with trailing blanks removed.
+    pub fn text_snapshot(&self) -> String {
+        let mut lines: Vec<String> = (0..TEXT_ROWS).map(|row| {
+            (0..TEXT_COLUMNS)
+                .map(|col| printable(self.text_cell(row, col).0))
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        }).collect();
+
+        while lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        lines.join("\n")
+    }
+}
+
+impl Emulator {
+    /// Switches the video mode the way INT 10h AH=00h does, clearing the
+    /// display memory of the new mode unless bit 7 of `mode` is set.
+    pub fn set_video_mode(&mut self, mode: u8) {
+        let keep = (mode & 0x80) != 0;
+        let mode = mode & 0x7f;
+        self.vga.mode = mode;
+        self.vga.set_cursor(0);
+        self.vga.set_cursor_shape(0x0607);
+
+        if keep {
+            return;
+        }
+        if mode == MODE_13H {
+            let start = GRAPHICS_BUFFER as usize;
+            let end = (start + MODE13_WIDTH * MODE13_HEIGHT).min(self.memory.len());
+            for b in self.memory.get_mut(start..end).unwrap_or_default() {
+                *b = 0;
+            }
+        } else if self.has_text_buffer() {
+            for addr in (TEXT_BUFFER..TEXT_BUFFER + (TEXT_COLUMNS * TEXT_ROWS * 2) as u32)
+                .step_by(2) {
+                self.set_memory8(addr, b' ' as u32);
+                self.set_memory8(addr + 1, 0x07);
+            }
+        }
+    }
+
+    /// Whether `memory` is large enough to hold the text buffer.
+    pub fn has_text_buffer(&self) -> bool {
+        self.memory.len() >= TEXT_BUFFER as usize + TEXT_COLUMNS * TEXT_ROWS * 2
+    }
+
+    /// Whether the display currently shows the text buffer rather than a
+    /// graphics framebuffer.
+    pub fn in_text_mode(&self) -> bool {
+        self.vga.is_text_mode() && !self.vbe.enabled() && self.has_text_buffer()
+    }
+
+    /// The visible graphics framebuffer converted to 8-bit RGB, or `None`
+    /// while a text mode is active.
+    pub fn framebuffer_rgb(&self) -> Option<(usize, usize, Vec<u8>)> {
+        if self.vbe.enabled() {
+            return self.vbe_framebuffer_rgb();
+        }
+        if self.vga.is_text_mode() {
+            return None;
+        }
+
+        let start = GRAPHICS_BUFFER as usize;
+        let mut rgb = Vec::with_capacity(MODE13_WIDTH * MODE13_HEIGHT * 3);
+        for i in 0..MODE13_WIDTH * MODE13_HEIGHT {
+            let idx = self.memory.get(start + i).cloned().unwrap_or(0);
+            rgb.extend_from_slice(&self.vga.palette_rgb(idx));
+        }
+        Some((MODE13_WIDTH, MODE13_HEIGHT, rgb))
+    }
+
+    /// Writes the current graphics framebuffer to `path`, as a binary PPM
+    /// when the name ends in `.ppm` and as a PNG otherwise.
+    pub fn save_screenshot(&self, path: &str) -> io::Result<()> {
+        if self.framebuffer_rgb().is_none() {
+            return Err(io::Error::new(io::ErrorKind::Other, "no graphics mode active"));
+        }
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_screenshot(&mut w, path.ends_with(".ppm"))?;
+        w.flush()
+    }
+
+    /// Encodes the current graphics framebuffer as a PPM or PNG image.
+    pub fn write_screenshot<W: Write>(&self, w: &mut W, ppm: bool) -> io::Result<()> {
+        let (width, height, rgb) = self.framebuffer_rgb().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "no graphics mode active")
+        })?;
+        if ppm {
+            image::write_ppm(w, width, height, &rgb)
+        } else {
+            image::write_png(w, width, height, &rgb)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::vbe::*;
+
+    #[test]
+    fn text_mode_has_no_screenshot() {
+        let emu = Emulator::new(0x100000, 0, 0);
+        assert!(emu.write_screenshot(&mut vec![], false).is_err());
+    }
+
+    #[test]
+    fn mode13h_screenshot() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        emu.set_video_mode(MODE_13H);
+        emu.memory[GRAPHICS_BUFFER as usize] = 1;
+        emu.memory[GRAPHICS_BUFFER as usize + 1] = 15;
+
+        let mut ppm = vec![];
+        emu.write_screenshot(&mut ppm, true).unwrap();
+        let header = b"P6\n320 200\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + MODE13_WIDTH * MODE13_HEIGHT * 3);
+        assert_eq!(&ppm[header.len()..header.len() + 9], &[0, 0, 0xaa, 0xff, 0xff, 0xff, 0, 0, 0]);
+    }
+
+    #[test]
+    fn vbe_screenshot_matches_golden_png() {
+        let mut emu = Emulator::new(0x1000, 0, 0);
+        for &(idx, val) in &[(VBE_DISPI_INDEX_XRES, 3), (VBE_DISPI_INDEX_YRES, 2),
+                             (VBE_DISPI_INDEX_BPP, 32), (VBE_DISPI_INDEX_ENABLE, VBE_DISPI_ENABLED)] {
+            emu.vbe.index = idx as u16;
+            emu.vbe.write_data(val);
+        }
+        // red, green, blue / white, black, grey, stored as BGRX
+        let pixels: [u32; 6] = [0xff0000, 0x00ff00, 0x0000ff, 0xffffff, 0x000000, 0x808080];
+        for (i, p) in pixels.iter().enumerate() {
+            emu.vbe.lfb[i * 4..i * 4 + 4].copy_from_slice(&p.to_le_bytes());
+        }
+
+        let mut png = vec![];
+        emu.write_screenshot(&mut png, false).unwrap();
+        assert_eq!(png, &include_bytes!("../../tests/data/screenshot_3x2.png")[..]);
+    }
+
+    #[test]
+    fn text_snapshot_trims_blanks() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        assert_eq!(emu.text_snapshot(), "");
+        for (col, &ch) in b"Hi \x01".iter().enumerate() {
+            emu.set_text_cell(0, col, ch, 0x07);
+        }
+        emu.set_text_cell(2, 3, b'x', 0x70);
+        emu.set_text_cell(24, 79, 0, 0x07);
+        assert_eq!(emu.text_snapshot(), "Hi\n\n   x");
+        assert!(emu.vga.dirty);
+
+        let emu = Emulator::new(0x10000, 0, 0);
+        assert_eq!(emu.text_snapshot(), "");
+    }
+
+    #[test]
+    fn render_text_colours_and_cursor() {
+        let mut emu = Emulator::new(0x100000, 0, 0);
+        emu.set_text_cell(0, 0, b'H', 0x1e);
+        emu.set_text_cell(0, 1, 0xdb, 0x1e);
+        emu.set_text_cell(1, 79, b'!', 0xc4);
+        emu.io_out8(0x3d4, CRTC_CURSOR_HIGH as u8);
+        emu.io_out8(0x3d5, 0x00);
+        emu.io_out8(0x3d4, CRTC_CURSOR_LOW as u8);
+        emu.io_out8(0x3d5, 0xa4);
+
+        let text = emu.render_text();
+        let rows: Vec<&str> = text.split("\x1b[0m\r\n").collect();
+        assert_eq!(rows.len(), TEXT_ROWS + 1);
+        assert_eq!(rows[0], format!("\x1b[H\x1b[1;33;44mH \x1b[22;37;40m{:78}", ""));
+        assert_eq!(rows[1], format!("\x1b[22;37;40m{:79}\x1b[22;31;41m!", ""));
+        assert_eq!(rows[TEXT_ROWS], "\x1b[3;5H");
+    }
+}
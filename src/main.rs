@@ -1,71 +1,567 @@
 use std::env;
 use std::process;
 use std::io::prelude::*;
-use std::fs::File;
+use std::time::{Duration, Instant};
 use x86_emu::emulator;
 
+mod monitor;
+
 const MEM_SIZE: usize = 1024 * 1024;
+const LOAD_ADDRESS: u32 = 0x7c00;
+const REGISTER_NAMES: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+/// Enough for executables linked at the usual 0x08048000 plus heap and stack.
+const LINUX_MEM_SIZE: usize = 0x0c00_0000;
+const MULTIBOOT_MEM_SIZE: usize = 0x0400_0000;
+/// Room for the interrupt vectors, the BIOS data area and a boot sector at
+/// 0x7c00; the CPU indexes guest memory directly.
+const MIN_MEM_SIZE: u64 = 0x10000;
+/// The whole 32-bit physical address space.
+const MAX_MEM_SIZE: u64 = 1 << 32;
+/// Instructions between reverse execution checkpoints.
+const CHECKPOINT_INTERVAL: u64 = 10_000;
+const REPAINT_INTERVAL: Duration = Duration::from_millis(33);
 
 fn main() {
-    let mut args: Vec<String> = env::args().collect();
-    
     let mut quiet_flag = false;
+    let mut screenshot: Option<String> = None;
+    let mut floppies: Vec<String> = vec![];
+    let mut hard_disks: Vec<String> = vec![];
+    let mut keys: Option<Vec<u8>> = None;
+    let mut boot = false;
+    let mut files: Vec<String> = vec![];
+    let mut linux = false;
+    let mut guest_args: Vec<String> = vec![];
+    let mut multiboot = false;
+    let mut cmdline: Option<String> = None;
+    let mut modules: Vec<String> = vec![];
+    let mut mem_size: Option<usize> = None;
+    let mut load_address = LOAD_ADDRESS;
+    let mut entry: Option<u32> = None;
+    let mut eflags: Option<u32> = None;
+    let mut register_values: Vec<(usize, u32)> = vec![];
+    let mut blobs: Vec<(String, u32, usize)> = vec![];
+    let mut gdb: Option<String> = None;
+    let mut monitor_flag = false;
+    let mut watchpoints: Vec<emulator::hooks::Watchpoint> = vec![];
+    let mut trace: Option<String> = None;
+    let mut trace_format: Option<emulator::trace::TraceFormat> = None;
+    let mut coverage: Option<String> = None;
+    let mut coverage_format: Option<emulator::coverage::CoverageFormat> = None;
+    let mut symbols: Option<String> = None;
+    let mut restore: Option<String> = None;
+    let mut save_snapshot: Option<String> = None;
+    let mut snapshot_at: Option<u32> = None;
+    let mut reverse = false;
+    let mut record: Option<String> = None;
+    let mut replay: Option<String> = None;
+    let mut checkpoint_interval = CHECKPOINT_INTERVAL;
+
+    if env::args().nth(1).as_deref() == Some("disasm") {
+        disasm(env::args().skip(2).collect());
+        return;
+    }
 
-    if args[1] == "quiet" {
-        quiet_flag = true;
-        args.remove(1);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "quiet" | "-q" | "--quiet" => { quiet_flag = true; },
+            "-h" | "--help" => {
+                usage();
+                process::exit(0);
+            },
+            "--gdb" => { gdb = args.next(); },
+            "--monitor" => { monitor_flag = true; },
+            "--trace" => { trace = args.next(); },
+            "--trace-format" => {
+                trace_format = match args.next().as_deref() {
+                    Some("jsonl") | Some("json") => Some(emulator::trace::TraceFormat::JsonLines),
+                    Some("bin") | Some("binary") => Some(emulator::trace::TraceFormat::Binary),
+                    other => {
+                        println!("unknown trace format {:?}; use jsonl or bin", other.unwrap_or(""));
+                        process::exit(1);
+                    }
+                };
+            },
+            "--coverage" => { coverage = args.next(); },
+            "--coverage-format" => {
+                coverage_format = match args.next().as_deref() {
+                    Some("hits") => Some(emulator::coverage::CoverageFormat::Hits),
+                    Some("lcov") => Some(emulator::coverage::CoverageFormat::Lcov),
+                    other => {
+                        println!("unknown coverage format {:?}; use hits or lcov", other.unwrap_or(""));
+                        process::exit(1);
+                    }
+                };
+            },
+            "--symbols" => { symbols = args.next(); },
+            "--watch" => {
+                let spec = args.next().unwrap_or_default();
+                match parse_watchpoint(&spec) {
+                    Some(wp) => watchpoints.push(wp),
+                    None => {
+                        println!("invalid watchpoint: {}", spec);
+                        process::exit(1);
+                    }
+                }
+            },
+            "--reverse" => { reverse = true; },
+            "--record" => { record = args.next(); },
+            "--replay" => { replay = args.next(); },
+            "--checkpoint-interval" => { checkpoint_interval = number_arg(&arg, args.next()); },
+            "--restore" => { restore = args.next(); },
+            "--save-snapshot" => { save_snapshot = args.next(); },
+            "--snapshot-at" => { snapshot_at = Some(number_arg(&arg, args.next()) as u32); },
+            "--mem" => {
+                let size = number_arg(&arg, args.next());
+                if !(MIN_MEM_SIZE..=MAX_MEM_SIZE).contains(&size) {
+                    println!("--mem must be between 64K and 4G");
+                    process::exit(1);
+                }
+                mem_size = Some(size as usize);
+            },
+            "--load" => { load_address = number_arg(&arg, args.next()) as u32; },
+            "--entry" => { entry = Some(number_arg(&arg, args.next()) as u32); },
+            "--reg" => {
+                let value = args.next().unwrap_or_default();
+                match parse_register(&value) {
+                    Some((name, val)) if name == "eflags" => { eflags = Some(val); },
+                    Some((name, val)) if name == "eip" => { entry = Some(val); },
+                    Some((name, val)) => {
+                        let idx = REGISTER_NAMES.iter().position(|r| *r == name).unwrap();
+                        register_values.push((idx, val));
+                    },
+                    None => {
+                        println!("invalid register setting: {}", value);
+                        process::exit(1);
+                    }
+                }
+            },
+            "--screenshot" => { screenshot = args.next(); },
+            "--floppy" => { floppies.extend(args.next()); },
+            "--disk" => { hard_disks.extend(args.next()); },
+            "--boot" => { boot = true; },
+            "--keys" => {
+                let script = args.next().unwrap_or_default();
+                keys.get_or_insert_with(Vec::new).extend(unescape(&script));
+            },
+            "--keys-file" => {
+                let path = args.next().unwrap_or_default();
+                match std::fs::read(&path) {
+                    Ok(data) => { keys.get_or_insert_with(Vec::new).extend(data); },
+                    Err(e) => {
+                        println!("cannot read key script {}: {}", path, e);
+                        process::exit(1);
+                    }
+                }
+            },
+            "--linux" => { linux = true; },
+            "--multiboot" => { multiboot = true; },
+            "--cmdline" => { cmdline = args.next(); },
+            "--module" => { modules.extend(args.next()); },
+            "--" => { guest_args.extend(args.by_ref()); },
+            _ => match arg.rsplit_once('@').and_then(|(path, place)| Some((path, parse_place(place)?))) {
+                Some((path, (addr, offset))) => {
+                    blobs.push((path.to_string(), addr, offset));
+                },
+                None => { files.push(arg); }
+            }
+        }
     }
 
-    if args.len() != 2 {
-        println!("usage: px86 filename");
+    let needs_program = !(boot || !blobs.is_empty() || restore.is_some());
+    if files.len() > 1 || (needs_program && files.is_empty())
+        || (restore.is_some() && !files.is_empty())
+        || (snapshot_at.is_some() && save_snapshot.is_none()) {
+        usage();
+        process::exit(1);
+    }
+    if reverse && !monitor_flag && gdb.is_none() {
+        println!("--reverse needs --monitor or --gdb to step backwards");
         process::exit(1);
     }
 
-    let mut emu = emulator::Emulator::new(MEM_SIZE, 0x7c00, 0x7c00);
+    let mem_size = mem_size.unwrap_or(if linux {
+        LINUX_MEM_SIZE
+    } else if multiboot {
+        MULTIBOOT_MEM_SIZE
+    } else {
+        MEM_SIZE
+    });
+    let mut emu = match &restore {
+        Some(path) => {
+            let res = std::fs::File::open(path).map_err(emulator::snapshot::SnapshotError::from)
+                .and_then(|f| emulator::Emulator::load_snapshot(&mut std::io::BufReader::new(f)));
+            res.unwrap_or_else(|e| {
+                println!("cannot restore snapshot {}: {}", path, e);
+                process::exit(1);
+            })
+        },
+        None => emulator::Emulator::new(mem_size, load_address, load_address),
+    };
+
+    match keys {
+        Some(script) => { emu.keyboard.push_bytes(&script); },
+        None => { emu.keyboard.use_host = true; }
+    }
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        emulator::keyboard::restore_terminal();
+        default_hook(info);
+    }));
 
-    let f = match File::open(args[1].to_string()) {
-        Ok(f) => f,
-        Err(_) => {
+    let drives = floppies.iter().zip(0x00..).chain(hard_disks.iter().zip(0x80..));
+    for (path, drive) in drives {
+        match emulator::disk::Disk::open(drive, path) {
+            Ok(disk) => emu.attach_disk(disk),
+            Err(e) => {
+                println!("cannot open disk image {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let (dump_start, dump_size) = if restore.is_some() {
+        (0, 0)
+    } else if boot {
+        let drive = emu.disks.iter().map(|d| d.drive).min().unwrap_or(0);
+        if let Err(e) = emu.boot_from_disk(drive) {
+            println!("cannot boot: {}", e);
+            process::exit(1);
+        }
+        (0x7c00, emulator::disk::SECTOR_SIZE)
+    } else if files.is_empty() {
+        (0, 0)
+    } else {
+        let data = std::fs::read(&files[0]).unwrap_or_else(|_e| {
             println!("cannot open file.");
             process::exit(1);
+        });
+        if linux {
+            let mut argv = vec![files[0].clone()];
+            argv.extend(guest_args);
+            if let Err(e) = emu.start_linux(&data, &argv, &[]) {
+                println!("cannot load Linux executable: {}", e);
+                process::exit(1);
+            }
+            (0, 0)
+        } else if multiboot {
+            let modules: Vec<_> = modules.iter().map(|m| {
+                let path = m.split(' ').next().unwrap_or_default();
+                let data = std::fs::read(path).unwrap_or_else(|e| {
+                    println!("cannot open module {}: {}", path, e);
+                    process::exit(1);
+                });
+                emulator::multiboot::Module { data, cmdline: m.clone() }
+            }).collect();
+            let cmdline = cmdline.unwrap_or_else(|| files[0].clone());
+            if let Err(e) = emu.load_multiboot(&data, &cmdline, &modules) {
+                println!("cannot load Multiboot kernel: {}", e);
+                process::exit(1);
+            }
+            (0, 0)
+        } else if data.starts_with(&emulator::loader::ELF_MAGIC) {
+            if let Err(e) = emu.load_elf(&data) {
+                println!("cannot load ELF file: {}", e);
+                process::exit(1);
+            }
+            (0, 0)
+        } else if let Some(format) = emulator::hexfile::detect(&data) {
+            if let Err(e) = emu.load_hex(format, &data) {
+                println!("cannot load {}: {}", files[0], e);
+                process::exit(1);
+            }
+            (0, 0)
+        } else {
+            if let Err(e) = emu.load_binary(load_address, &data) {
+                println!("cannot load {}: {}", files[0], e);
+                process::exit(1);
+            }
+            (load_address as usize, data.len())
         }
     };
 
-    let data: Result<Vec<_>, _> = f.bytes().collect();
-
-    emu.memory.splice(0x7c00.., data.map_err(|_e| {
-            println!("memory loading error.");
+    let mut blob_entry: Option<u32> = None;
+    for (path, addr, offset) in &blobs {
+        let data = std::fs::read(path).unwrap_or_else(|e| {
+            println!("cannot open {}: {}", path, e);
+            process::exit(1);
+        });
+        let data = data.get(*offset..).unwrap_or_else(|| {
+            println!("offset {:#x} is past the end of {}", offset, path);
             process::exit(1);
-    }).unwrap()); 
+        });
+        // records in a HEX or S-record file are relative to the address
+        let res = match emulator::hexfile::detect(data) {
+            Some(format) => emu.load_hex_at(format, data, *addr).map(|image| {
+                image.entry.or_else(|| image.chunks.first().map(|c| c.0))
+                    .map_or(*addr, |eip| addr.wrapping_add(eip))
+            }),
+            None => emu.load_binary(*addr, data).map(|_| *addr),
+        };
+        match res {
+            Ok(start) => { blob_entry.get_or_insert(start); },
+            Err(e) => {
+                println!("cannot load {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    if files.is_empty() && !boot && restore.is_none() {
+        emu.eip = blob_entry.unwrap_or(blobs[0].1);
+    }
+
+    for wp in watchpoints {
+        emu.add_watchpoint(wp);
+    }
+
+    if let Some(addr) = entry {
+        emu.eip = addr;
+    }
+    if let Some(val) = eflags {
+        emu.eflags = val;
+    }
+    for (idx, val) in register_values {
+        emu.registers.regs[idx] = val;
+    }
 
     let instructions = emu.init_instructions();
 
+    let replay_end = replay.map(|path| {
+        let res = std::fs::File::open(&path).map_err(emulator::loader::LoadError::from)
+            .and_then(|f| emulator::replay::InputLog::load(&mut std::io::BufReader::new(f)))
+            .and_then(|log| {
+                let end = log.horizon;
+                emu.replay(log).map(|_| end)
+            });
+        res.unwrap_or_else(|e| {
+            println!("cannot replay {}: {}", path, e);
+            process::exit(1);
+        })
+    });
+    if record.is_some() {
+        emu.start_recording();
+    }
+
+    // symbols default to the program itself when it is an ELF file
+    let symbols = symbols.or_else(|| files.first().filter(|path| {
+        restore.is_none() && std::fs::read(path).is_ok_and(|d| d.starts_with(&emulator::loader::ELF_MAGIC))
+    }).cloned());
+    let debug_info = symbols.map(|path| {
+        load_debug_info(&path).unwrap_or_else(|e| {
+            println!("cannot read symbols {}: {}", path, e);
+            process::exit(1);
+        })
+    });
+    let coverage = coverage.map(|path| {
+        let format = coverage_format.unwrap_or(if path.ends_with(".info") || path.ends_with(".lcov") {
+            emulator::coverage::CoverageFormat::Lcov
+        } else {
+            emulator::coverage::CoverageFormat::Hits
+        });
+        if format == emulator::coverage::CoverageFormat::Lcov
+            && debug_info.as_ref().map_or(true, |info| info.lines.is_empty()) {
+            println!("lcov coverage needs DWARF line information; build with -g or use --coverage-format hits");
+            process::exit(1);
+        }
+        (path, format, emu.start_coverage())
+    });
+
+    // started last: exiting early would lose what the BufWriter holds
+    let trace = trace.map(|path| {
+        let format = trace_format.unwrap_or(if path.ends_with(".bin") {
+            emulator::trace::TraceFormat::Binary
+        } else {
+            emulator::trace::TraceFormat::JsonLines
+        });
+        let res = std::fs::File::create(&path)
+            .and_then(|f| emu.start_trace(Box::new(std::io::BufWriter::new(f)), format));
+        res.unwrap_or_else(|e| {
+            println!("cannot write trace {}: {}", path, e);
+            process::exit(1);
+        })
+    });
+
+    let mut history = if reverse {
+        Some(emulator::reverse::History::new(checkpoint_interval))
+    } else {
+        None
+    };
+
+    if let Some(addr) = &gdb {
+        let res = emulator::gdb::GdbStub::listen(addr).and_then(|mut stub| {
+            stub.history = history.take();
+            let res = stub.run(&mut emu, &instructions);
+            history = stub.history.take();
+            res
+        });
+        if let Err(e) = res {
+            println!("gdb connection failed: {}", e);
+        }
+    }
+
+    let mut monitor = if monitor_flag {
+        emu.keyboard.keep_signals = true;
+        monitor::catch_interrupts();
+        let mut m = monitor::Monitor::default();
+        m.history = history.take();
+        Some(m)
+    } else {
+        None
+    };
+    let mut last_repaint: Option<Instant> = None;
+
     println!();
-    while emu.eip < MEM_SIZE as u32 {
-        let code = emu.get_code8(0);
+    if let Some(m) = monitor.as_mut() {
+        if !m.enter(&mut emu, &instructions, "") {
+            emu.halted = true;
+        }
+    }
+    while !emu.finished() {
+        if replay_end == Some(emu.icount) {
+            let reason = format!("end of replay after {} instructions", emu.icount);
+            match monitor.as_mut() {
+                Some(m) => {
+                    if !m.enter(&mut emu, &instructions, &reason) {
+                        break;
+                    }
+                },
+                None => {
+                    println!("\n\n--------{}--------\n", reason);
+                    break;
+                }
+            }
+        }
+        if snapshot_at == Some(emu.eip) {
+            println!("\n\n--------Snapshot at {:#010x}--------\n", emu.eip);
+            break;
+        }
+        if let Some(m) = monitor.as_mut() {
+            if let Some(reason) = m.check(&emu) {
+                if !m.enter(&mut emu, &instructions, &reason) {
+                    break;
+                }
+            }
+        }
 
-        if let Some(inst) = instructions[code as usize] {
-            if !quiet_flag {
-                println!("EIP: {:#06x}, ESP: {:#06x}, Code: {:#02x}",
-                         emu.eip, emu.registers.regs[4], code);
+        if !quiet_flag && trace.is_none() {
+            println!("EIP: {:#06x}, ESP: {:#06x}, Code: {:#02x}  {}",
+                     emu.eip, emu.registers.regs[4], emu.peek8(emu.eip).unwrap_or(0),
+                     emu.disassemble(emu.eip).text);
+        }
+
+        if let Some(history) = monitor.as_mut().and_then(|m| m.history.as_mut()) {
+            history.record(&mut emu);
+        }
+        if let Err(code) = emu.step(&instructions) {
+            let fault = format!("Not Implemented Instruction: 0x{:x}", code);
+            match monitor.as_mut() {
+                Some(m) => {
+                    if m.enter(&mut emu, &instructions, &fault) {
+                        continue;
+                    }
+                    break;
+                },
+                None => {
+                    println!("\n\n{}\n", fault);
+                    break;
+                }
             }
+        }
 
-            inst(&mut emu);
+        if let Some(reason) = emu.take_stop_reason() {
+            match monitor.as_mut() {
+                Some(m) => {
+                    if !m.enter(&mut emu, &instructions, &reason.to_string()) {
+                        break;
+                    }
+                },
+                None => {
+                    println!("{}", reason);
+                    if let emulator::hooks::StopReason::Denied(_) = reason {
+                        break;
+                    }
+                },
+            }
+        }
 
-            if emu.eip == 0x00 {
-                println!("\n\n--------End of Program--------\n");
-                break;
+        if quiet_flag && emu.vga.dirty && emu.in_text_mode()
+            && last_repaint.map_or(true, |t| t.elapsed() >= REPAINT_INTERVAL) {
+            if last_repaint.is_none() {
+                print!("\x1b[2J");
             }
+            repaint(&mut emu);
+            last_repaint = Some(Instant::now());
+        }
+    }
+
+    if emu.eip == 0x00 {
+        println!("\n\n--------End of Program--------\n");
+    } else if emu.halted {
+        match emu.exit_code {
+            Some(code) => println!("\n\n--------Exit Code: {}--------\n", code),
+            None => println!("\n\n--------Halted--------\n"),
+        }
+    }
+
+    emu.keyboard.release_host();
+
+    if let Some(path) = record {
+        let res = std::fs::File::create(&path)
+            .and_then(|f| emu.input_log.save(&mut std::io::BufWriter::new(f)));
+        if let Err(e) = res {
+            println!("cannot write input log {}: {}", path, e);
+        }
+    }
+
+    if let Some(path) = save_snapshot {
+        if let Err(e) = write_snapshot(&emu, &path) {
+            println!("cannot write snapshot {}: {}", path, e);
+        }
+    }
+
+    if let Some(handle) = trace {
+        if let Err(e) = emu.stop_trace(handle) {
+            println!("cannot write trace: {}", e);
+        }
+    }
+
+    if let Some((path, format, handle)) = coverage {
+        let coverage = emu.stop_coverage(handle);
+        let res = std::fs::File::create(&path).and_then(|f| {
+            let mut out = std::io::BufWriter::new(f);
+            match format {
+                emulator::coverage::CoverageFormat::Hits => coverage.write_hits(&mut out, debug_info.as_ref()),
+                emulator::coverage::CoverageFormat::Lcov => {
+                    coverage.write_lcov(&mut out, debug_info.as_ref().unwrap(), "")
+                }
+            }
+        });
+        if let Err(e) = res {
+            println!("cannot write coverage {}: {}", path, e);
+        }
+    }
+
+    if emu.vga.dirty && emu.in_text_mode() {
+        if quiet_flag {
+            repaint(&mut emu);
         } else {
-            println!("\n\nNot Implemented Instruction: 0x{:x}\n", code);
-            break;
+            println!("{}\n", emu.text_snapshot());
+        }
+    }
+
+    if let Some(path) = screenshot {
+        if let Err(e) = emu.save_screenshot(&path) {
+            println!("cannot write screenshot: {}", e);
         }
     }
 
     println!("{}", emu.registers);
     println!("EIP: {:#010x}", emu.eip);
+    if let Some(code) = emu.chipset.post_code {
+        println!("POST: {:#04x}", code);
+    }
 
-    for  (a, m) in emu.memory[0x7c00..].iter().enumerate() {
+    for  (a, m) in emu.memory[dump_start..dump_start + dump_size].iter().enumerate() {
         print!("{:02x} ", m);
         if (a+1) % 8 == 0 {
             println!();
@@ -73,5 +569,272 @@ fn main() {
     }
     println!();
 
-    process::exit(0);
+    process::exit(emu.exit_code.unwrap_or(0));
+}
+
+/// `px86 disasm`: lists the instructions of a raw binary, or of the data
+/// records of an Intel HEX/S-record file.
+fn disasm(args: Vec<String>) {
+    let mut load_address = LOAD_ADDRESS;
+    let mut file = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--load" => { load_address = number_arg(&arg, args.next()) as u32; },
+            _ => { file = Some(arg); }
+        }
+    }
+    let file = file.unwrap_or_else(|| {
+        usage();
+        process::exit(1);
+    });
+    let data = std::fs::read(&file).unwrap_or_else(|e| {
+        println!("cannot open {}: {}", file, e);
+        process::exit(1);
+    });
+
+    let chunks = match emulator::hexfile::detect(&data) {
+        Some(format) => {
+            let image = match format {
+                emulator::hexfile::HexFormat::IntelHex => emulator::hexfile::parse_ihex(&data),
+                emulator::hexfile::HexFormat::SRecord => emulator::hexfile::parse_srec(&data),
+            };
+            image.unwrap_or_else(|e| {
+                println!("cannot load {}: {}", file, e);
+                process::exit(1);
+            }).chunks
+        },
+        None => vec![(load_address, data)],
+    };
+
+    for (addr, bytes) in chunks {
+        for inst in emulator::disasm::disassemble_bytes(&bytes, addr) {
+            let hex: Vec<String> = inst.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{:08x}  {:<24} {}", inst.addr, hex.join(" "), inst.text);
+        }
+    }
+}
+
+fn usage() {
+    println!("usage: px86 [options] program|program.hex|program.srec [file@address...]");
+    println!("       px86 [options] file@address[:offset]...");
+    println!("       px86 [options] --boot");
+    println!("       px86 [options] --restore snapshot");
+    println!("       px86 [options] --linux program [-- args...]");
+    println!("       px86 [options] --multiboot [--cmdline text] [--module \"file args\"]... kernel");
+    println!("       px86 disasm [--load address] file");
+    println!();
+    println!("options:");
+    println!("  -q, --quiet, quiet     do not trace instructions; draw the text screen instead");
+    println!("  -h, --help             show this message");
+    println!("  --mem size             guest memory size from 64K to 4G, e.g. 4M or 0x200000");
+    println!("                         (default 1M; 192M with --linux, 64M with --multiboot)");
+    println!("  --load address         where a raw program is loaded (default 0x7c00)");
+    println!("  --entry address        initial EIP (default: the load address)");
+    println!("  --reg name=value       initial register value; eax..edi, eip or eflags");
+    println!("  file@address[:offset]  copy a raw file, skipping offset bytes, to address;");
+    println!("                         HEX and S-record files are loaded relative to address");
+    println!("  --monitor              start in the interactive monitor; Ctrl-C returns to it");
+    println!("  --watch addr[:len][:rwx]  report accesses to a range (default: writes of 1 byte)");
+    println!("  --trace file           write a JSON Lines trace, or binary for *.bin, instead of");
+    println!("                         tracing to the terminal");
+    println!("  --trace-format jsonl|bin  override the trace format");
+    println!("  --coverage file        count executed instructions; lcov for *.info or *.lcov");
+    println!("  --coverage-format hits|lcov  override the coverage format");
+    println!("  --symbols file         ELF or nm-style symbol map for coverage (default: an ELF program)");
+    println!("  --restore file         start from a snapshot instead of loading a program");
+    println!("  --save-snapshot file   save the machine state when the run stops");
+    println!("  --snapshot-at address  stop and save the snapshot when EIP reaches address");
+    println!("  --record file          log keyboard, serial, clock and file input for --replay");
+    println!("  --replay file          feed the guest the input recorded by --record");
+    println!("  --reverse              keep checkpoints so the monitor and gdb can step backwards;
+                         needs --monitor or --gdb");
+    println!("  --checkpoint-interval n  instructions between checkpoints (default 10000)");
+    println!("  --gdb port|unix:path   wait for gdb to attach over TCP or a Unix socket");
+    println!("  --screenshot file      save the screen as PNG, or PPM for *.ppm");
+    println!("  --floppy image         attach a floppy image as the next drive from 0x00");
+    println!("  --disk image           attach a hard disk image as the next drive from 0x80");
+    println!("  --boot                 run sector 0 of the first drive at 0x7c00, as 32-bit code");
+    println!("  --keys text            scripted keyboard input (\\n, \\e, \\xNN escapes)");
+    println!("  --keys-file file       scripted keyboard input from a file");
+    println!("  --linux                run a static i386 Linux executable; -- passes its arguments");
+    println!("  --multiboot            boot a Multiboot kernel");
+    println!("  --cmdline text         kernel command line for --multiboot (default: the file name)");
+    println!("  --module \"file args\"   load a Multiboot module with its command line");
+    println!();
+    println!("Numbers are decimal, or hexadecimal with 0x; sizes take K, M or G suffixes.");
+}
+
+/// Parses a decimal or 0x-prefixed hexadecimal number with an optional K, M
+/// or G suffix.
+fn parse_number(s: &str) -> Option<u64> {
+    let (digits, scale) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 1 << 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 1 << 20),
+        b'g' | b'G' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let val = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    val.checked_mul(scale)
+}
+
+/// Parses the `address[:offset]` of a `file@address[:offset]` argument.
+/// Anything else after an `@` is part of a file name.
+fn parse_place(place: &str) -> Option<(u32, usize)> {
+    let (addr, offset) = match place.split_once(':') {
+        Some((addr, offset)) => (addr, parse_number(offset)?),
+        None => (place, 0),
+    };
+    Some((parse_number(addr)? as u32, offset as usize))
+}
+
+fn number_arg(option: &str, value: Option<String>) -> u64 {
+    let value = value.unwrap_or_default();
+    parse_number(&value).unwrap_or_else(|| {
+        println!("invalid number for {}: {:?}", option, value);
+        process::exit(1);
+    })
+}
+
+/// Parses `addr[:len][:rwx]` as used by `--watch` and the monitor.
+fn parse_watchpoint(spec: &str) -> Option<emulator::hooks::Watchpoint> {
+    let mut fields = spec.split(':');
+    let start = parse_number(fields.next()?)? as u32;
+    let mut len = 1;
+    let mut kinds = "w";
+    for field in fields {
+        if !field.is_empty() && field.chars().all(|c| "rwx".contains(c)) {
+            kinds = field;
+        } else {
+            len = parse_number(field)? as u32;
+        }
+    }
+    Some(emulator::hooks::Watchpoint {
+        start,
+        end: start.wrapping_add(len.max(1)),
+        read: kinds.contains('r'),
+        write: kinds.contains('w'),
+        execute: kinds.contains('x'),
+    })
+}
+
+/// Splits a `--reg` setting such as `eax=0x10` into the lower-case register
+/// name and its value.
+fn parse_register(s: &str) -> Option<(String, u32)> {
+    let (name, value) = s.split_once('=')?;
+    let name = name.to_ascii_lowercase();
+    if !REGISTER_NAMES.contains(&name.as_str()) && name != "eip" && name != "eflags" {
+        return None;
+    }
+    let value = parse_number(value)?;
+    if value > u32::MAX as u64 {
+        return None;
+    }
+    Some((name, value as u32))
+}
+
+fn write_snapshot(emu: &emulator::Emulator, path: &str) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    emu.save_snapshot(&mut out)
+}
+
+/// Reads symbols and line numbers from an ELF file or an nm-style map.
+fn load_debug_info(path: &str) -> Result<emulator::debuginfo::DebugInfo, emulator::loader::LoadError> {
+    let data = std::fs::read(path)?;
+    if data.starts_with(&emulator::loader::ELF_MAGIC) {
+        emulator::debuginfo::DebugInfo::from_elf(&data)
+    } else {
+        Ok(emulator::debuginfo::DebugInfo::from_symbol_map(&String::from_utf8_lossy(&data)))
+    }
+}
+
+fn repaint(emu: &mut emulator::Emulator) {
+    print!("{}", emu.render_text());
+    std::io::stdout().flush().unwrap();
+    emu.vga.dirty = false;
+}
+
+/// Expands the backslash escapes accepted by `--keys`: `\n`, `\r`, `\t`, `\e`,
+/// `\\` and `\xNN`.
+fn unescape(s: &str) -> Vec<u8> {
+    let mut out = vec![];
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') | Some(b'r') => out.push(b'\r'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'e') => out.push(0x1b),
+            Some(b'x') => {
+                let hex: String = bytes.by_ref().take(2).map(|c| c as char).collect();
+                out.push(u8::from_str_radix(&hex, 16).unwrap_or(0));
+            },
+            Some(c) => out.push(c),
+            None => out.push(b'\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("1234"), Some(1234));
+        assert_eq!(parse_number("0x7c00"), Some(0x7c00));
+        assert_eq!(parse_number("0XFF"), Some(0xff));
+        assert_eq!(parse_number("64K"), Some(0x10000));
+        assert_eq!(parse_number("4m"), Some(0x40_0000));
+        assert_eq!(parse_number("0x2G"), Some(0x8000_0000));
+        assert_eq!(parse_number("4G"), Some(MAX_MEM_SIZE));
+        assert_eq!(parse_number(""), None);
+        assert_eq!(parse_number("K"), None);
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("12abc"), None);
+        assert_eq!(parse_number("-1"), None);
+        assert_eq!(parse_number("0xffffffffffffffffK"), None);
+    }
+
+    #[test]
+    fn places() {
+        assert_eq!(parse_place("0x1000"), Some((0x1000, 0)));
+        assert_eq!(parse_place("0x1000:512"), Some((0x1000, 512)));
+        assert_eq!(parse_place("1M:0x10"), Some((0x10_0000, 0x10)));
+        assert_eq!(parse_place("host"), None);
+        assert_eq!(parse_place("0x1000:"), None);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let w = parse_watchpoint("0x100").unwrap();
+        assert_eq!((w.start, w.end, w.read, w.write, w.execute), (0x100, 0x101, false, true, false));
+        let w = parse_watchpoint("0x100:16:rx").unwrap();
+        assert_eq!((w.start, w.end, w.read, w.write, w.execute), (0x100, 0x110, true, false, true));
+        let w = parse_watchpoint("0x100:rw:4").unwrap();
+        assert_eq!((w.start, w.end, w.read, w.write, w.execute), (0x100, 0x104, true, true, false));
+        let w = parse_watchpoint("0xfffffffe:4").unwrap();
+        assert_eq!((w.start, w.end), (0xffff_fffe, 2));
+        let w = parse_watchpoint("0x100:0").unwrap();
+        assert_eq!((w.start, w.end), (0x100, 0x101));
+        assert!(parse_watchpoint("").is_none());
+        assert!(parse_watchpoint("0x100:zz").is_none());
+    }
+
+    #[test]
+    fn key_escapes() {
+        assert_eq!(unescape("ab"), b"ab");
+        assert_eq!(unescape("a\\nb\\r"), b"a\rb\r");
+        assert_eq!(unescape("\\t\\e\\\\"), b"\t\x1b\\");
+        assert_eq!(unescape("\\x41\\x0a!"), b"A\n!");
+        assert_eq!(unescape("\\xzz\\q"), b"\0q");
+        assert_eq!(unescape("end\\"), b"end\\");
+    }
 }
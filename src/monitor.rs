@@ -0,0 +1,387 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use x86_emu::emulator::{Emulator, Instructions, reverse::History};
+use super::{parse_number, parse_register, parse_watchpoint, write_snapshot, REGISTER_NAMES};
+
+/// Set by the SIGINT handler.
+fn interrupted() -> &'static Arc<AtomicBool> {
+    static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    INTERRUPTED.get_or_init(Default::default)
+}
+
+/// Makes Ctrl-C stop the guest and enter the monitor instead of killing
+/// the emulator.
+pub fn catch_interrupts() {
+    #[cfg(unix)]
+    {
+        let res = signal_hook::flag::register(signal_hook::consts::SIGINT, interrupted().clone());
+        if let Err(e) = res {
+            println!("cannot catch Ctrl-C: {}", e);
+        }
+    }
+}
+
+const HELP: &str = "\
+s [n]             step n instructions (default 1)
+c                 continue until a breakpoint, Ctrl-C or a fault
+b addr            set a breakpoint
+bc addr|*         clear a breakpoint, or all of them
+bl                list breakpoints
+wp addr[:len][:rwx]  watch a range (default: writes of 1 byte)
+wpc addr          clear the watchpoints starting at addr
+wpl               list watchpoints
+r [name=value]    show registers, or set eax..edi, eip or eflags
+x addr [len]      examine memory
+w addr byte...    write bytes to memory
+u [addr] [n]      show the next n instructions (default: at EIP)
+k [n]             print n dwords of the stack
+rs [n]            step back n instructions (with --reverse)
+rc                run backwards to a breakpoint or watchpoint hit (with --reverse)
+snap file         save a snapshot of the machine
+shot file         save the graphics screen as PNG, or PPM for *.ppm
+q                 quit
+An empty line repeats the last command.";
+
+/// Interactive debugger prompt driven by the main execution loop: the loop
+/// asks `check` before every instruction and calls `enter` when it says to
+/// stop. The instruction at EIP always runs after `enter` returns, so a
+/// breakpoint never stops the guest twice in a row.
+#[derive(Debug, Default)]
+pub struct Monitor {
+    breakpoints: BTreeSet<u32>,
+    /// Instructions left to run before stopping again, when stepping.
+    steps: Option<u64>,
+    last_command: String,
+    /// Checkpoints for stepping backwards, when recording.
+    pub history: Option<History>,
+}
+
+impl Monitor {
+    /// Why execution should stop before the next instruction, if it should.
+    pub fn check(&mut self, emu: &Emulator) -> Option<String> {
+        if interrupted().swap(false, Ordering::SeqCst) {
+            return Some("interrupted".to_string());
+        }
+        if let Some(steps) = self.steps {
+            if steps == 0 {
+                return Some(String::new());
+            }
+            self.steps = Some(steps - 1);
+        }
+        if self.breakpoints.contains(&emu.eip) {
+            return Some(format!("breakpoint at {:#010x}", emu.eip));
+        }
+        None
+    }
+
+    /// Runs the prompt until the user resumes the guest. Returns false if
+    /// they quit instead.
+    pub fn enter(&mut self, emu: &mut Emulator, instructions: &Instructions, reason: &str) -> bool {
+        interrupted().store(false, Ordering::SeqCst);
+        self.steps = None;
+        if !reason.is_empty() {
+            println!("\r\n{}", reason);
+        }
+        print_location(emu);
+
+        loop {
+            print!("(px86) ");
+            io::stdout().flush().unwrap();
+            let line = match emu.keyboard.read_host_line() {
+                Some(line) => line.trim().to_string(),
+                None => { return false; }
+            };
+            let line = if line.is_empty() { self.last_command.clone() } else { line };
+            self.last_command = line.clone();
+
+            if let Some(resume) = self.command(emu, instructions, &line) {
+                return resume;
+            }
+        }
+    }
+
+    /// Runs one command line. Returns whether to resume the guest once a
+    /// command leaves the prompt, or `None` to keep prompting.
+    fn command(&mut self, emu: &mut Emulator, instructions: &Instructions, line: &str) -> Option<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |i: usize| words.get(i).and_then(|w| parse_number(w));
+        match words.first().copied().unwrap_or("") {
+            "" => (),
+            "s" | "step" => {
+                // the first one runs as soon as we return
+                self.steps = Some(number(1).unwrap_or(1).max(1) - 1);
+                return Some(true);
+            },
+            "c" | "continue" => { return Some(true); },
+            "b" | "break" => match number(1) {
+                Some(addr) => { self.breakpoints.insert(addr as u32); },
+                None => println!("usage: b addr"),
+            },
+            "bc" => match words.get(1) {
+                Some(&"*") => self.breakpoints.clear(),
+                _ => match number(1) {
+                    Some(addr) if self.breakpoints.remove(&(addr as u32)) => (),
+                    _ => println!("no such breakpoint"),
+                }
+            },
+            "bl" => {
+                for addr in &self.breakpoints {
+                    println!("{:#010x}", addr);
+                }
+            },
+            "wp" | "watch" => match words.get(1).and_then(|w| parse_watchpoint(w)) {
+                Some(wp) => emu.add_watchpoint(wp),
+                None => println!("usage: wp addr[:len][:rwx]"),
+            },
+            "wpc" => match number(1) {
+                Some(addr) => {
+                    let matching: Vec<_> = emu.watchpoints().iter()
+                        .filter(|wp| wp.start == addr as u32).copied().collect();
+                    if matching.is_empty() {
+                        println!("no such watchpoint");
+                    }
+                    for wp in matching {
+                        emu.remove_watchpoint(&wp);
+                    }
+                },
+                None => println!("usage: wpc addr"),
+            },
+            "wpl" => {
+                for wp in emu.watchpoints() {
+                    let kinds: String = [(wp.read, 'r'), (wp.write, 'w'), (wp.execute, 'x')]
+                        .iter().filter(|(on, _)| *on).map(|(_, c)| *c).collect();
+                    println!("{:#010x}-{:#010x} {}", wp.start, wp.end, kinds);
+                }
+            },
+            "r" | "regs" => match words.get(1) {
+                Some(setting) => set_register(emu, setting),
+                None => print_registers(emu),
+            },
+            "x" => match number(1) {
+                Some(addr) => dump_memory(emu, addr as u32, number(2).unwrap_or(64) as u32),
+                None => println!("usage: x addr [len]"),
+            },
+            "w" => {
+                let bytes: Option<Vec<u64>> = (2..words.len()).map(number).collect();
+                match (number(1), bytes) {
+                    (Some(addr), Some(bytes)) => {
+                        for (i, b) in bytes.iter().enumerate() {
+                            let addr = (addr as u32).wrapping_add(i as u32);
+                            if !emu.poke8(addr, *b as u8) {
+                                println!("cannot write {:#010x}", addr);
+                                break;
+                            }
+                        }
+                    },
+                    _ => println!("usage: w addr byte..."),
+                }
+            },
+            "u" => {
+                let addr = number(1).map_or(emu.eip, |a| a as u32);
+                list_instructions(emu, addr, number(2).unwrap_or(8) as usize);
+            },
+            "k" | "stack" => print_stack(emu, number(1).unwrap_or(8) as u32),
+            "rs" => match &self.history {
+                Some(history) => {
+                    for _ in 0..number(1).unwrap_or(1) {
+                        if !history.step_back(emu, instructions) {
+                            println!("start of history");
+                            break;
+                        }
+                    }
+                    print_location(emu);
+                },
+                None => println!("not recording; start with --reverse"),
+            },
+            "rc" => match &self.history {
+                Some(history) => {
+                    println!("{}", history.reverse_continue(emu, instructions, &self.breakpoints));
+                    print_location(emu);
+                },
+                None => println!("not recording; start with --reverse"),
+            },
+            "snap" => match words.get(1) {
+                Some(path) => {
+                    if let Err(e) = write_snapshot(emu, path) {
+                        println!("cannot write snapshot {}: {}", path, e);
+                    }
+                },
+                None => println!("usage: snap file"),
+            },
+            "shot" | "screenshot" => match words.get(1) {
+                Some(path) => {
+                    if let Err(e) = emu.save_screenshot(path) {
+                        println!("cannot write screenshot {}: {}", path, e);
+                    }
+                },
+                None => println!("usage: shot file"),
+            },
+            "q" | "quit" => { return Some(false); },
+            "h" | "help" | "?" => println!("{}", HELP),
+            cmd => println!("unknown command {:?}; try h", cmd),
+        }
+        None
+    }
+}
+
+fn print_location(emu: &Emulator) {
+    list_instructions(emu, emu.eip, 1);
+}
+
+fn print_registers(emu: &Emulator) {
+    println!("{}", emu.registers);
+    println!("EIP: {:#010x}\nEFLAGS: {:#010x}", emu.eip, emu.eflags);
+}
+
+fn set_register(emu: &mut Emulator, setting: &str) {
+    match parse_register(setting) {
+        Some((name, val)) if name == "eip" => { emu.eip = val; },
+        Some((name, val)) if name == "eflags" => { emu.eflags = val; },
+        Some((name, val)) => {
+            let idx = REGISTER_NAMES.iter().position(|r| *r == name).unwrap();
+            emu.registers.regs[idx] = val;
+        },
+        None => println!("usage: r name=value"),
+    }
+}
+
+fn dump_memory(emu: &Emulator, addr: u32, len: u32) {
+    for row in (0..len).step_by(16) {
+        let start = addr.wrapping_add(row);
+        let bytes: Vec<Option<u8>> = (0..16.min(len - row))
+            .map(|i| emu.peek8(start.wrapping_add(i)))
+            .collect();
+        let hex: Vec<String> = bytes.iter()
+            .map(|b| b.map_or("??".to_string(), |b| format!("{:02x}", b)))
+            .collect();
+        let text: String = bytes.iter()
+            .map(|b| match b {
+                Some(b) if b.is_ascii_graphic() || *b == b' ' => *b as char,
+                _ => '.',
+            })
+            .collect();
+        println!("{:08x}  {:<48} {}", start, hex.join(" "), text);
+    }
+}
+
+fn list_instructions(emu: &Emulator, addr: u32, count: usize) {
+    let mut addr = addr;
+    for _ in 0..count {
+        let inst = emu.disassemble(addr);
+        let hex: Vec<String> = inst.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{:08x}  {:<24} {}", addr, hex.join(" "), inst.text);
+        addr = addr.wrapping_add(inst.len().max(1) as u32);
+    }
+}
+
+fn print_stack(emu: &Emulator, count: u32) {
+    let esp = emu.registers.regs[4];
+    for i in 0..count {
+        let addr = esp.wrapping_add(i.wrapping_mul(4));
+        let bytes: Option<Vec<u8>> = (0..4).map(|j| emu.peek8(addr.wrapping_add(j))).collect();
+        match bytes {
+            Some(b) => println!("{:08x}  {:#010x}", addr, u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            None => { break; }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(monitor: &mut Monitor, emu: &mut Emulator, line: &str) -> Option<bool> {
+        let instructions = emu.init_instructions();
+        monitor.command(emu, &instructions, line)
+    }
+
+    #[test]
+    fn step_continue_and_quit() {
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x7c00);
+        let mut monitor = Monitor::default();
+        assert_eq!(run(&mut monitor, &mut emu, "s 5"), Some(true));
+        assert_eq!(monitor.steps, Some(4));
+        assert_eq!(run(&mut monitor, &mut emu, "step"), Some(true));
+        assert_eq!(monitor.steps, Some(0));
+        assert_eq!(monitor.check(&emu), Some(String::new()));
+        assert_eq!(run(&mut monitor, &mut emu, "c"), Some(true));
+        assert_eq!(run(&mut monitor, &mut emu, "q"), Some(false));
+        assert_eq!(run(&mut monitor, &mut emu, "bogus"), None);
+        assert_eq!(run(&mut monitor, &mut emu, ""), None);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x7c00);
+        let mut monitor = Monitor::default();
+        for line in ["b 0x7c00", "break 0x7c10", "b", "bl"] {
+            assert_eq!(run(&mut monitor, &mut emu, line), None);
+        }
+        assert_eq!(monitor.breakpoints.iter().copied().collect::<Vec<_>>(), [0x7c00, 0x7c10]);
+        assert_eq!(monitor.check(&emu), Some("breakpoint at 0x00007c00".to_string()));
+
+        run(&mut monitor, &mut emu, "bc 0x7c00");
+        run(&mut monitor, &mut emu, "bc 0x1234");
+        assert_eq!(monitor.check(&emu), None);
+        run(&mut monitor, &mut emu, "bc *");
+        assert!(monitor.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let mut monitor = Monitor::default();
+        run(&mut monitor, &mut emu, "wp 0x100:4:rw");
+        run(&mut monitor, &mut emu, "watch 0x200");
+        run(&mut monitor, &mut emu, "wp 0x300:zz");
+        run(&mut monitor, &mut emu, "wpl");
+        let wps: Vec<_> = emu.watchpoints().iter().map(|wp| (wp.start, wp.end, wp.read)).collect();
+        assert_eq!(wps, [(0x100, 0x104, true), (0x200, 0x201, false)]);
+        run(&mut monitor, &mut emu, "wpc 0x100");
+        run(&mut monitor, &mut emu, "wpc 0x100");
+        assert_eq!(emu.watchpoints().len(), 1);
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let mut monitor = Monitor::default();
+        for line in ["r eax=5", "regs EBX=0x10", "r eip=0x7c00", "r eflags=0x202", "r foo=1", "r"] {
+            run(&mut monitor, &mut emu, line);
+        }
+        assert_eq!(emu.registers.regs[0], 5);
+        assert_eq!(emu.registers.regs[3], 0x10);
+        assert_eq!((emu.eip, emu.eflags), (0x7c00, 0x202));
+
+        run(&mut monitor, &mut emu, "w 0x500 1 2 0xff");
+        assert_eq!(emu.memory[0x500..0x503], [1, 2, 0xff]);
+        run(&mut monitor, &mut emu, "w 0xfffe 7 8 9");
+        assert_eq!(emu.memory[0xfffe..], [7, 8]);
+        run(&mut monitor, &mut emu, "w 0x500 zz");
+        assert_eq!(emu.memory[0x500], 1);
+        for line in ["x 0x500 20", "x 0xfff8", "x", "u", "u 0x500 3", "k", "stack 4"] {
+            assert_eq!(run(&mut monitor, &mut emu, line), None);
+        }
+    }
+
+    #[test]
+    fn stack_near_the_top_of_the_address_space() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let mut monitor = Monitor::default();
+        emu.registers.regs[4] = 0xffff_fff8;
+        assert_eq!(run(&mut monitor, &mut emu, "k 0xffffffff"), None);
+        emu.registers.regs[4] = 0xfff8;
+        assert_eq!(run(&mut monitor, &mut emu, "k 0x40000001"), None);
+    }
+
+    #[test]
+    fn reverse_commands_need_history() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let mut monitor = Monitor::default();
+        assert_eq!(run(&mut monitor, &mut emu, "rs 2"), None);
+        assert_eq!(run(&mut monitor, &mut emu, "rc"), None);
+        assert_eq!(emu.eip, 0);
+    }
+}
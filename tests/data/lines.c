@@ -0,0 +1,19 @@
+/* Debug info fixture: a .bss variable and two functions, one line program.
+ * Build: gcc -m32 -g -gdwarf-4 -O0 -fno-pie -no-pie -nostdlib -static \
+ *            -fdebug-prefix-map=$PWD=/src -o lines.elf lines.c
+ *        objcopy --dump-section .debug_line=lines.debug_line lines.elf
+ */
+int counter;
+
+static int square(int x)
+{
+    return x * x;
+}
+
+void _start(void)
+{
+    for (int i = 0; i < 3; i++)
+        counter += square(i);
+    for (;;)
+        ;
+}
@@ -0,0 +1,91 @@
+# End-to-end test for --linux: reads a line from stdin, prints a greeting
+# and "ok" through a GS-relative TLS pointer, and exits with code 7.
+# Only the instructions the emulator implements are used, so conditional
+# branches to fail go through a near jmp.
+# Build: as --32 linux_e2e.s -o linux_e2e.o && ld -m elf_i386 -o linux_e2e.elf linux_e2e.o
+        .globl _start
+        .text
+_start:
+        # set_thread_area with entry_number -1
+        movl    $desc, %ebx
+        movl    $243, %eax
+        int     $0x80
+        cmpl    $0, %eax
+        jz      1f
+        jmp     fail
+1:
+        cmpl    $6, desc
+        je      2f
+        jmp     fail
+2:
+
+        # read(0, buf, 64)
+        movl    $3, %eax
+        xorl    %ebx, %ebx
+        movl    $buf, %ecx
+        movl    $64, %edx
+        int     $0x80
+        movl    %eax, %esi
+
+        # write(1, hello, 7) through an indirect call
+        movl    $write_hello, %edi
+        call    *%edi
+
+        # echo the input: write(1, buf, n)
+        movl    $4, %eax
+        movl    $1, %ebx
+        movl    $buf, %ecx
+        movl    %esi, %edx
+        int     $0x80
+
+        # SIB addressing: msgs[1] is the "ok" string
+        movl    $1, %ecx
+        movl    $msgs, %ebx
+        # and it must equal the pointer stored at %gs:0
+        movl    %gs:0, %edx
+        cmpl    (%ebx,%ecx,4), %edx
+        je      3f
+        jmp     fail
+3:
+        movl    %edx, %ecx
+        movl    $4, %eax
+        movl    $1, %ebx
+        movl    $3, %edx
+        int     $0x80
+
+        # writing from an address past the end of memory fails with EFAULT
+        movl    $4, %eax
+        movl    $1, %ebx
+        movl    $0xfffffff0, %ecx
+        movl    $4, %edx
+        int     $0x80
+        cmpl    $-14, %eax
+        je      4f
+        jmp     fail
+4:
+
+        movl    $1, %eax
+        movl    $7, %ebx
+        int     $0x80
+
+write_hello:
+        movl    $4, %eax
+        movl    $1, %ebx
+        movl    $hello, %ecx
+        movl    $7, %edx
+        int     $0x80
+        ret
+
+fail:
+        movl    $1, %eax
+        movl    $1, %ebx
+        int     $0x80
+
+        .data
+hello:  .ascii  "hello, "
+ok:     .ascii  "ok\n"
+msgs:   .long   hello, ok
+tls:    .long   ok
+desc:   .long   -1, tls, 0xfffff, 0x51
+        .bss
+buf:    .space  64
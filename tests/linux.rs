@@ -0,0 +1,18 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn linux_program_runs_end_to_end() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_x86_emu"))
+        .args(["-q", "--linux", "tests/data/linux_e2e.elf"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"world\n").unwrap();
+    let out = child.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("hello, world\nok\n"), "{}", stdout);
+    assert_eq!(out.status.code(), Some(7));
+}